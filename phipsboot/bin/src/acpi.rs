@@ -0,0 +1,237 @@
+//! Discovery and validation of the ACPI tables of the firmware. The tables
+//! are parsed with [`lib::acpi`] and read via the identity mapping of the
+//! loader.
+//!
+//! The RSDP is taken from the Multiboot2 information, from the Xen PVH start
+//! info, or from a scan of the legacy BIOS areas, in this order. All tables
+//! are validated once at boot; invalid ones are ignored.
+
+use crate::mem::paging;
+use alloc::vec::Vec;
+use core::cell::OnceCell;
+use core::fmt::{Display, Formatter};
+use lib::acpi::{Rsdp, Sdt, SdtHeader, FADT_SIGNATURE, RSDP_V1_LEN, RSDP_V2_LEN, SDT_HEADER_LEN};
+use lib::safe::Safe;
+
+/// Location of the segment of the Extended BIOS Data Area (EBDA) in the BIOS
+/// Data Area.
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+/// The RSDP is in the first KiB of the EBDA.
+const EBDA_SCAN_LEN: u64 = 0x400;
+/// The BIOS read-only memory area that may hold the RSDP.
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+static ACPI: Safe<OnceCell<Acpi>> = Safe::new(OnceCell::new());
+
+/// Where the RSDP was found.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RsdpSource {
+    Multiboot2,
+    XenPvh(u64),
+    Bios(u64),
+}
+
+impl Display for RsdpSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Multiboot2 => write!(f, "Multiboot2 information"),
+            Self::XenPvh(addr) => write!(f, "Xen PVH start info ({addr:#x})"),
+            Self::Bios(addr) => write!(f, "BIOS area at {addr:#x}"),
+        }
+    }
+}
+
+/// Where the kernel finds the RSDP.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RsdpLocation {
+    /// Physical address of the RSDP in firmware memory.
+    Firmware(u64),
+    /// A copy of the RSDP, zero-padded to [`RSDP_V2_LEN`] bytes. The
+    /// Multiboot2 information only holds a copy, which is not passed to the
+    /// kernel.
+    Copy([u8; RSDP_V2_LEN]),
+}
+
+#[derive(Debug)]
+struct Acpi {
+    rsdp: RsdpLocation,
+    /// All valid tables, including the DSDT.
+    tables: Vec<Sdt<'static>>,
+}
+
+/// Finds the RSDP, validates all tables, and logs them. Logs a warning if
+/// there is no RSDP.
+pub fn init() {
+    let Some((rsdp, location, source)) = find_rsdp() else {
+        log::warn!("No ACPI RSDP found");
+        return;
+    };
+    log::debug!(
+        "ACPI: RSDP revision {} (OEM {:?}) from the {source}",
+        rsdp.revision,
+        name(&rsdp.oem_id)
+    );
+    let tables = tables(&rsdp);
+    let _ = ACPI.set(Acpi {
+        rsdp: location,
+        tables,
+    });
+}
+
+/// Returns where the kernel finds the RSDP, if there is one.
+pub fn rsdp() -> Option<&'static RsdpLocation> {
+    ACPI.get().map(|acpi| &acpi.rsdp)
+}
+
+/// Returns the first valid table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt<'static>> {
+    ACPI.get()?
+        .tables
+        .iter()
+        .find(|table| &table.header.signature == signature)
+        .copied()
+}
+
+/// Returns the first valid RSDP, its location for the kernel, and where it
+/// was found.
+fn find_rsdp() -> Option<(Rsdp, RsdpLocation, RsdpSource)> {
+    let mut candidates = core::iter::once_with(mbi_rsdp)
+        .chain(core::iter::once_with(pvh_rsdp))
+        .chain(core::iter::once_with(bios_rsdp))
+        .flatten();
+    candidates.find_map(|(bytes, source)| {
+        let rsdp = Rsdp::parse(bytes)
+            .inspect_err(|e| log::warn!("Ignoring the RSDP from the {source}: {e}"))
+            .ok()?;
+        let location = match source {
+            RsdpSource::Multiboot2 => {
+                // Extensions beyond ACPI 2.0 are not part of the copy.
+                let len = (rsdp.length as usize).min(RSDP_V2_LEN);
+                let mut copy = [0; RSDP_V2_LEN];
+                copy[..len].copy_from_slice(&bytes[..len]);
+                RsdpLocation::Copy(copy)
+            }
+            RsdpSource::XenPvh(addr) | RsdpSource::Bios(addr) => RsdpLocation::Firmware(addr),
+        };
+        Some((rsdp, location, source))
+    })
+}
+
+/// Returns the copy of the RSDP in the Multiboot2 information.
+fn mbi_rsdp() -> Option<(&'static [u8], RsdpSource)> {
+    let mbi = crate::env::mbi()?;
+    // The tags hold a copy of the RSDP after the tag header of 8 bytes.
+    let (tag, len) = if let Some(tag) = mbi.rsdp_v2_tag() {
+        (tag as *const _ as *const u8, RSDP_V2_LEN)
+    } else {
+        (mbi.rsdp_v1_tag()? as *const _ as *const u8, RSDP_V1_LEN)
+    };
+    let bytes = unsafe { core::slice::from_raw_parts(tag.add(8), len) };
+    Some((bytes, RsdpSource::Multiboot2))
+}
+
+/// Returns the RSDP that the Xen PVH start info references.
+fn pvh_rsdp() -> Option<(&'static [u8], RsdpSource)> {
+    let addr = crate::xen_pvh::rsdp_paddr(crate::env::xen_pvh_start_info()?)?;
+    Some((
+        phys_slice(addr, RSDP_V2_LEN as u64)?,
+        RsdpSource::XenPvh(addr),
+    ))
+}
+
+/// Scans the first KiB of the EBDA and the BIOS area below 1 MiB for the
+/// RSDP.
+fn bios_rsdp() -> Option<(&'static [u8], RsdpSource)> {
+    let ebda_segment = unsafe { (EBDA_SEGMENT_PTR as *const u16).read_unaligned() };
+    let ebda = (ebda_segment as u64) << 4;
+    // Without a BIOS, the pointer to the EBDA is garbage.
+    let ebda_area = (0x80000..BIOS_AREA_START)
+        .contains(&ebda)
+        .then_some((ebda, EBDA_SCAN_LEN));
+    let bios_area = (BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START);
+    ebda_area
+        .into_iter()
+        .chain([bios_area])
+        .find_map(|(start, len)| {
+            let area = phys_slice(start, len)?;
+            let offset = lib::acpi::scan_rsdp(area)?;
+            Some((&area[offset..], RsdpSource::Bios(start + offset as u64)))
+        })
+}
+
+/// Returns all valid tables that the root table of the RSDP references, plus
+/// the DSDT. Logs an inventory of the tables.
+fn tables(rsdp: &Rsdp) -> Vec<Sdt<'static>> {
+    let (root_addr, entry_size) = rsdp.root_table();
+    let Some(root) = table(root_addr) else {
+        log::warn!("ACPI: the root table is invalid, ignoring all tables");
+        return Vec::new();
+    };
+
+    log::debug!("ACPI tables:");
+    log_table(root_addr, &root);
+    let mut tables = Vec::new();
+    for addr in root.root_table_entries(entry_size) {
+        let Some(sdt) = table(addr) else {
+            continue;
+        };
+        log_table(addr, &sdt);
+        tables.push(sdt);
+        if &sdt.header.signature != FADT_SIGNATURE {
+            continue;
+        }
+        let Some(dsdt_addr) = lib::acpi::fadt_dsdt(&sdt) else {
+            continue;
+        };
+        if let Some(dsdt) = table(dsdt_addr) {
+            log_table(dsdt_addr, &dsdt);
+            tables.push(dsdt);
+        }
+    }
+    tables
+}
+
+fn log_table(addr: u64, table: &Sdt) {
+    let header = &table.header;
+    log::debug!(
+        "  {} at {addr:#x}: {:#x} bytes, revision {}, OEM {:?} {:?}",
+        name(&header.signature),
+        header.length,
+        header.revision,
+        name(&header.oem_id),
+        name(&header.oem_table_id)
+    );
+}
+
+/// Returns the validated table at the given physical address. Tables that
+/// are not accessible or invalid are skipped with a warning.
+fn table(addr: u64) -> Option<Sdt<'static>> {
+    let header = SdtHeader::parse(phys_slice(addr, SDT_HEADER_LEN as u64)?)
+        .inspect_err(|e| log::warn!("Ignoring ACPI table at {addr:#x}: {e}"))
+        .ok()?;
+    let table = phys_slice(addr, header.length as u64)?;
+    Sdt::parse(table)
+        .inspect_err(|e| {
+            log::warn!(
+                "Ignoring ACPI table {} at {addr:#x}: {e}",
+                name(&header.signature)
+            )
+        })
+        .ok()
+}
+
+/// Returns the physical memory as slice, if it is within the identity
+/// mapping. Logs a warning otherwise.
+fn phys_slice(addr: u64, len: u64) -> Option<&'static [u8]> {
+    let slice = paging::phys_slice(addr, len);
+    if slice.is_none() {
+        log::warn!("ACPI structure at {addr:#x} is not accessible");
+    }
+    slice
+}
+
+/// Returns an ASCII identifier of an ACPI structure without the padding.
+fn name(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("????").trim_end()
+}
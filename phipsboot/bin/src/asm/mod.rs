@@ -16,3 +16,7 @@ core::arch::global_asm!(include_str!("vars.S"), options(att_syntax));
 core::arch::global_asm!(include_str!("macros.S"), options(att_syntax));
 core::arch::global_asm!(include_str!("start.S"), options(att_syntax));
 core::arch::global_asm!(include_str!("headers.S"), options(att_syntax));
+core::arch::global_asm!(include_str!("trampoline.S"), options(att_syntax));
+core::arch::global_asm!(include_str!("ap_startup.S"), options(att_syntax));
+core::arch::global_asm!(include_str!("exceptions.S"), options(att_syntax));
+core::arch::global_asm!(include_str!("symbols.S"), options(att_syntax));
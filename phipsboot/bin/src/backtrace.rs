@@ -0,0 +1,119 @@
+//! Symbolized backtraces for panics and exceptions.
+//!
+//! The loader is built with frame pointers, so the stack can be unwound by
+//! following the chain of saved `%rbp` values. Each frame holds the `%rbp` of
+//! the caller followed by the return address. The boot code zeroes `%rbp`
+//! before it enters the high-level code, which terminates the chain.
+//!
+//! Addresses are symbolized with the table that the `symtab` tool embeds into
+//! the binary after linking (see [`lib::symbols`]).
+
+use crate::extern_symbols;
+use crate::mem::paging;
+use core::fmt::{Display, Formatter};
+use lib::symbols::SymbolTable;
+
+/// Maximum number of frames that are unwound. Protects against corrupt
+/// frame-pointer chains.
+const MAX_FRAMES: usize = 32;
+
+/// A single frame of a backtrace.
+#[derive(Debug, Copy, Clone)]
+pub struct Frame {
+    /// The instruction pointer of the frame.
+    addr: u64,
+    /// Function that contains the instruction pointer and the offset into it.
+    symbol: Option<(&'static str, u64)>,
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#018x}", self.addr)?;
+        match self.symbol {
+            Some((name, offset)) => write!(f, " {name}+{offset:#x}"),
+            None => write!(f, " <unknown>"),
+        }
+    }
+}
+
+/// Iterator over the frames of a backtrace, innermost first.
+#[derive(Debug)]
+pub struct Backtrace {
+    /// The instruction pointer of the innermost frame, if known.
+    rip: Option<u64>,
+    /// Frame pointer of the next frame to unwind, or 0 when done.
+    rbp: u64,
+    frames: usize,
+    symbols: Option<SymbolTable<'static>>,
+}
+
+impl Backtrace {
+    /// Creates a backtrace that starts at the given frame pointer. If `rip` is
+    /// given, such as the instruction pointer of an exception, it is reported
+    /// as innermost frame.
+    pub fn new(rip: Option<u64>, rbp: u64) -> Self {
+        Self {
+            rip,
+            rbp,
+            frames: 0,
+            symbols: SymbolTable::parse(extern_symbols::symbols()),
+        }
+    }
+
+    /// Creates a backtrace of the caller.
+    #[inline(always)]
+    pub fn here() -> Self {
+        let rbp: u64;
+        unsafe { core::arch::asm!("mov %rbp, {}", out(reg) rbp, options(att_syntax)) };
+        Self::new(None, rbp)
+    }
+
+    /// Returns false if no symbol table is embedded into the binary.
+    pub fn has_symbols(&self) -> bool {
+        self.symbols.is_some()
+    }
+
+    /// Creates a frame for an instruction pointer and symbolizes it with
+    /// `lookup_addr`.
+    fn frame(&self, addr: u64, lookup_addr: u64) -> Frame {
+        Frame {
+            addr,
+            symbol: self.symbols.and_then(|table| table.lookup(lookup_addr)),
+        }
+    }
+
+    /// Reads the next link of the frame-pointer chain, i.e., the saved
+    /// `%rbp` of the caller and the return address.
+    fn unwind(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+        if rbp == 0 || rbp % 8 != 0 || !paging::is_mapped(rbp, 16) {
+            return None;
+        }
+        let (next_rbp, ret) = unsafe {
+            let ptr = rbp as *const u64;
+            (ptr.read(), ptr.add(1).read())
+        };
+        // The stack grows down, so the frames of the callers are at higher
+        // addresses. Everything else is a corrupt chain.
+        self.rbp = if next_rbp > rbp { next_rbp } else { 0 };
+        (ret != 0).then_some(ret)
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.frames >= MAX_FRAMES {
+            return None;
+        }
+        self.frames += 1;
+        if let Some(rip) = self.rip.take() {
+            return Some(self.frame(rip, rip));
+        }
+        // A return address points after the call, which might already be the
+        // next function if the call was the last instruction.
+        let ret = self.unwind()?;
+        Some(self.frame(ret, ret - 1))
+    }
+}
@@ -0,0 +1,83 @@
+//! Optional CPU features for the kernel (`--cpu-features=`). The features are
+//! enabled right before the hand-off, so PhipsBoot itself runs without them.
+
+use lib::cpu::{CpuFeature, CpuFeatures};
+use x86::controlregs::{cr4, cr4_write, xcr0_write, Cr4, Xcr0};
+use x86::cpuid::CpuId;
+
+/// Returns all optional features that the CPU supports.
+pub fn supported() -> CpuFeatures {
+    let cpuid = CpuId::new();
+    let info = cpuid.get_feature_info();
+    let extended = cpuid.get_extended_feature_info();
+    CpuFeature::ALL
+        .into_iter()
+        .filter(|feature| match feature {
+            CpuFeature::Xsave => info.as_ref().is_some_and(|i| i.has_xsave()),
+            CpuFeature::Avx => info.as_ref().is_some_and(|i| i.has_xsave() && i.has_avx()),
+            CpuFeature::Pcid => info.as_ref().is_some_and(|i| i.has_pcid()),
+            CpuFeature::Smep => extended.as_ref().is_some_and(|e| e.has_smep()),
+            CpuFeature::Smap => extended.as_ref().is_some_and(|e| e.has_smap()),
+            CpuFeature::Umip => extended.as_ref().is_some_and(|e| e.has_umip()),
+            CpuFeature::Fsgsbase => extended.as_ref().is_some_and(|e| e.has_fsgsbase()),
+        })
+        .collect()
+}
+
+/// Returns true if the CPU supports 5-level paging.
+pub fn has_la57() -> bool {
+    CpuId::new()
+        .get_extended_feature_info()
+        .is_some_and(|features| features.has_la57())
+}
+
+/// Returns the requested features that the CPU supports. AVX implies XSAVE.
+/// Unsupported features are logged and skipped.
+pub fn select(requested: CpuFeatures) -> CpuFeatures {
+    let mut requested = requested;
+    if requested.contains(CpuFeature::Avx) {
+        requested.insert(CpuFeature::Xsave);
+    }
+    let missing = requested.difference(supported());
+    if !missing.is_empty() {
+        log::warn!("The CPU doesn't support the requested features: {missing}");
+    }
+    requested.difference(missing)
+}
+
+/// Enables the features, which must be supported by the CPU.
+pub fn enable(features: CpuFeatures) {
+    unsafe { cr4_write(cr4_with(features)) };
+    if let Some(xcr0) = xcr0_for(features) {
+        unsafe { xcr0_write(xcr0) };
+    }
+}
+
+/// Returns the current `CR4` with the bits of the features set.
+pub fn cr4_with(features: CpuFeatures) -> Cr4 {
+    let mut cr4 = unsafe { cr4() };
+    for feature in features.iter() {
+        cr4 |= match feature {
+            CpuFeature::Xsave => Cr4::CR4_ENABLE_OS_XSAVE,
+            CpuFeature::Avx => Cr4::empty(),
+            CpuFeature::Smep => Cr4::CR4_ENABLE_SMEP,
+            CpuFeature::Smap => Cr4::CR4_ENABLE_SMAP,
+            CpuFeature::Umip => Cr4::CR4_ENABLE_UMIP,
+            CpuFeature::Pcid => Cr4::CR4_ENABLE_PCID,
+            CpuFeature::Fsgsbase => Cr4::CR4_ENABLE_FSGSBASE,
+        };
+    }
+    cr4
+}
+
+/// Returns the value of `XCR0` for the features, if XSAVE is among them.
+pub fn xcr0_for(features: CpuFeatures) -> Option<Xcr0> {
+    if !features.contains(CpuFeature::Xsave) {
+        return None;
+    }
+    let mut xcr0 = Xcr0::XCR0_FPU_MMX_STATE | Xcr0::XCR0_SSE_STATE;
+    if features.contains(CpuFeature::Avx) {
+        xcr0 |= Xcr0::XCR0_AVX_STATE;
+    }
+    Some(xcr0)
+}
@@ -0,0 +1,78 @@
+//! Driver for the main counter of the High Precision Event Timer (HPET),
+//! which serves as reference clock to calibrate other timers.
+
+/// Default physical address of the HPET registers. The actual address is
+/// described by the ACPI HPET table, but virtually all systems, including
+/// QEMU, use this one. Only used if there are no ACPI tables.
+pub const DEFAULT_BASE: u64 = 0xfed0_0000;
+
+const REG_CAPABILITIES: u64 = 0x0;
+const REG_CONFIGURATION: u64 = 0x10;
+const REG_MAIN_COUNTER: u64 = 0xf0;
+/// Capabilities: the main counter has 64 bits, not 32.
+const CAPABILITIES_COUNT_SIZE_64: u64 = 1 << 13;
+/// Configuration: the main counter runs.
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+/// The specification limits the period to 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+#[derive(Debug)]
+pub struct Hpet {
+    base: u64,
+    /// Period of the main counter in femtoseconds.
+    period_fs: u64,
+    /// Valid bits of the main counter.
+    counter_mask: u64,
+}
+
+impl Hpet {
+    /// Checks whether an HPET is at the given address and enables its main
+    /// counter. The address must be identity-mapped.
+    pub fn probe(base: u64) -> Option<Self> {
+        let mut hpet = Self {
+            base,
+            period_fs: 0,
+            counter_mask: 0,
+        };
+        let capabilities = hpet.read(REG_CAPABILITIES);
+        // Unbacked MMIO reads all ones, which is an invalid period.
+        hpet.period_fs = capabilities >> 32;
+        if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+            return None;
+        }
+        hpet.counter_mask = if capabilities & CAPABILITIES_COUNT_SIZE_64 != 0 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        let config = hpet.read(REG_CONFIGURATION);
+        hpet.write(REG_CONFIGURATION, config | CONFIGURATION_ENABLE);
+        Some(hpet)
+    }
+
+    /// Returns the frequency of the main counter in Hz.
+    pub fn frequency(&self) -> u64 {
+        FS_PER_SEC / self.period_fs
+    }
+
+    /// Returns the value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER) & self.counter_mask
+    }
+
+    /// Returns the ticks of the main counter since `start`, which is a
+    /// previous value of [`Self::counter`].
+    pub fn ticks_since(&self, start: u64) -> u64 {
+        self.counter().wrapping_sub(start) & self.counter_mask
+    }
+
+    fn read(&self, reg: u64) -> u64 {
+        unsafe { ((self.base + reg) as *const u64).read_volatile() }
+    }
+
+    fn write(&self, reg: u64, value: u64) {
+        unsafe { ((self.base + reg) as *mut u64).write_volatile(value) }
+    }
+}
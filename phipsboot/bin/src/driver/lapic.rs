@@ -0,0 +1,218 @@
+//! Minimal driver for the local APIC (LAPIC) of the bootstrap processor.
+//!
+//! The loader uses the LAPIC timer as free-running clock (see
+//! [`crate::time`]) and masks all local interrupt sources, so that the kernel
+//! starts with a known state. With `--smp`, it sends the IPIs that start the
+//! application processors. Both xAPIC (MMIO) and x2APIC (MSR) mode are
+//! supported; the mode that the firmware selected is kept.
+
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use x86::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
+
+/// `IA32_APIC_BASE`: the LAPIC is globally enabled.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// `IA32_APIC_BASE`: the LAPIC is in x2APIC mode.
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// `IA32_APIC_BASE`: physical base address of the xAPIC registers.
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Register offset of the ID register (xAPIC layout).
+const REG_ID: u32 = 0x20;
+/// Register offset of the version register (xAPIC layout).
+const REG_VERSION: u32 = 0x30;
+/// Register offsets of the interrupt command register (ICR) (xAPIC layout).
+/// In x2APIC mode, it is a single 64-bit MSR at the offset of the low half.
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+/// ICR: delivery mode INIT.
+const ICR_INIT: u32 = 0b101 << 8;
+/// ICR: delivery mode Start-Up.
+const ICR_STARTUP: u32 = 0b110 << 8;
+/// ICR: level assert.
+const ICR_ASSERT: u32 = 1 << 14;
+/// ICR: the IPI is not yet accepted. Only used in xAPIC mode.
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// Register offsets of the local vector table (LVT) entries (xAPIC layout),
+/// with the minimum "max LVT entry" value of the version register that
+/// indicates their presence.
+const LVT_REGS: [(u32, u32); 7] = [
+    (0x320, 0), // timer
+    (0x350, 0), // LINT0
+    (0x360, 0), // LINT1
+    (0x370, 0), // error
+    (0x340, 4), // performance monitoring counters
+    (0x330, 5), // thermal sensor
+    (0x2f0, 6), // corrected machine check (CMCI)
+];
+/// LVT entry: the interrupt is masked.
+const LVT_MASKED: u32 = 1 << 16;
+/// LVT timer entry: periodic mode.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Vector of the timer. It is never delivered, as the entry is masked, but a
+/// vector below 16 is illegal.
+const TIMER_VECTOR: u32 = 0xff;
+
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+/// Divide configuration: divide the timer clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// Initial count of the timer. It counts down from this value and restarts.
+pub const TIMER_INITIAL_COUNT: u32 = u32::MAX;
+
+/// Operating mode of the LAPIC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Disabled,
+    XApic,
+    X2Apic,
+}
+
+/// Returns the current mode of the LAPIC.
+pub fn mode() -> Mode {
+    if !has_apic() {
+        return Mode::Disabled;
+    }
+    let base = unsafe { rdmsr(IA32_APIC_BASE) };
+    match (base & APIC_BASE_ENABLE != 0, base & APIC_BASE_X2APIC != 0) {
+        (false, _) => Mode::Disabled,
+        (true, false) => Mode::XApic,
+        (true, true) => Mode::X2Apic,
+    }
+}
+
+/// Globally enables the LAPIC in xAPIC mode, if the firmware disabled it.
+/// Returns the resulting mode.
+pub fn enable() -> Mode {
+    if has_apic() && mode() == Mode::Disabled {
+        unsafe { wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE) };
+    }
+    mode()
+}
+
+/// Returns true if the CPU supports the x2APIC mode.
+pub fn has_x2apic() -> bool {
+    x86::cpuid::CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_x2apic())
+}
+
+/// Starts the timer as free-running counter: periodic mode from
+/// [`TIMER_INITIAL_COUNT`] with the interrupt masked. Returns false if the
+/// LAPIC is disabled or its registers are not accessible.
+pub fn start_timer() -> bool {
+    let mode = mode();
+    if mode == Mode::Disabled || (mode == Mode::XApic && xapic_base() >= IDENTITY_MAPPING_LIMIT) {
+        return false;
+    }
+    write(mode, REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(
+        mode,
+        REG_LVT_TIMER,
+        LVT_MASKED | LVT_TIMER_PERIODIC | TIMER_VECTOR,
+    );
+    write(mode, REG_TIMER_INITIAL_COUNT, TIMER_INITIAL_COUNT);
+    true
+}
+
+/// Returns the current count of the timer.
+pub fn timer_count() -> u32 {
+    read(mode(), REG_TIMER_CURRENT_COUNT)
+}
+
+/// Masks all entries of the local vector table. The mode of the LAPIC is
+/// unchanged.
+pub fn mask_all() {
+    let mode = mode();
+    if mode == Mode::Disabled {
+        return;
+    }
+    if mode == Mode::XApic && xapic_base() >= IDENTITY_MAPPING_LIMIT {
+        log::warn!("LAPIC registers at {:#x} are not mapped", xapic_base());
+        return;
+    }
+    let max_lvt = (read(mode, REG_VERSION) >> 16) & 0xff;
+    for (reg, min_max_lvt) in LVT_REGS {
+        if max_lvt >= min_max_lvt {
+            write(mode, reg, read(mode, reg) | LVT_MASKED);
+        }
+    }
+}
+
+/// Returns the APIC ID of the current processor.
+pub fn apic_id() -> u32 {
+    match mode() {
+        Mode::X2Apic => read(Mode::X2Apic, REG_ID),
+        mode => read(mode, REG_ID) >> 24,
+    }
+}
+
+/// Sends an INIT IPI to the processor with the given APIC ID.
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Sends a Start-Up IPI (SIPI) to the processor with the given APIC ID. The
+/// processor starts in real mode at `vector << 12`.
+pub fn send_startup(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | vector as u32);
+}
+
+/// Sends an IPI with the given low half of the ICR. In xAPIC mode, this waits
+/// until the IPI is accepted.
+fn send_ipi(apic_id: u32, icr: u32) {
+    match mode() {
+        Mode::X2Apic => unsafe {
+            wrmsr(
+                x2apic_msr(REG_ICR_LOW),
+                ((apic_id as u64) << 32) | icr as u64,
+            )
+        },
+        mode => {
+            write(mode, REG_ICR_HIGH, apic_id << 24);
+            write(mode, REG_ICR_LOW, icr);
+            while read(mode, REG_ICR_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+fn has_apic() -> bool {
+    x86::cpuid::CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_apic())
+}
+
+/// Reads a register. `reg` is the offset in the xAPIC layout; x2APIC MSRs
+/// are derived from it.
+fn read(mode: Mode, reg: u32) -> u32 {
+    match mode {
+        Mode::X2Apic => unsafe { rdmsr(x2apic_msr(reg)) as u32 },
+        _ => unsafe { xapic_reg(reg).read_volatile() },
+    }
+}
+
+fn write(mode: Mode, reg: u32, value: u32) {
+    match mode {
+        Mode::X2Apic => unsafe { wrmsr(x2apic_msr(reg), value as u64) },
+        _ => unsafe { xapic_reg(reg).write_volatile(value) },
+    }
+}
+
+fn x2apic_msr(reg: u32) -> u32 {
+    0x800 + (reg >> 4)
+}
+
+/// Returns the physical base address of the xAPIC registers.
+pub fn xapic_base() -> u64 {
+    unsafe { rdmsr(IA32_APIC_BASE) & APIC_BASE_ADDR_MASK }
+}
+
+/// Returns a pointer to an xAPIC register. The registers are usually below
+/// 4 GiB and thus identity-mapped.
+fn xapic_reg(reg: u32) -> *mut u32 {
+    (xapic_base() + reg as u64) as *mut u32
+}
@@ -1,4 +1,8 @@
 mod debugcon;
+pub mod hpet;
+pub mod lapic;
+pub mod pic;
+pub mod pit;
 mod serial;
 
 pub use debugcon::*;
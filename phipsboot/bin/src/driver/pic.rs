@@ -0,0 +1,101 @@
+//! Driver for the legacy 8259 programmable interrupt controllers (PICs).
+//!
+//! The firmware might leave the PICs with their IRQs mapped to the vectors of
+//! CPU exceptions (0x08 to 0x0f). The loader remaps them to dedicated vectors
+//! and masks all IRQs, so that neither the loader nor the kernel receives
+//! legacy interrupts unless the kernel unmasks them.
+
+use x86::io::{inb, outb};
+
+/// First vector of the IRQs of the primary PIC (IRQ 0 to 7).
+pub const PRIMARY_VECTOR_BASE: u8 = 0x20;
+/// First vector of the IRQs of the secondary PIC (IRQ 8 to 15).
+pub const SECONDARY_VECTOR_BASE: u8 = 0x28;
+
+const PRIMARY_COMMAND: u16 = 0x20;
+const PRIMARY_DATA: u16 = 0x21;
+const SECONDARY_COMMAND: u16 = 0xa0;
+const SECONDARY_DATA: u16 = 0xa1;
+
+/// ICW1: initialization, ICW4 follows.
+const ICW1_INIT: u8 = 0x11;
+/// ICW3 of the primary PIC: the secondary PIC is connected to IRQ 2.
+const ICW3_PRIMARY: u8 = 1 << 2;
+/// ICW3 of the secondary PIC: its cascade identity.
+const ICW3_SECONDARY: u8 = 2;
+/// ICW4: 8086 mode.
+const ICW4_8086: u8 = 0x01;
+/// OCW3: read the in-service register with the next read of the command port.
+const OCW3_READ_ISR: u8 = 0x0b;
+/// OCW2: non-specific end of interrupt.
+const OCW2_EOI: u8 = 0x20;
+
+/// Remaps the IRQs of both PICs to [`PRIMARY_VECTOR_BASE`] and
+/// [`SECONDARY_VECTOR_BASE`] and masks all of them.
+pub fn init() {
+    unsafe {
+        outb(PRIMARY_COMMAND, ICW1_INIT);
+        io_wait();
+        outb(SECONDARY_COMMAND, ICW1_INIT);
+        io_wait();
+        outb(PRIMARY_DATA, PRIMARY_VECTOR_BASE);
+        io_wait();
+        outb(SECONDARY_DATA, SECONDARY_VECTOR_BASE);
+        io_wait();
+        outb(PRIMARY_DATA, ICW3_PRIMARY);
+        io_wait();
+        outb(SECONDARY_DATA, ICW3_SECONDARY);
+        io_wait();
+        outb(PRIMARY_DATA, ICW4_8086);
+        io_wait();
+        outb(SECONDARY_DATA, ICW4_8086);
+        io_wait();
+
+        outb(PRIMARY_DATA, 0xff);
+        outb(SECONDARY_DATA, 0xff);
+    }
+}
+
+/// Returns the IRQ of a vector, if it belongs to one of the PICs.
+pub fn irq(vector: u8) -> Option<u8> {
+    (PRIMARY_VECTOR_BASE..SECONDARY_VECTOR_BASE + 8)
+        .contains(&vector)
+        .then(|| vector - PRIMARY_VECTOR_BASE)
+}
+
+/// Returns true if the IRQ is spurious, i.e., IRQ 7 or 15 without the
+/// corresponding bit in the in-service register.
+pub fn is_spurious(irq: u8) -> bool {
+    match irq {
+        7 => read_isr(PRIMARY_COMMAND) & (1 << 7) == 0,
+        15 => read_isr(SECONDARY_COMMAND) & (1 << 7) == 0,
+        _ => false,
+    }
+}
+
+/// Acknowledges an IRQ. For a spurious IRQ 15, only the primary PIC is
+/// acknowledged, as it doesn't know that the IRQ was spurious.
+pub fn end_of_interrupt(irq: u8) {
+    let spurious = is_spurious(irq);
+    unsafe {
+        if irq >= 8 && !spurious {
+            outb(SECONDARY_COMMAND, OCW2_EOI);
+        }
+        if irq != 7 || !spurious {
+            outb(PRIMARY_COMMAND, OCW2_EOI);
+        }
+    }
+}
+
+fn read_isr(command_port: u16) -> u8 {
+    unsafe {
+        outb(command_port, OCW3_READ_ISR);
+        inb(command_port)
+    }
+}
+
+/// Gives the PIC time to process a command on old hardware. Port 0x80 is the
+/// POST code port, which is unused.
+fn io_wait() {
+    unsafe { outb(0x80, 0) }
+}
@@ -0,0 +1,49 @@
+//! Driver for channel 2 of the legacy programmable interval timer (PIT), which
+//! serves as reference clock to calibrate other timers.
+//!
+//! Channel 2 is used, as its gate and output are controlled by software via
+//! port 0x61 and it doesn't raise interrupts.
+
+use core::time::Duration;
+use x86::io::{inb, outb};
+
+/// Frequency of the PIT in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Controls the gate of channel 2 (bit 0) and the PC speaker (bit 1), and
+/// reports the output of channel 2 (bit 5).
+const CONTROL: u16 = 0x61;
+const CONTROL_GATE_2: u8 = 1 << 0;
+const CONTROL_SPEAKER: u8 = 1 << 1;
+const CONTROL_OUT_2: u8 = 1 << 5;
+
+/// Command: channel 2, low byte then high byte, mode 0 (interrupt on
+/// terminal count), binary.
+const COMMAND_CHANNEL_2_ONESHOT: u8 = 0b1011_0000;
+
+/// Upper bound of polls of the output until the PIT is considered absent.
+/// Each poll is an I/O port access, which takes roughly a microsecond.
+const MAX_POLLS: u64 = 10_000_000;
+
+/// Starts a one-shot countdown of the given duration on channel 2. The
+/// duration must be below 55 ms (`u16::MAX` ticks).
+pub fn start_oneshot(duration: Duration) {
+    let ticks = lib::time::duration_to_ticks(duration, FREQUENCY);
+    let ticks = u16::try_from(ticks).expect("duration should fit into the counter");
+    unsafe {
+        // The gate must be high for the counter to run, the speaker stays off.
+        let control = inb(CONTROL);
+        outb(CONTROL, (control & !CONTROL_SPEAKER) | CONTROL_GATE_2);
+        outb(COMMAND, COMMAND_CHANNEL_2_ONESHOT);
+        outb(CHANNEL_2_DATA, ticks as u8);
+        outb(CHANNEL_2_DATA, (ticks >> 8) as u8);
+    }
+}
+
+/// Polls until the countdown started by [`start_oneshot`] has finished.
+/// Returns false if it doesn't finish, because there is no PIT.
+pub fn wait_oneshot() -> bool {
+    (0..MAX_POLLS).any(|_| unsafe { inb(CONTROL) } & CONTROL_OUT_2 != 0)
+}
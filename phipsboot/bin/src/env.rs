@@ -1,10 +1,18 @@
 //! Everything regarding the environment of the kernel.
 
-use core::cell::OnceCell;
+use alloc::boxed::Box;
+use core::cell::{Cell, OnceCell};
+use core::str::FromStr;
+use lib::cli::CliArgs;
 use lib::safe::Safe;
+use multiboot2::{BootInformation, BootInformationHeader};
 
 static BOOT_VARIANT: Safe<OnceCell<BootVariant>> = Safe::new(OnceCell::new());
 static BOOT_INFO_PTR: Safe<OnceCell<u64>> = Safe::new(OnceCell::new());
+/// The Multiboot2 boot information. It changes if the loader moves it out of
+/// the way of the kernel.
+static MBI: Safe<Cell<Option<&'static BootInformation<'static>>>> = Safe::new(Cell::new(None));
+static CLI_ARGS: Safe<OnceCell<CliArgs>> = Safe::new(OnceCell::new());
 
 #[derive(Debug)]
 enum BootVariant {
@@ -30,6 +38,63 @@ pub fn init(bootloader_magic: u64, bootloader_info_ptr: u64) {
     }
     BOOT_VARIANT.get_or_init(|| boot_variant);
     BOOT_INFO_PTR.get_or_init(|| bootloader_info_ptr);
+
+    if let BootVariant::Multiboot2 = BOOT_VARIANT.get().unwrap() {
+        // The boot information lives below 4 GiB and is identity-mapped.
+        let ptr = bootloader_info_ptr as *const BootInformationHeader;
+        let mbi = unsafe { BootInformation::load(ptr) }
+            .unwrap_or_else(|e| panic!("Invalid Multiboot2 boot information: {e:?}"));
+        MBI.set(Some(Box::leak(Box::new(mbi))));
+    }
+
+    let cmdline = cmdline();
+    let cli_args = CliArgs::from_str(cmdline)
+        .unwrap_or_else(|_| panic!("Invalid command line: {cmdline:?}"));
+    CLI_ARGS.get_or_init(|| cli_args);
+}
+
+/// Returns the Multiboot2 boot information, if the loader was booted via
+/// Multiboot2.
+pub fn mbi() -> Option<&'static BootInformation<'static>> {
+    MBI.get()
+}
+
+/// Returns the address of the `hvm_start_info` structure, if the loader was
+/// booted via Xen PVH.
+pub fn xen_pvh_start_info() -> Option<u64> {
+    match BOOT_VARIANT.get()? {
+        BootVariant::XenPvh => BOOT_INFO_PTR.get().copied(),
+        _ => None,
+    }
+}
+
+/// Copies the Multiboot2 boot information to `dest`, which must be free
+/// memory within the identity mapping, and uses the copy from now on.
+pub fn relocate_mbi(dest: u64) {
+    let mbi = mbi().expect("should have been booted via Multiboot2");
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            mbi.start_address() as *const u8,
+            dest as *mut u8,
+            mbi.total_size(),
+        )
+    };
+    let mbi = unsafe { BootInformation::load(dest as *const BootInformationHeader) }
+        .expect("should be a valid copy");
+    MBI.set(Some(Box::leak(Box::new(mbi))));
+}
+
+/// Returns the command line of the loader.
+pub fn cmdline() -> &'static str {
+    mbi()
+        .and_then(|mbi| mbi.command_line_tag())
+        .and_then(|tag| tag.cmdline().ok())
+        .unwrap_or("")
+}
+
+/// Returns the parsed command line of the loader.
+pub fn cli_args() -> &'static CliArgs {
+    CLI_ARGS.get().expect("should have been initialized")
 }
 
 /// Trace-print all relevant symbols.
@@ -37,6 +102,7 @@ pub fn init(bootloader_magic: u64, bootloader_info_ptr: u64) {
 pub fn print() {
     log::debug!("PhipsBoot was loaded via   {:?}", BOOT_VARIANT.get().unwrap());
     log::debug!("              boot info at {:#016x} (phys)", BOOT_INFO_PTR.get().unwrap());
+    log::debug!("             command line {:?}", cmdline());
     log::debug!("          expected load at {:#016x} (phys)", crate::extern_symbols::link_addr_boot() as u64);
     log::debug!("            actual load at {:#016x} (phys)", load_addr());
     log::debug!("              with offset {}{:#x}",
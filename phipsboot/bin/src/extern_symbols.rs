@@ -91,6 +91,45 @@ mod other {
 
         #[link_name = "LINK_ADDR_RW"]
         static LINK_ADDR_RW: [u64; 0];
+
+        #[link_name = "COUNT_PAGES_RX"]
+        static COUNT_PAGES_RX: [u64; 0];
+
+        #[link_name = "COUNT_PAGES_RO"]
+        static COUNT_PAGES_RO: [u64; 0];
+
+        #[link_name = "COUNT_PAGES_RW"]
+        static COUNT_PAGES_RW: [u64; 0];
+
+        #[link_name = "STACK_GUARD_PAGE"]
+        static STACK_GUARD_PAGE: [u64; 0];
+
+        #[link_name = "BIN_SIZE"]
+        static BIN_SIZE: [u64; 0];
+
+        #[link_name = "exception_stubs"]
+        static EXCEPTION_STUBS: [u64; 0];
+
+        #[link_name = "interrupt_stubs"]
+        static INTERRUPT_STUBS: [u8; 0];
+
+        #[link_name = "symbols"]
+        static SYMBOLS: [u8; 0];
+
+        #[link_name = "symbols_end"]
+        static SYMBOLS_END: [u8; 0];
+
+        #[link_name = "trampoline"]
+        static TRAMPOLINE: [u64; 0];
+
+        #[link_name = "trampoline_end"]
+        static TRAMPOLINE_END: [u64; 0];
+
+        #[link_name = "ap_startup"]
+        static AP_STARTUP: [u64; 0];
+
+        #[link_name = "ap_startup_end"]
+        static AP_STARTUP_END: [u64; 0];
     }
 
     pub fn link_addr_boot() -> *const u8 {
@@ -112,4 +151,70 @@ mod other {
     pub fn link_addr_rw() -> *const u8 {
         (unsafe { LINK_ADDR_RW.as_ptr() }).cast()
     }
+
+    pub fn count_pages_rx() -> u64 {
+        (unsafe { COUNT_PAGES_RX.as_ptr() }) as u64
+    }
+
+    pub fn count_pages_ro() -> u64 {
+        (unsafe { COUNT_PAGES_RO.as_ptr() }) as u64
+    }
+
+    pub fn count_pages_rw() -> u64 {
+        (unsafe { COUNT_PAGES_RW.as_ptr() }) as u64
+    }
+
+    /// The unmapped page below the stack of the loader. It is the first page
+    /// of the RW segment.
+    pub fn stack_guard_page() -> *const u8 {
+        (unsafe { STACK_GUARD_PAGE.as_ptr() }).cast()
+    }
+
+    /// Size of the whole binary in memory, starting at [`link_addr_boot`].
+    pub fn bin_size() -> u64 {
+        (unsafe { BIN_SIZE.as_ptr() }) as u64
+    }
+
+    /// Returns the address of the entry stub of the exception with the given
+    /// vector (0 to 31).
+    pub fn exception_stub(vector: u8) -> u64 {
+        assert!(vector < 32);
+        unsafe { EXCEPTION_STUBS.as_ptr().add(vector as usize).read() }
+    }
+
+    /// Returns the address of the entry stub of the interrupt with the given
+    /// vector (32 to 255).
+    pub fn interrupt_stub(vector: u8) -> u64 {
+        // Must match INTERRUPT_STUB_SIZE in `exceptions.S`.
+        const STUB_SIZE: u64 = 16;
+        assert!(vector >= 32);
+        (unsafe { INTERRUPT_STUBS.as_ptr() }) as u64 + (vector as u64 - 32) * STUB_SIZE
+    }
+
+    /// The embedded symbol table (see [`lib::symbols`]). It is all zeroes if
+    /// the table was not written into the binary after linking.
+    pub fn symbols() -> &'static [u8] {
+        unsafe {
+            let start = SYMBOLS.as_ptr();
+            let len = SYMBOLS_END.as_ptr() as usize - start as usize;
+            core::slice::from_raw_parts(start, len)
+        }
+    }
+
+    pub fn trampoline() -> *const u8 {
+        (unsafe { TRAMPOLINE.as_ptr() }).cast()
+    }
+
+    pub fn trampoline_end() -> *const u8 {
+        (unsafe { TRAMPOLINE_END.as_ptr() }).cast()
+    }
+
+    /// The startup code of the application processors (see `ap_startup.S`).
+    pub fn ap_startup() -> *const u8 {
+        (unsafe { AP_STARTUP.as_ptr() }).cast()
+    }
+
+    pub fn ap_startup_end() -> *const u8 {
+        (unsafe { AP_STARTUP_END.as_ptr() }).cast()
+    }
 }
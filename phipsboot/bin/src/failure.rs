@@ -0,0 +1,96 @@
+//! Failure policy of the loader: what happens after a fatal error, such as a
+//! panic or an exception (`--on-error=`).
+
+use crate::driver::{DebugconLogger, SerialLogger};
+use core::cell::OnceCell;
+use core::fmt::{Arguments, Write};
+use lib::cli::OnError;
+use lib::logger;
+use lib::safe::Safe;
+use x86::io::{inb, outb, outl};
+
+/// I/O port of the `isa-debug-exit` device of QEMU, as configured in
+/// `run_iso_in_qemu.sh`.
+const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// Value that is written to [`QEMU_EXIT_PORT`]. QEMU exits with
+/// `(value << 1) | 1`, i.e., 3.
+const QEMU_EXIT_FAILURE: u32 = 1;
+
+/// Command and status port of the 8042 keyboard controller.
+const KBC_PORT: u16 = 0x64;
+/// Status bit: the input buffer of the controller is full.
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// Command: pulse the reset line of the CPU.
+const KBC_RESET: u8 = 0xfe;
+
+/// Reset control register of the chipset. This is what the ACPI reset register
+/// of the FADT points to on most systems, including QEMU's Q35.
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+/// Value for [`RESET_CONTROL_PORT`]: full reset (CPU and system).
+const RESET_CONTROL_FULL: u8 = 0x06;
+
+/// The policy from the command line. Unset until it was parsed.
+static POLICY: Safe<OnceCell<OnError>> = Safe::new(OnceCell::new());
+
+/// Sets the failure policy. Until then, the loader halts on fatal errors.
+pub fn init(policy: OnError) {
+    let _ = POLICY.set(policy);
+}
+
+/// Applies the failure policy. Called at the end of the panic and the
+/// exception handler.
+pub fn fail() -> ! {
+    let policy = POLICY.get().copied().unwrap_or_default();
+    // The regular logging might be broken at this point.
+    emergency_println(format_args!(
+        "Fatal error, applying policy --on-error={policy}"
+    ));
+    match policy {
+        OnError::Halt => {}
+        OnError::Reboot => reboot(),
+        OnError::QemuExit => unsafe { outl(QEMU_EXIT_PORT, QEMU_EXIT_FAILURE) },
+    }
+    // Also the fallback if reboot or exit didn't work.
+    halt()
+}
+
+/// Writes a line to all logger backends for a fatal error, which ends the
+/// execution. Falls back to debugcon and the serial port if there are no
+/// backends yet or if the error happened in the emergency path itself.
+pub fn emergency_println(args: Arguments) {
+    if !logger::emergency_write(format_args!("{args}\n")) {
+        let _ = writeln!(DebugconLogger, "{args}");
+        let _ = writeln!(SerialLogger::default(), "{args}");
+    }
+}
+
+/// Halts the CPU forever with interrupts disabled. NMIs may still wake up
+/// the CPU, so this is a loop.
+pub fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
+
+/// Tries to reset the machine via the keyboard controller, then via the reset
+/// control register, and finally with a triple fault.
+fn reboot() {
+    unsafe {
+        // Bounded, as the controller might not exist.
+        for _ in 0..0x10000 {
+            if inb(KBC_PORT) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        outb(KBC_PORT, KBC_RESET);
+
+        outb(RESET_CONTROL_PORT, RESET_CONTROL_FULL);
+
+        // An exception without a valid IDT escalates to a triple fault, which
+        // resets the CPU.
+        let idt = x86::dtables::DescriptorTablePointer::<u64>::default();
+        x86::dtables::lidt(&idt);
+        core::arch::asm!("int3");
+    }
+}
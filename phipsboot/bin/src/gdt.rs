@@ -0,0 +1,131 @@
+//! The GDT and the TSS of the loader, and their construction, which the GDT of
+//! the kernel ([`crate::loader`]) shares.
+//!
+//! The GDT of the boot code only has a code segment. The loader replaces it
+//! with a GDT that also holds a TSS, so that exceptions that indicate a broken
+//! stack, such as a double fault after a stack overflow, run on a stack of
+//! their own (IST).
+//!
+//! The layout of the GDT is fixed, so that the selectors never change:
+//!
+//! | Selector | Segment                                |
+//! |----------|----------------------------------------|
+//! | `0x00`   | null                                   |
+//! | `0x08`   | kernel code (64-bit)                   |
+//! | `0x10`   | kernel data                            |
+//! | `0x1b`   | user data (null without user segments) |
+//! | `0x23`   | user code (null without user segments) |
+//! | `0x28`   | TSS (64-bit, two entries)              |
+//!
+//! The user segments follow the layout that `sysret` expects. The loader
+//! itself has no user segments.
+
+use core::cell::OnceCell;
+use core::ptr::addr_of;
+use lib::mem::stack::Stack;
+use lib::safe::Safe;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::segmentation::SegmentSelector;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// IST index of the double-fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// IST index of the NMI handler.
+pub const NMI_IST_INDEX: u16 = 1;
+/// IST index of the machine-check handler.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Number of IST stacks in the TSS. They are IST1 to IST3.
+pub const IST_STACK_COUNT: usize = 3;
+/// Size of each IST stack.
+pub const IST_STACK_SIZE: usize = 0x4000 /* 16 KiB */;
+
+const EMPTY_STACK: Stack<IST_STACK_SIZE> = Stack::new();
+
+/// Backing memory of the IST stacks.
+static mut IST_STACKS: [Stack<IST_STACK_SIZE>; IST_STACK_COUNT] = [EMPTY_STACK; IST_STACK_COUNT];
+
+static TSS: Safe<OnceCell<TaskStateSegment>> = Safe::new(OnceCell::new());
+
+static GDT: Safe<OnceCell<(GlobalDescriptorTable, Selectors)>> = Safe::new(OnceCell::new());
+
+/// The selectors of a GDT from [`new_gdt`].
+#[derive(Debug, Copy, Clone)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+/// Creates and loads the GDT and the TSS and reloads the segment registers.
+pub fn init() {
+    let tss = TSS.get_or_init(|| {
+        let stacks = unsafe { &*addr_of!(IST_STACKS) };
+        new_tss(core::array::from_fn(|i| stacks[i].adjusted_top() as u64))
+    });
+    let (gdt, selectors) = GDT.get_or_init(|| new_gdt(tss, false));
+    unsafe { load(gdt, selectors) };
+}
+
+/// Returns a TSS whose IST entries point to the given stack tops.
+pub fn new_tss(ist_stack_tops: [u64; IST_STACK_COUNT]) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    for (i, top) in ist_stack_tops.into_iter().enumerate() {
+        tss.interrupt_stack_table[i] = VirtAddr::new(top);
+    }
+    tss
+}
+
+/// Returns a GDT with the fixed layout that references the TSS.
+pub fn new_gdt(
+    tss: &'static TaskStateSegment,
+    user_segments: bool,
+) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    if user_segments {
+        gdt.add_entry(Descriptor::user_data_segment());
+        gdt.add_entry(Descriptor::user_code_segment());
+    } else {
+        gdt.add_entry(Descriptor::UserSegment(0));
+        gdt.add_entry(Descriptor::UserSegment(0));
+    }
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    let selectors = Selectors {
+        kernel_code,
+        kernel_data,
+        tss,
+    };
+    (gdt, selectors)
+}
+
+/// Loads the GDT and the TSS and reloads all segment registers. `%fs` and
+/// `%gs` become null.
+///
+/// # Safety
+/// The selectors must belong to the GDT, and the TSS descriptor must not be
+/// marked as busy.
+pub unsafe fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    gdt.load();
+    load_segments(selectors);
+}
+
+/// Reloads all segment registers and the TSS from the currently loaded GDT.
+/// `%fs` and `%gs` become null.
+///
+/// # Safety
+/// The selectors must belong to the loaded GDT, and the TSS descriptor must not
+/// be marked as busy.
+pub unsafe fn load_segments(selectors: &Selectors) {
+    CS::set_reg(selectors.kernel_code);
+    SS::set_reg(selectors.kernel_data);
+    DS::set_reg(selectors.kernel_data);
+    ES::set_reg(selectors.kernel_data);
+    FS::set_reg(SegmentSelector(0));
+    GS::set_reg(SegmentSelector(0));
+    load_tss(selectors.tss);
+}
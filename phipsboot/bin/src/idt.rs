@@ -1,4 +1,13 @@
+use crate::backtrace::Backtrace;
+use crate::driver::{pic, DebugconLogger, SerialLogger};
+use crate::extern_symbols::{exception_stub, interrupt_stub};
+use crate::failure::emergency_println;
+use crate::gdt;
+use crate::mem::paging;
 use core::cell::RefCell;
+use core::fmt::{Arguments, Write};
+use lib::exception::{self, HexDump, PageFaultError, SelectorError};
+use lib::logger;
 use lib::safe::Safe;
 use x86_64::structures::idt::*;
 
@@ -6,211 +15,247 @@ type IDT = Safe<RefCell<InterruptDescriptorTable>>;
 
 static IDT: IDT = Safe::new(RefCell::new(InterruptDescriptorTable::new()));
 
-/// Initializes the Interrupt Descriptor Table (IDT).
-pub fn init() {
-    let mut idt = IDT.borrow_mut();
-    idt.divide_error.set_handler_fn(exception_handlers::divide);
-    idt.debug.set_handler_fn(exception_handlers::debug);
-    idt.non_maskable_interrupt
-        .set_handler_fn(exception_handlers::nmi);
-    idt.breakpoint
-        .set_handler_fn(exception_handlers::breakpoint);
-    idt.overflow.set_handler_fn(exception_handlers::overflow);
-    idt.bound_range_exceeded
-        .set_handler_fn(exception_handlers::bound_range_exceeded);
-    idt.invalid_opcode
-        .set_handler_fn(exception_handlers::invalid_opcode);
-    idt.device_not_available
-        .set_handler_fn(exception_handlers::device_not_available);
-    idt.double_fault
-        .set_handler_fn(exception_handlers::double_fault);
-    idt.invalid_tss
-        .set_handler_fn(exception_handlers::invalid_tss);
-    idt.segment_not_present
-        .set_handler_fn(exception_handlers::segment_not_present);
-    idt.stack_segment_fault
-        .set_handler_fn(exception_handlers::stack_segment_fault);
-    idt.general_protection_fault
-        .set_handler_fn(exception_handlers::general_protection_fault);
-    idt.page_fault
-        .set_handler_fn(exception_handlers::page_fault);
-    idt.x87_floating_point
-        .set_handler_fn(exception_handlers::x87_floating_point);
-    idt.alignment_check
-        .set_handler_fn(exception_handlers::alignment_check);
-    idt.machine_check
-        .set_handler_fn(exception_handlers::machine_check);
-    idt.simd_floating_point
-        .set_handler_fn(exception_handlers::simd_floating_point);
-    idt.virtualization
-        .set_handler_fn(exception_handlers::virtualization);
-    idt.cp_protection_exception
-        .set_handler_fn(exception_handlers::cp_protection_exception);
-    idt.hv_injection_exception
-        .set_handler_fn(exception_handlers::hv_injection_exception);
-    idt.vmm_communication_exception
-        .set_handler_fn(exception_handlers::vmm_communication_exception);
-    idt.security_exception
-        .set_handler_fn(exception_handlers::security_exception);
-
-    /* TODO add interrupt handlers.
-     for i in 0..256 /* vectors */ - 32 /* exceptions */ {
-        idt[i] = interrupt_handler;
-    }*/
+/// Number of bytes before and after the faulting instruction that are
+/// dumped.
+const CODE_DUMP_RANGE: u64 = 16;
 
-    unsafe {
-        idt.load_unsafe();
-    }
+/// Registers at the time of an exception or interrupt, as saved by the entry
+/// stubs in `exceptions.S`.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    vector: u64,
+    /// The error code of the exception, or 0 if it has none.
+    error_code: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
 }
 
-mod exception_handlers {
-    use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
-
-    pub extern "x86-interrupt" fn divide(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x0 division error, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn debug(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x1 debug, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn nmi(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x2 nmi, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x3 breakpoint, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn overflow(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x4 overflow, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn bound_range_exceeded(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x5 bound_range_exceeded, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn invalid_opcode(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x6 invalid_opcode, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn device_not_available(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x7 device_not_available, stack_frame={stack_frame:#?}");
-        loop {}
-    }
-
-    pub extern "x86-interrupt" fn double_fault(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) -> ! {
-        panic!(
-            "exception: 0x8 double_fault, error_code={error_code:?}, stack_frame={stack_frame:#?}"
-        );
-
-        // TODO triple fault detection
-    }
+/// Sets the entry stub of the exception with the given vector and,
+/// optionally, the index of its IST stack.
+macro_rules! set_stub {
+    ($entry:expr, $vector:literal) => {
+        unsafe { $entry.set_handler_addr(x86_64::VirtAddr::new(exception_stub($vector))) }
+    };
+    ($entry:expr, $vector:literal, $ist_index:expr) => {
+        unsafe {
+            $entry
+                .set_handler_addr(x86_64::VirtAddr::new(exception_stub($vector)))
+                .set_stack_index($ist_index)
+        }
+    };
+}
 
-    pub extern "x86-interrupt" fn invalid_tss(stack_frame: InterruptStackFrame, error_code: u64) {
-        log::error!(
-            "exception: 0xa invalid_tss, error_code={error_code:?}, stack_frame={stack_frame:#?}"
-        );
-        loop {}
-    }
+/// Initializes the Interrupt Descriptor Table (IDT).
+pub fn init() {
+    let mut idt = IDT.borrow_mut();
+    set_stub!(idt.divide_error, 0);
+    set_stub!(idt.debug, 1);
+    set_stub!(idt.non_maskable_interrupt, 2, gdt::NMI_IST_INDEX);
+    set_stub!(idt.breakpoint, 3);
+    set_stub!(idt.overflow, 4);
+    set_stub!(idt.bound_range_exceeded, 5);
+    set_stub!(idt.invalid_opcode, 6);
+    set_stub!(idt.device_not_available, 7);
+    set_stub!(idt.double_fault, 8, gdt::DOUBLE_FAULT_IST_INDEX);
+    set_stub!(idt.invalid_tss, 10);
+    set_stub!(idt.segment_not_present, 11);
+    set_stub!(idt.stack_segment_fault, 12);
+    set_stub!(idt.general_protection_fault, 13);
+    set_stub!(idt.page_fault, 14);
+    set_stub!(idt.x87_floating_point, 16);
+    set_stub!(idt.alignment_check, 17);
+    set_stub!(idt.machine_check, 18, gdt::MACHINE_CHECK_IST_INDEX);
+    set_stub!(idt.simd_floating_point, 19);
+    set_stub!(idt.virtualization, 20);
+    set_stub!(idt.cp_protection_exception, 21);
+    set_stub!(idt.hv_injection_exception, 28);
+    set_stub!(idt.vmm_communication_exception, 29);
+    set_stub!(idt.security_exception, 30);
 
-    pub extern "x86-interrupt" fn segment_not_present(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        log::error!("exception: 0xb segment_not_present, error_code={error_code:?}, stack_frame={stack_frame:#?}");
-        loop {}
+    for vector in 32..=255 {
+        let addr = x86_64::VirtAddr::new(interrupt_stub(vector));
+        unsafe { idt[vector as usize].set_handler_addr(addr) };
     }
 
-    pub extern "x86-interrupt" fn stack_segment_fault(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        log::error!("exception: 0xc stack_segment_fault, error_code={error_code:?}, stack_frame={stack_frame:#?}");
-        loop {}
+    unsafe {
+        idt.load_unsafe();
     }
+}
 
-    pub extern "x86-interrupt" fn general_protection_fault(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        log::error!("exception: 0xd general_protection_fault, error_code={error_code:?}, stack_frame={stack_frame:#?}");
-        loop {}
-    }
+/// Common handler of all exceptions, called by the entry stubs. The NMI,
+/// double-fault, and machine-check handlers run on an IST stack, so they also
+/// work if the stack of the loader overflowed.
+#[no_mangle]
+extern "C" fn exception_handler(frame: &ExceptionFrame) -> ! {
+    report(frame);
+    crate::failure::fail()
+}
 
-    pub extern "x86-interrupt" fn page_fault(
-        stack_frame: InterruptStackFrame,
-        error_code: PageFaultErrorCode,
-    ) {
-        log::error!(
-            "exception: 0xe page_fault, error_code={error_code:?}, stack_frame={stack_frame:#?}"
-        );
-        loop {}
+/// Common handler of all interrupts (vectors 32 to 255), called by the entry
+/// stubs. The loader runs with interrupts disabled and all IRQs masked, so
+/// only spurious interrupts and software interrupts end up here. They are
+/// reported and acknowledged at the PIC, if they come from it.
+#[no_mangle]
+extern "C" fn interrupt_handler(frame: &ExceptionFrame) {
+    let vector = frame.vector as u8;
+    if let Some(irq) = pic::irq(vector) {
+        let kind = if pic::is_spurious(irq) {
+            "spurious"
+        } else {
+            "unexpected"
+        };
+        interrupt_println(format_args!(
+            "{kind} interrupt: vector {vector:#x} (PIC IRQ {irq}), rip={:#x}",
+            frame.rip
+        ));
+        pic::end_of_interrupt(irq);
+    } else {
+        interrupt_println(format_args!(
+            "unexpected interrupt: vector {vector:#x}, rip={:#x}",
+            frame.rip
+        ));
     }
+}
 
-    pub extern "x86-interrupt" fn x87_floating_point(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x10 x87_floating_point, stack_frame={stack_frame:#?}");
-        loop {}
+/// Writes a line to all logger backends without the regular logging, which
+/// the interrupt might have interrupted. Unlike [`emergency_println`], this
+/// never forces access to a borrowed logger, as the interrupted code resumes.
+/// Falls back to debugcon and the serial port instead.
+fn interrupt_println(args: Arguments) {
+    if !logger::try_write(format_args!("{args}\n")) {
+        let _ = writeln!(DebugconLogger, "{args}");
+        let _ = writeln!(SerialLogger::default(), "{args}");
     }
+}
 
-    pub extern "x86-interrupt" fn alignment_check(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        log::error!("exception: 0x11 alignment_check, error_code={error_code:?}, stack_frame={stack_frame:#?}");
-        loop {}
-    }
+/// Writes everything that is known about the exception via the emergency path,
+/// as the exception might have interrupted the logger.
+fn report(frame: &ExceptionFrame) {
+    let vector = frame.vector;
+    let cr2 = unsafe { x86::controlregs::cr2() } as u64;
+    emergency_println(format_args!(
+        "exception: {vector:#x} {}, error_code={:#x}",
+        exception::name(vector),
+        frame.error_code
+    ));
 
-    pub extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) -> ! {
-        panic!("exception: 0x12 machine_check, stack_frame={stack_frame:#?}");
+    if vector == 14 {
+        // This is only reached if the CPU could still push the exception
+        // frame. Otherwise, the overflow escalates to a double fault.
+        let note = if crate::mem::stack::guard_page().contains(&cr2) {
+            " (stack overflow)"
+        } else {
+            ""
+        };
+        emergency_println(format_args!(
+            "  page fault at {cr2:#x}{note}: {}",
+            PageFaultError(frame.error_code)
+        ));
+    } else if exception::has_selector_error_code(vector) && frame.error_code != 0 {
+        emergency_println(format_args!(
+            "  caused by {}",
+            SelectorError(frame.error_code)
+        ));
     }
 
-    pub extern "x86-interrupt" fn simd_floating_point(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x13 simd_floating_point, stack_frame={stack_frame:#?}");
-        loop {}
-    }
+    emergency_println(format_args!(
+        "  rip={:#018x} cs={:#06x} rflags={:#010x}",
+        frame.rip, frame.cs, frame.rflags
+    ));
+    emergency_println(format_args!(
+        "  rsp={:#018x} ss={:#06x}{}",
+        frame.rsp,
+        frame.ss,
+        guard_page_note(frame.rsp)
+    ));
+    emergency_println(format_args!(
+        "  rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx
+    ));
+    emergency_println(format_args!(
+        "  rsi={:#018x} rdi={:#018x} rbp={:#018x} r8 ={:#018x}",
+        frame.rsi, frame.rdi, frame.rbp, frame.r8
+    ));
+    emergency_println(format_args!(
+        "  r9 ={:#018x} r10={:#018x} r11={:#018x} r12={:#018x}",
+        frame.r9, frame.r10, frame.r11, frame.r12
+    ));
+    emergency_println(format_args!(
+        "  r13={:#018x} r14={:#018x} r15={:#018x}",
+        frame.r13, frame.r14, frame.r15
+    ));
 
-    pub extern "x86-interrupt" fn virtualization(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x14 virtualization, stack_frame={stack_frame:#?}");
-        loop {}
-    }
+    let (cr0, cr3, cr4, efer) = unsafe {
+        (
+            x86::controlregs::cr0().bits() as u64,
+            x86::controlregs::cr3(),
+            x86::controlregs::cr4().bits() as u64,
+            x86::msr::rdmsr(x86::msr::IA32_EFER),
+        )
+    };
+    emergency_println(format_args!(
+        "  cr0={cr0:#x} cr2={cr2:#x} cr3={cr3:#x} cr4={cr4:#x} efer={efer:#x}"
+    ));
 
-    pub extern "x86-interrupt" fn cp_protection_exception(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        log::error!("exception: 0x15 cp_protection_exception, error_code={error_code:?}, stack_frame={stack_frame:#?}");
-        loop {}
+    match code_bytes(frame.rip) {
+        Some(bytes) => emergency_println(format_args!(
+            "  code: {}",
+            HexDump {
+                addr: frame.rip - CODE_DUMP_RANGE,
+                bytes,
+                mark: frame.rip,
+            }
+        )),
+        None => emergency_println(format_args!("  code: not mapped")),
     }
 
-    pub extern "x86-interrupt" fn hv_injection_exception(stack_frame: InterruptStackFrame) {
-        log::error!("exception: 0x1c hv_injection_exception, stack_frame={stack_frame:#?}");
-        loop {}
+    let backtrace = Backtrace::new(Some(frame.rip), frame.rbp);
+    let note = if backtrace.has_symbols() {
+        ""
+    } else {
+        " (no symbol table embedded)"
+    };
+    emergency_println(format_args!("  backtrace{note}:"));
+    for (i, frame) in backtrace.enumerate() {
+        emergency_println(format_args!("    #{i:<2} {frame}"));
     }
+}
 
-    pub extern "x86-interrupt" fn vmm_communication_exception(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        log::error!("exception: 0x1d vmm_communication_exception, error_code={error_code:?}, stack_frame={stack_frame:#?}");
-        loop {}
+/// Returns a note if the stack pointer lies in the guard page of the loader's
+/// stack.
+fn guard_page_note(rsp: u64) -> &'static str {
+    let guard = crate::mem::stack::guard_page();
+    // A push with %rsp at the end of the guard page faults as well.
+    if guard.start <= rsp && rsp <= guard.end {
+        " (in the stack guard page: stack overflow)"
+    } else {
+        ""
     }
+}
 
-    pub extern "x86-interrupt" fn security_exception(
-        stack_frame: InterruptStackFrame,
-        error_code: u64,
-    ) {
-        log::error!("exception: 0x1e security_exception, error_code={error_code:?}, stack_frame={stack_frame:#?}");
-        loop {}
-    }
+/// Returns the bytes around `rip` if they are mapped.
+fn code_bytes(rip: u64) -> Option<&'static [u8]> {
+    let start = rip.checked_sub(CODE_DUMP_RANGE)?;
+    let len = 2 * CODE_DUMP_RANGE;
+    paging::is_mapped(start, len)
+        .then(|| unsafe { core::slice::from_raw_parts(start as *const u8, len as usize) })
 }
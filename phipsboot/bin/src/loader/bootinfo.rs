@@ -0,0 +1,284 @@
+//! Creates the [`BootInformation`] for the kernel.
+
+use super::kernel::Kernel;
+use super::modules::Module;
+use super::Error;
+use crate::acpi::RsdpLocation;
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use crate::smbios::Smbios;
+use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
+use lib::acpi::RSDP_V2_LEN;
+use lib::bootinfo::{
+    BootInfoWriter, BootInformation, BootModule, Cpu, Framebuffer, MemoryMapEntry, PciFunction,
+};
+use lib::bootinfo::{FRAMEBUFFER_INDEXED, FRAMEBUFFER_RGB, FRAMEBUFFER_TEXT};
+use lib::cpu::CpuFeatures;
+use lib::mem::map::{MemoryMap, MemoryRegionKind, PhysRange};
+use lib::mem::paging::{flags, AddressSpace, PageSize, PhysAddr, VirtAddr, PAGE_SIZE};
+use lib::smbios::ENTRY_POINT_MAX_LEN;
+use multiboot2::FramebufferType;
+
+/// Number of memory map entries that allocations after [`reserve`] may add at
+/// most.
+const MEMORY_MAP_SLACK: usize = 32;
+
+/// The copy of the ACPI RSDP for the kernel. The alignment matches that of
+/// the RSDP in the BIOS areas.
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+struct RsdpCopy([u8; RSDP_V2_LEN]);
+
+/// A copy of an SMBIOS entry point or one that PhipsBoot creates for a copy
+/// of the structure table, zero-padded. The alignment matches that of the
+/// entry point in the BIOS area.
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+struct SmbiosEntryPoint([u8; ENTRY_POINT_MAX_LEN]);
+
+/// What PhipsBoot found out about the machine, for [`write`].
+#[derive(Debug)]
+pub struct Platform<'a> {
+    pub cpus: &'a [Cpu],
+    pub rsdp: Option<&'a RsdpLocation>,
+    pub smbios: Option<&'a Smbios>,
+    pub pci_functions: &'a [PciFunction],
+}
+
+/// Allocates the memory for the boot information and identity-maps it into
+/// the kernel's address space. The boot information contains the memory map,
+/// so it must be written with [`write`] after all other allocations.
+pub fn reserve(
+    kernel: &Kernel,
+    modules: &[Module],
+    cpu_count: usize,
+    smbios: Option<&Smbios>,
+    pci_function_count: usize,
+    memory_map: &mut MemoryMap,
+    address_space: &mut AddressSpace,
+) -> Result<PhysRange, Error> {
+    let smbios_tables = match smbios {
+        Some(Smbios::Tables { tables, .. }) => tables.len(),
+        _ => 0,
+    };
+    // Upper bound including the padding for the alignment of each write.
+    let size = size_of::<BootInformation>()
+        + size_of::<Framebuffer>()
+        + size_of::<BootModule>() * modules.len()
+        + size_of::<Cpu>() * cpu_count
+        + size_of::<PciFunction>() * pci_function_count
+        + size_of::<RsdpCopy>()
+        + align_of::<RsdpCopy>()
+        + size_of::<SmbiosEntryPoint>()
+        + align_of::<SmbiosEntryPoint>()
+        + smbios_tables
+        + size_of::<MemoryMapEntry>() * (memory_map.regions().len() + MEMORY_MAP_SLACK)
+        + kernel.cmdline().len()
+        + modules.iter().map(|m| m.cmdline().len()).sum::<usize>()
+        + 8 * (modules.len() + 4);
+    let size = (size as u64).next_multiple_of(PAGE_SIZE);
+
+    let range = memory_map
+        .allocate(size, PAGE_SIZE, MemoryRegionKind::BootInfo, IDENTITY_MAPPING_LIMIT)
+        .ok_or(Error::OutOfMemory)?;
+    let mut alloc = memory_map.frame_allocator(MemoryRegionKind::PageTables, IDENTITY_MAPPING_LIMIT);
+    address_space.map_range(
+        VirtAddr::new(range.start()),
+        PhysAddr::new(range.start()),
+        range.len(),
+        PageSize::Size4KiB,
+        flags::WRITABLE | flags::NO_EXECUTE,
+        &mut alloc,
+    )?;
+    Ok(range)
+}
+
+/// Returns the framebuffer that the bootloader set up, if any.
+pub fn framebuffer(mbi: &multiboot2::BootInformation) -> Option<Framebuffer> {
+    let tag = mbi.framebuffer_tag()?.ok()?;
+    let mut framebuffer = Framebuffer {
+        phys_addr: tag.address(),
+        pitch: tag.pitch(),
+        width: tag.width(),
+        height: tag.height(),
+        bpp: tag.bpp(),
+        kind: FRAMEBUFFER_INDEXED,
+        red_position: 0,
+        red_size: 0,
+        green_position: 0,
+        green_size: 0,
+        blue_position: 0,
+        blue_size: 0,
+        reserved: 0,
+    };
+    match tag.buffer_type().ok()? {
+        FramebufferType::Indexed { .. } => {}
+        FramebufferType::RGB { red, green, blue } => {
+            framebuffer.kind = FRAMEBUFFER_RGB;
+            framebuffer.red_position = red.position;
+            framebuffer.red_size = red.size;
+            framebuffer.green_position = green.position;
+            framebuffer.green_size = green.size;
+            framebuffer.blue_position = blue.position;
+            framebuffer.blue_size = blue.size;
+        }
+        FramebufferType::Text => framebuffer.kind = FRAMEBUFFER_TEXT,
+    }
+    Some(framebuffer)
+}
+
+/// Writes the boot information into the memory from [`reserve`]. `info` holds
+/// all fields except for the ones that reference other data. Returns the
+/// address of the [`BootInformation`].
+pub fn write(
+    range: PhysRange,
+    info: BootInformation,
+    framebuffer: Option<&Framebuffer>,
+    kernel: &Kernel,
+    modules: &[Module],
+    platform: &Platform,
+    memory_map: &MemoryMap,
+) -> u64 {
+    let Platform {
+        cpus,
+        rsdp,
+        smbios,
+        pci_functions,
+    } = *platform;
+    // The memory is within the identity mapping of the loader.
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(range.start() as *mut u8, range.len() as usize)
+    };
+    buf.fill(0);
+    let mut writer = BootInfoWriter::new(buf, range.start());
+    let write_error = "should have reserved enough memory";
+
+    let cmdline_ptr = writer.write_str(kernel.cmdline()).expect(write_error);
+    let boot_modules = modules
+        .iter()
+        .map(|module| BootModule {
+            phys_addr: module.range().start(),
+            size: module.range().len(),
+            virt_addr: module.virt_addr().unwrap_or(0),
+            cmdline_ptr: writer.write_str(module.cmdline()).expect(write_error),
+            cmdline_len: module.cmdline().len() as u64,
+        })
+        .collect::<Vec<_>>();
+    let modules_ptr = writer.write_slice(&boot_modules).expect(write_error);
+
+    // Everything that PhipsBoot itself used is no longer needed by the kernel.
+    let mut memory_map = memory_map.clone();
+    memory_map.replace_kind(
+        MemoryRegionKind::PhipsBoot,
+        MemoryRegionKind::BootloaderReclaimable,
+    );
+    memory_map.replace_kind(
+        MemoryRegionKind::BootloaderInfo,
+        MemoryRegionKind::BootloaderReclaimable,
+    );
+    let memory_map = memory_map
+        .regions()
+        .iter()
+        .map(MemoryMapEntry::from)
+        .collect::<Vec<_>>();
+    let memory_map_ptr = writer.write_slice(&memory_map).expect(write_error);
+    let framebuffer_ptr = framebuffer
+        .map(|framebuffer| writer.write(framebuffer).expect(write_error))
+        .unwrap_or(0);
+    let cpus_ptr = writer.write_slice(cpus).expect(write_error);
+    let rsdp_addr = match rsdp {
+        None => 0,
+        Some(RsdpLocation::Firmware(addr)) => *addr,
+        Some(RsdpLocation::Copy(copy)) => writer.write(&RsdpCopy(*copy)).expect(write_error),
+    };
+    let pci_functions_ptr = writer.write_slice(pci_functions).expect(write_error);
+    let smbios_addr = match smbios {
+        None => 0,
+        Some(Smbios::Firmware { addr, .. }) => *addr,
+        Some(Smbios::EntryPointCopy { bytes, .. }) => writer
+            .write(&SmbiosEntryPoint(*bytes))
+            .expect(write_error),
+        Some(Smbios::Tables {
+            major,
+            minor,
+            tables,
+        }) => {
+            let tables_ptr = writer.write_slice(tables).expect(write_error);
+            let entry_point =
+                lib::smbios::entry_point3(*major, *minor, tables_ptr, tables.len() as u32);
+            let mut bytes = [0; ENTRY_POINT_MAX_LEN];
+            bytes[..entry_point.len()].copy_from_slice(&entry_point);
+            writer
+                .write(&SmbiosEntryPoint(bytes))
+                .expect(write_error)
+        }
+    };
+
+    let boot_info = writer
+        .write(&BootInformation {
+            cmdline_ptr,
+            cmdline_len: kernel.cmdline().len() as u64,
+            modules_ptr,
+            modules_count: boot_modules.len() as u64,
+            memory_map_ptr,
+            memory_map_count: memory_map.len() as u64,
+            framebuffer_ptr,
+            cpus_ptr,
+            cpus_count: cpus.len() as u64,
+            rsdp_addr,
+            smbios_addr,
+            pci_functions_ptr,
+            pci_functions_count: pci_functions.len() as u64,
+            ..info
+        })
+        .expect(write_error);
+
+    log::debug!("Boot information at {boot_info:#x}:");
+    log::debug!("  cmdline: {:?}", kernel.cmdline());
+    if info.direct_map_offset != 0 {
+        log::debug!("  direct map at {:#x}", info.direct_map_offset);
+    }
+    log::debug!(
+        "  stack: {}",
+        PhysRange::from_len(info.stack_base, info.stack_size)
+    );
+    for (module, boot_module) in modules.iter().zip(&boot_modules) {
+        log::debug!(
+            "  module {:?}: {} (virt {:#x})",
+            module.cmdline(),
+            module.range(),
+            boot_module.virt_addr
+        );
+    }
+    log::debug!("  memory map: {} entries", memory_map.len());
+    if let Some(framebuffer) = framebuffer {
+        log::debug!(
+            "  framebuffer: {:#x} ({}x{}, {} bpp, kind {})",
+            framebuffer.phys_addr,
+            framebuffer.width,
+            framebuffer.height,
+            framebuffer.bpp,
+            framebuffer.kind
+        );
+    }
+    if !cpus.is_empty() {
+        log::debug!("  CPUs: {}", cpus.len());
+    }
+    if rsdp_addr != 0 {
+        log::debug!("  RSDP at {rsdp_addr:#x}");
+    }
+    if smbios_addr != 0 {
+        log::debug!("  SMBIOS entry point at {smbios_addr:#x}");
+    }
+    if !pci_functions.is_empty() {
+        log::debug!("  PCI functions: {}", pci_functions.len());
+    }
+    if info.cpu_features != 0 {
+        log::debug!(
+            "  CPU features: {}",
+            CpuFeatures::from_bits(info.cpu_features)
+        );
+    }
+
+    boot_info
+}
@@ -0,0 +1,117 @@
+//! The GDT and the TSS of the kernel. Both live in memory that belongs to the
+//! kernel and is identity-mapped into its address space. They are built like
+//! the ones of the loader, see [`crate::gdt`] for the layout.
+
+use super::Error;
+use crate::gdt::{Selectors, IST_STACK_COUNT, IST_STACK_SIZE};
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use lib::mem::map::{MemoryMap, MemoryRegionKind};
+use lib::mem::paging::{flags, AddressSpace, PageSize, PhysAddr, VirtAddr, PAGE_SIZE};
+use x86_64::instructions::tables::lgdt;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::DescriptorTablePointer;
+
+/// Offset of the TSS in the first page, which also holds the GDT.
+const TSS_OFFSET: u64 = 0x100;
+
+/// Bit of a TSS descriptor that marks the TSS as busy.
+const TSS_DESCRIPTOR_BUSY: u64 = 1 << 41;
+
+/// Maximum number of entries of the GDT.
+const GDT_ENTRIES: usize = 8;
+
+/// The descriptors of the GDT, with a layout as expected by the CPU. The GDT
+/// of the `x86_64` crate makes no guarantees about its layout, so its entries
+/// are copied into this table.
+#[repr(C, align(8))]
+struct Table([u64; GDT_ENTRIES]);
+
+/// The GDT and the selectors of the kernel.
+#[derive(Debug)]
+pub struct Gdt {
+    /// The GDT in the kernel's memory, which is never freed.
+    table: *mut Table,
+    /// Number of used entries of the GDT.
+    len: usize,
+    selectors: Selectors,
+}
+
+impl Gdt {
+    /// Creates the GDT, the TSS, and the IST stacks in newly allocated memory
+    /// and identity-maps them into the kernel's address space.
+    pub fn new(
+        user_segments: bool,
+        memory_map: &mut MemoryMap,
+        address_space: &mut AddressSpace,
+    ) -> Result<Self, Error> {
+        let len = PAGE_SIZE + (IST_STACK_COUNT * IST_STACK_SIZE) as u64;
+        let range = memory_map
+            .allocate(
+                len,
+                PAGE_SIZE,
+                MemoryRegionKind::CpuTables,
+                IDENTITY_MAPPING_LIMIT,
+            )
+            .ok_or(Error::OutOfMemory)?;
+        let mut alloc =
+            memory_map.frame_allocator(MemoryRegionKind::PageTables, IDENTITY_MAPPING_LIMIT);
+        address_space.map_range(
+            VirtAddr::new(range.start()),
+            PhysAddr::new(range.start()),
+            range.len(),
+            PageSize::Size4KiB,
+            flags::WRITABLE | flags::NO_EXECUTE,
+            &mut alloc,
+        )?;
+
+        let tss = crate::gdt::new_tss(core::array::from_fn(|i| {
+            range.start() + PAGE_SIZE + ((i + 1) * IST_STACK_SIZE) as u64
+        }));
+        // The memory is within the identity mapping of the loader and is never
+        // freed.
+        let tss = unsafe {
+            let ptr = (range.start() + TSS_OFFSET) as *mut TaskStateSegment;
+            ptr.write(tss);
+            &*ptr
+        };
+
+        let (gdt, selectors) = crate::gdt::new_gdt(tss, user_segments);
+        let entries = gdt.as_raw_slice();
+        let mut table = Table([0; GDT_ENTRIES]);
+        table.0[..entries.len()].copy_from_slice(entries);
+        let table_ptr = range.start() as *mut Table;
+        unsafe { table_ptr.write(table) };
+
+        log::debug!("GDT, TSS, and IST stacks at {range}");
+        Ok(Self {
+            table: table_ptr,
+            len: entries.len(),
+            selectors,
+        })
+    }
+
+    /// Returns the limit and the base address of the GDT, as expected by
+    /// `lgdt`.
+    pub fn pointer(&self) -> (u16, u64) {
+        let limit = (self.len * 8 - 1) as u16;
+        (limit, self.table as u64)
+    }
+
+    /// Loads the GDT and the TSS and reloads all segment registers. The TSS
+    /// descriptor is marked as available again afterwards, so that the kernel
+    /// can load the TSS itself without a #GP.
+    pub fn load(&self) {
+        let (limit, base) = self.pointer();
+        let pointer = DescriptorTablePointer {
+            limit,
+            base: x86_64::VirtAddr::new(base),
+        };
+        unsafe {
+            lgdt(&pointer);
+            crate::gdt::load_segments(&self.selectors);
+            let entries = core::ptr::addr_of_mut!((*self.table).0);
+            let descriptor = entries.cast::<u64>().add(self.selectors.tss.index() as usize);
+            descriptor.write_volatile(descriptor.read_volatile() & !TSS_DESCRIPTOR_BUSY);
+        }
+    }
+}
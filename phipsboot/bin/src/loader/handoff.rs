@@ -0,0 +1,243 @@
+//! Hand-off of control to the kernel.
+
+use super::gdt::Gdt;
+use super::Error;
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use crate::mem::virt_to_phys;
+use lib::cpu::CpuFeatures;
+use lib::logger;
+use lib::mem::map::{MemoryMap, MemoryRegionKind, PhysRange};
+use lib::mem::paging::{flags, AddressSpace, FrameAllocator, Level, MapError, PageSize, PhysAddr};
+use lib::mem::paging::{VirtAddr, PAGE_SIZE};
+
+/// Default size of the kernel stack.
+pub const DEFAULT_KERNEL_STACK_SIZE: u64 = 0x20000 /* 128 KiB */;
+
+/// Everything that is needed to jump into the kernel.
+#[derive(Debug)]
+pub struct Handoff {
+    root_page_table: PhysAddr,
+    paging_levels: Level,
+    entry: u64,
+    stack: PhysRange,
+    /// Address of the trampoline code, which is mapped in the current and in
+    /// the kernel's address space.
+    trampoline: u64,
+    gdt: Gdt,
+    boot_info: u64,
+    cpu_features: CpuFeatures,
+}
+
+impl Handoff {
+    /// Prepares the hand-off and allocates the kernel stack of `stack_size`
+    /// bytes. If PhipsBoot stays mapped in the kernel's address space, the
+    /// trampoline runs from PhipsBoot's code. Otherwise, or if the trampoline
+    /// switches to 5-level paging, it is copied to a dedicated identity-mapped
+    /// page.
+    pub fn new(
+        root_page_table: PhysAddr,
+        entry: u64,
+        gdt: Gdt,
+        stack_size: u64,
+        phipsboot_mapped: bool,
+        memory_map: &mut MemoryMap,
+        address_space: &mut AddressSpace,
+    ) -> Result<Self, Error> {
+        let paging_levels = address_space.root_level();
+        let trampoline = if phipsboot_mapped && paging_levels == Level::Four {
+            crate::extern_symbols::trampoline() as u64
+        } else {
+            map_trampoline(memory_map, address_space)?
+        };
+        let stack = allocate_stack(stack_size, memory_map, address_space)?;
+        log::debug!("Kernel stack at {stack} (guard page below)");
+        Ok(Self {
+            root_page_table,
+            paging_levels,
+            entry,
+            stack,
+            trampoline,
+            gdt,
+            boot_info: 0,
+            cpu_features: CpuFeatures::NONE,
+        })
+    }
+
+    /// Returns the usable memory of the kernel stack, without the guard page.
+    pub fn stack(&self) -> PhysRange {
+        self.stack
+    }
+
+    /// Returns the stack pointer for the kernel entry. As if the entry was
+    /// called, the stack is 16-byte aligned before the return address.
+    fn stack_pointer(&self) -> u64 {
+        self.stack.end() - 8
+    }
+
+    /// Sets the address of the boot information.
+    pub fn set_boot_info(&mut self, boot_info: u64) {
+        self.boot_info = boot_info;
+    }
+
+    /// Sets the optional CPU features that are enabled for the kernel.
+    pub fn set_cpu_features(&mut self, features: CpuFeatures) {
+        self.cpu_features = features;
+    }
+
+    /// Switches to the address space of the kernel and jumps to its entry.
+    /// The machine state is described in the README.
+    pub fn jump(self) -> ! {
+        log::info!(
+            "Jumping to kernel entry {:#x} (cr3={:#x}, {}-level paging, rsp={:#x}, boot info={:#x})",
+            self.entry,
+            self.root_page_table.val(),
+            self.paging_levels.val(),
+            self.stack_pointer(),
+            self.boot_info
+        );
+        logger::flush();
+
+        crate::cpu::enable(self.cpu_features);
+        // The GDT is identity-mapped in both address spaces.
+        self.gdt.load();
+        unsafe {
+            core::arch::asm!(
+                "jmp *{trampoline}",
+                trampoline = in(reg) self.trampoline,
+                in("rdi") self.boot_info,
+                in("rsi") self.root_page_table.val(),
+                in("rdx") self.stack_pointer(),
+                in("rcx") self.entry,
+                in("r8") self.paging_levels.val(),
+                options(noreturn, att_syntax)
+            )
+        }
+    }
+}
+
+/// Maps the high-level code of the loader at its link address into the
+/// kernel's address space, so that the hand-off code keeps running after the
+/// switch of `%cr3`. The permissions match those of the boot page tables and
+/// the guard page of the loader's stack stays unmapped.
+pub fn map_loader(
+    address_space: &mut AddressSpace,
+    alloc: &mut impl FrameAllocator,
+) -> Result<(), MapError> {
+    use crate::extern_symbols::*;
+    let segments = [
+        (link_addr_rx(), count_pages_rx(), 0),
+        (link_addr_ro(), count_pages_ro(), flags::NO_EXECUTE),
+        (
+            stack_guard_page().wrapping_add(PAGE_SIZE as usize),
+            count_pages_rw() - 1,
+            flags::WRITABLE | flags::NO_EXECUTE,
+        ),
+    ];
+    for (vaddr, pages, page_flags) in segments {
+        let vaddr = VirtAddr::from(vaddr);
+        address_space.map_range(
+            vaddr,
+            virt_to_phys(vaddr),
+            pages * PAGE_SIZE,
+            PageSize::Size4KiB,
+            page_flags,
+            alloc,
+        )?;
+    }
+    Ok(())
+}
+
+/// Copies the trampoline code to a new page that is identity-mapped and
+/// executable in both address spaces. Only that page is executable in the
+/// identity mapping of the loader. Returns the address of the page.
+fn map_trampoline(
+    memory_map: &mut MemoryMap,
+    address_space: &mut AddressSpace,
+) -> Result<u64, Error> {
+    let page = allocate_trampoline_page(memory_map, address_space)?;
+
+    let code = crate::extern_symbols::trampoline();
+    let len = crate::extern_symbols::trampoline_end() as usize - code as usize;
+    assert!(len as u64 <= PAGE_SIZE);
+    unsafe { core::ptr::copy_nonoverlapping(code, page.start() as *mut u8, len) };
+    crate::mem::paging::make_executable(PhysAddr::new(page.start()));
+
+    let mut alloc = memory_map.frame_allocator(MemoryRegionKind::PageTables, IDENTITY_MAPPING_LIMIT);
+    address_space.map_page(
+        VirtAddr::new(page.start()),
+        PhysAddr::new(page.start()),
+        PageSize::Size4KiB,
+        0,
+        &mut alloc,
+    )?;
+    Ok(page.start())
+}
+
+/// Allocates a page for the trampoline whose address the kernel's address
+/// space doesn't map yet. Pages that are mapped are skipped, and the next page
+/// below them is tried. Fails with the first collision if no page is left.
+fn allocate_trampoline_page(
+    memory_map: &mut MemoryMap,
+    address_space: &AddressSpace,
+) -> Result<PhysRange, Error> {
+    let mut limit = IDENTITY_MAPPING_LIMIT;
+    let mut first_collision = None;
+    loop {
+        let Some(page) = memory_map.allocate(
+            PAGE_SIZE,
+            PAGE_SIZE,
+            MemoryRegionKind::BootloaderReclaimable,
+            limit,
+        ) else {
+            return Err(first_collision.unwrap_or(Error::OutOfMemory));
+        };
+        let Some(phys) = address_space.translate(VirtAddr::new(page.start())) else {
+            return Ok(page);
+        };
+        log::debug!(
+            "Trampoline page at {:#x} is mapped by the kernel, trying the next page",
+            page.start()
+        );
+        memory_map.free(page, MemoryRegionKind::BootloaderReclaimable);
+        first_collision.get_or_insert(Error::TrampolineCollision {
+            page: page.start(),
+            phys: phys.val(),
+        });
+        limit = page.start();
+    }
+}
+
+/// Allocates a stack for the kernel and identity-maps it. The page below the
+/// stack is allocated as well but stays unmapped, also in a later identity
+/// mapping, so that an overflow causes a page fault. Returns the mapped part
+/// of the stack.
+pub fn allocate_stack(
+    size: u64,
+    memory_map: &mut MemoryMap,
+    address_space: &mut AddressSpace,
+) -> Result<PhysRange, Error> {
+    let len = size
+        .checked_next_multiple_of(PAGE_SIZE)
+        .and_then(|size| size.checked_add(PAGE_SIZE))
+        .ok_or(Error::OutOfMemory)?;
+    let allocation = memory_map
+        .allocate(
+            len,
+            PAGE_SIZE,
+            MemoryRegionKind::Kernel,
+            IDENTITY_MAPPING_LIMIT,
+        )
+        .ok_or(Error::OutOfMemory)?;
+    let stack = PhysRange::new(allocation.start() + PAGE_SIZE, allocation.end());
+    address_space.add_hole(allocation.start()..stack.start());
+    let mut alloc = memory_map.frame_allocator(MemoryRegionKind::PageTables, IDENTITY_MAPPING_LIMIT);
+    address_space.map_range(
+        VirtAddr::new(stack.start()),
+        PhysAddr::new(stack.start()),
+        stack.len(),
+        PageSize::Size4KiB,
+        flags::WRITABLE | flags::NO_EXECUTE,
+        &mut alloc,
+    )?;
+    Ok(stack)
+}
@@ -0,0 +1,138 @@
+//! Loads the LOAD segments of the kernel's ELF file.
+
+use super::modules::Module;
+use super::Error;
+use alloc::vec::Vec;
+use lib::elf::{segment_pages, Elf, ProgramHeader, TYPE_DYN};
+use lib::mem::map::PhysRange;
+use lib::mem::paging::{flags, AddressSpace, FrameAllocator, MapError, PageSize};
+use lib::mem::paging::{PhysAddr, VirtAddr, PAGE_SIZE};
+use lib::note::KernelRequests;
+
+/// The kernel and its ELF file.
+#[derive(Debug)]
+pub struct Kernel {
+    module: Module,
+    entry: u64,
+    segments: Vec<ProgramHeader>,
+    requests: KernelRequests,
+}
+
+impl Kernel {
+    /// Parses and validates the ELF file of the kernel.
+    pub fn new(module: Module) -> Result<Self, Error> {
+        let elf = Elf::parse(module.bytes())?;
+        if elf.typ() == TYPE_DYN {
+            log::warn!("Kernel is a position-independent executable; relocations are not applied");
+        }
+
+        let segments = elf
+            .load_segments()
+            .filter(|segment| segment.memsz > 0)
+            .collect::<Vec<_>>();
+        for segment in &segments {
+            if segment.vaddr % PAGE_SIZE != segment.paddr % PAGE_SIZE {
+                return Err(Error::MisalignedSegment {
+                    vaddr: segment.vaddr,
+                    paddr: segment.paddr,
+                });
+            }
+        }
+
+        let requests = KernelRequests::from_notes(elf.notes()).map_err(Error::InvalidNote)?;
+        if requests != KernelRequests::default() {
+            log::debug!("Kernel requests: {requests:?}");
+        }
+
+        let entry = elf.entry();
+        Ok(Self {
+            module,
+            entry,
+            segments,
+            requests,
+        })
+    }
+
+    /// Returns the virtual address of the entry point.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns the requests from the PhipsBoot notes of the kernel.
+    pub fn requests(&self) -> &KernelRequests {
+        &self.requests
+    }
+
+    /// Returns the command line of the kernel.
+    pub fn cmdline(&self) -> &str {
+        self.module.cmdline()
+    }
+
+    /// Returns the boot module that holds the ELF file.
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    pub fn module_mut(&mut self) -> &mut Module {
+        &mut self.module
+    }
+
+    /// Returns the page-aligned physical ranges of all LOAD segments.
+    pub fn segment_ranges(&self) -> Vec<PhysRange> {
+        self.segments
+            .iter()
+            .map(|segment| PhysRange::from_len(segment.paddr, segment.memsz).page_aligned())
+            .collect()
+    }
+
+    /// Copies all LOAD segments to their physical addresses and zeroes the
+    /// remaining memory of each segment.
+    pub fn load(&self) {
+        let elf = Elf::parse(self.module.bytes()).unwrap();
+        for segment in &self.segments {
+            let data = elf.segment_data(segment);
+            log::debug!(
+                "Loading segment to {}",
+                PhysRange::from_len(segment.paddr, segment.memsz)
+            );
+            // The segments are within the identity mapping and don't overlap
+            // with the ELF file.
+            unsafe {
+                let dest = segment.paddr as *mut u8;
+                core::ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+                core::ptr::write_bytes(
+                    dest.add(data.len()),
+                    0,
+                    segment.memsz.saturating_sub(segment.filesz) as usize,
+                );
+            }
+        }
+    }
+
+    /// Maps all LOAD segments at their virtual addresses with the permissions
+    /// of the segments. If segments share a page, the page gets the
+    /// permissions of all of them.
+    pub fn map(
+        &self,
+        address_space: &mut AddressSpace,
+        alloc: &mut impl FrameAllocator,
+    ) -> Result<(), MapError> {
+        for page in segment_pages(&self.segments) {
+            let mut page_flags = 0;
+            if page.writable {
+                page_flags |= flags::WRITABLE;
+            }
+            if !page.executable {
+                page_flags |= flags::NO_EXECUTE;
+            }
+            address_space.map_page(
+                VirtAddr::new(page.vaddr),
+                PhysAddr::new(page.paddr),
+                PageSize::Size4KiB,
+                page_flags,
+                alloc,
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Additional linear mappings of physical memory in the kernel's address
+//! space.
+
+use super::Error;
+use alloc::vec::Vec;
+use lib::bootinfo::Framebuffer;
+use lib::mem::map::{MemoryMap, MemoryRegionKind, PhysRange};
+use lib::mem::paging::{flags, AddressSpace, FrameAllocator, MapError, PageSize};
+use lib::mem::paging::{PhysAddr, VirtAddr};
+use x86::cpuid::CpuId;
+
+/// Returns the largest page size that the CPU supports.
+fn huge_page_size() -> PageSize {
+    let has_1gib_pages = CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .is_some_and(|features| features.has_1gib_pages());
+    if has_1gib_pages {
+        PageSize::Size1GiB
+    } else {
+        PageSize::Size2MiB
+    }
+}
+
+/// Describes the direct map of physical memory at a fixed offset.
+#[derive(Debug)]
+pub struct DirectMap {
+    offset: u64,
+    page_size: PageSize,
+    ranges: Vec<PhysRange>,
+}
+
+impl DirectMap {
+    /// Determines the physical ranges of the direct map: All RAM and, if
+    /// `mmio` is set, also the reserved ranges of the memory map. The ranges
+    /// are extended to the largest supported page size that `offset` is
+    /// aligned to. Fails if `offset` isn't aligned to 2 MiB.
+    pub fn new(offset: u64, mmio: bool, memory_map: &MemoryMap) -> Result<Self, Error> {
+        let page_size = match huge_page_size() {
+            PageSize::Size1GiB if offset % PageSize::Size1GiB.val() != 0 => PageSize::Size2MiB,
+            page_size => page_size,
+        };
+        if offset % page_size.val() != 0 {
+            return Err(Error::MisalignedDirectMap(offset));
+        }
+        let ranges = memory_map.aligned_ranges(page_size.val(), |kind| match kind {
+            MemoryRegionKind::BadMemory => false,
+            MemoryRegionKind::Reserved => mmio,
+            _ => true,
+        });
+        Ok(Self {
+            offset,
+            page_size,
+            ranges,
+        })
+    }
+
+    /// Maps the direct map read-write and non-executable.
+    pub fn map(
+        &self,
+        address_space: &mut AddressSpace,
+        alloc: &mut impl FrameAllocator,
+    ) -> Result<(), MapError> {
+        for range in &self.ranges {
+            log::debug!(
+                "Direct map: {range} at {:#x} ({:?} pages)",
+                self.offset + range.start(),
+                self.page_size
+            );
+            address_space.map_range(
+                VirtAddr::new(self.offset + range.start()),
+                PhysAddr::new(range.start()),
+                range.len(),
+                self.page_size,
+                flags::WRITABLE | flags::NO_EXECUTE,
+                alloc,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Identity-maps the framebuffer read-write and non-executable.
+pub fn map_framebuffer(
+    framebuffer: &Framebuffer,
+    address_space: &mut AddressSpace,
+    alloc: &mut impl FrameAllocator,
+) -> Result<(), MapError> {
+    let range = PhysRange::from_len(framebuffer.phys_addr, framebuffer.size()).page_aligned();
+    log::debug!("Framebuffer: {range}");
+    address_space.map_range(
+        VirtAddr::new(range.start()),
+        PhysAddr::new(range.start()),
+        range.len(),
+        PageSize::Size4KiB,
+        flags::WRITABLE | flags::NO_EXECUTE,
+        alloc,
+    )
+}
+
+/// Identity-maps the first `size` bytes of physical memory read-write and
+/// executable with the largest supported page size. Existing mappings take
+/// precedence, so this must be called after all other mappings were created.
+/// Fails if `size` is 0 or, rounded up to the page size, exceeds the lower
+/// half of the address space.
+pub fn map_identity(
+    size: u64,
+    address_space: &mut AddressSpace,
+    alloc: &mut impl FrameAllocator,
+) -> Result<(), Error> {
+    let page_size = huge_page_size();
+    let limit = address_space.lower_half_size();
+    let size = size
+        .checked_next_multiple_of(page_size.val())
+        .filter(|aligned| (1..=limit).contains(aligned))
+        .ok_or(Error::InvalidIdentityMap { size, limit })?;
+    log::debug!("Identity map: {} ({page_size:?} pages)", PhysRange::new(0, size));
+    address_space.fill_range(
+        VirtAddr::new(0),
+        PhysAddr::new(0),
+        size,
+        page_size,
+        flags::WRITABLE,
+        alloc,
+    )?;
+    Ok(())
+}
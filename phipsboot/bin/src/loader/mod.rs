@@ -0,0 +1,424 @@
+//! Loads the kernel. The loader finds the kernel among the boot modules, loads
+//! its LOAD segments to their physical addresses, prepares the address space
+//! and the boot information of the kernel, and finally hands off control.
+
+mod bootinfo;
+mod gdt;
+mod handoff;
+mod kernel;
+mod mappings;
+mod modules;
+mod overlap;
+mod smp;
+
+use crate::mem::paging::{IDENTITY_MAPPING_LIMIT, RELOCATION_LIMIT};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use gdt::Gdt;
+use kernel::Kernel;
+use lib::bootinfo::{BootInformation, Framebuffer};
+use lib::cli::KernelSelector;
+use lib::elf::ElfError;
+use lib::mem::map::{MemoryMap, MemoryRegionKind, PhysRange};
+use lib::mem::paging::{AddressSpace, Level, MapError, PageSize, PhysAddr};
+use lib::note::{KernelRequests, NoteError};
+use mappings::DirectMap;
+use multiboot2::MemoryAreaType;
+use overlap::Collision;
+use smp::{ApState, Smp};
+
+pub use handoff::Handoff;
+
+/// Errors that prevent the kernel from being loaded.
+#[derive(Debug)]
+pub enum Error {
+    /// Kernels can only be loaded if the loader was booted via Multiboot2.
+    UnsupportedBootVariant,
+    /// The bootloader didn't provide a memory map.
+    NoMemoryMap,
+    /// No boot module matches the `--load` argument. Holds the name and
+    /// location of all boot modules.
+    KernelNotFound {
+        selector: KernelSelector,
+        available: Vec<(String, PhysRange)>,
+    },
+    /// The kernel is not a valid ELF executable.
+    InvalidKernel(ElfError),
+    /// A PhipsBoot note of the kernel is invalid.
+    InvalidNote(NoteError),
+    /// The kernel requests something via its notes that PhipsBoot can't
+    /// provide.
+    UnsatisfiableRequest {
+        request: &'static str,
+        reason: String,
+    },
+    /// A LOAD segment's virtual and physical address have a different offset
+    /// into the page.
+    MisalignedSegment { vaddr: u64, paddr: u64 },
+    /// The kernel's LOAD segments collide with memory that can't be moved.
+    Collisions(Vec<Collision>),
+    /// The offset of the direct map is not aligned to 2 MiB.
+    MisalignedDirectMap(u64),
+    /// The size of the identity mapping for the kernel is 0 or exceeds the
+    /// lower half of its address space.
+    InvalidIdentityMap { size: u64, limit: u64 },
+    /// The memory is above the identity mapping of the loader.
+    NotAccessible(PhysRange),
+    /// No page for the trampoline into the kernel can be identity-mapped, as
+    /// the kernel's address space maps all free pages already. Holds the first
+    /// page that was tried and what the kernel maps it to.
+    TrampolineCollision { page: u64, phys: u64 },
+    /// There is not enough free memory.
+    OutOfMemory,
+    /// The address space of the kernel can't be created.
+    Map(MapError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnsupportedBootVariant => write!(f, "only Multiboot2 can load a kernel"),
+            Self::NoMemoryMap => write!(f, "the bootloader provided no memory map"),
+            Self::KernelNotFound {
+                selector,
+                available,
+            } => {
+                write!(f, "no boot module matches --load={selector}; available modules:")?;
+                for (index, (name, range)) in available.iter().enumerate() {
+                    write!(f, "\n  [{index}] {name:?}: {:#x} bytes at {range}", range.len())?;
+                }
+                Ok(())
+            }
+            Self::InvalidKernel(e) => write!(f, "invalid kernel ELF: {e}"),
+            Self::InvalidNote(e) => write!(f, "invalid PhipsBoot note in the kernel: {e}"),
+            Self::UnsatisfiableRequest { request, reason } => {
+                write!(f, "the kernel requests {request}, but {reason}")
+            }
+            Self::MisalignedSegment { vaddr, paddr } => write!(
+                f,
+                "segment vaddr={vaddr:#x} and paddr={paddr:#x} are not congruent modulo the page size"
+            ),
+            Self::Collisions(collisions) => {
+                write!(f, "the kernel collides with memory that can't be moved:")?;
+                for collision in collisions {
+                    write!(f, "\n  {collision}")?;
+                }
+                Ok(())
+            }
+            Self::MisalignedDirectMap(offset) => {
+                write!(f, "the direct map offset {offset:#x} is not aligned to 2 MiB")
+            }
+            Self::InvalidIdentityMap { size, limit } => write!(
+                f,
+                "the identity mapping of {size:#x} bytes (--identity-map) is empty or exceeds the lower half of the address space ({limit:#x} bytes)"
+            ),
+            Self::NotAccessible(range) => {
+                write!(f, "{range} is above {IDENTITY_MAPPING_LIMIT:#x}")
+            }
+            Self::TrampolineCollision { page, phys } => write!(
+                f,
+                "no free page for the trampoline can be identity-mapped; the kernel maps the first candidate {page:#x} to {phys:#x}"
+            ),
+            Self::OutOfMemory => write!(f, "out of memory"),
+            Self::Map(e) => write!(f, "can't map memory: {e:?}"),
+        }
+    }
+}
+
+impl From<MapError> for Error {
+    fn from(e: MapError) -> Self {
+        match e {
+            MapError::OutOfMemory => Self::OutOfMemory,
+            e => Self::Map(e),
+        }
+    }
+}
+
+impl From<ElfError> for Error {
+    fn from(e: ElfError) -> Self {
+        Self::InvalidKernel(e)
+    }
+}
+
+/// Loads the kernel and returns everything that is needed to hand off control.
+pub fn load_kernel() -> Result<Handoff, Error> {
+    let mbi = crate::env::mbi().ok_or(Error::UnsupportedBootVariant)?;
+    let cli_args = crate::env::cli_args();
+
+    let mut memory_map = memory_map(mbi)?;
+    let mut modules = modules::from_mbi(mbi, &mut memory_map)?;
+    if cli_args.relocate_phipsboot() {
+        relocate_phipsboot(&mut memory_map)?;
+    }
+    let kernel_module = modules::take_kernel(&mut modules, cli_args.load())?;
+    let mut kernel = Kernel::new(kernel_module)?;
+    let framebuffer = bootinfo::framebuffer(mbi);
+    check_requests(kernel.requests(), framebuffer.as_ref())?;
+    let requests = *kernel.requests();
+
+    let segments = kernel.segment_ranges();
+    overlap::resolve(
+        &segments,
+        kernel.module_mut(),
+        &mut modules,
+        &mut memory_map,
+        cli_args.relocate_modules(),
+    )?;
+
+    kernel.load();
+    // The kernel's ELF file is not passed to the kernel.
+    memory_map.mark(
+        kernel.module().range().page_aligned(),
+        MemoryRegionKind::BootloaderReclaimable,
+    );
+
+    let mut info = BootInformation::new();
+    let direct_map_offset = direct_map_offset(cli_args.direct_map(), requests.direct_map)?;
+    let direct_map = direct_map_offset
+        .map(|offset| DirectMap::new(offset, cli_args.direct_map_mmio(), &memory_map))
+        .transpose()?;
+
+    let mut alloc = memory_map.frame_allocator(MemoryRegionKind::PageTables, IDENTITY_MAPPING_LIMIT);
+    let paging_levels = paging_levels(&kernel);
+    info.paging_levels = paging_levels.val();
+    let mut address_space = AddressSpace::with_levels(paging_levels, &mut alloc)?;
+    kernel.map(&mut address_space, &mut alloc)?;
+    if !cli_args.unmap_phipsboot() {
+        handoff::map_loader(&mut address_space, &mut alloc)?;
+    }
+    if let Some(base) = cli_args.module_window() {
+        modules::map_window(&mut modules, base, &mut address_space, &mut alloc)?;
+    }
+    if let Some(direct_map) = direct_map {
+        direct_map.map(&mut address_space, &mut alloc)?;
+        info.direct_map_offset = direct_map_offset.unwrap();
+    }
+    if let Some(framebuffer) = framebuffer.filter(|_| requests.framebuffer.is_some()) {
+        mappings::map_framebuffer(&framebuffer, &mut address_space, &mut alloc)?;
+    }
+
+    let stack_size = cli_args
+        .stack_size()
+        .unwrap_or(handoff::DEFAULT_KERNEL_STACK_SIZE)
+        .max(requests.stack_size.unwrap_or(0));
+    let smp = if cli_args.smp() {
+        Smp::new(stack_size, &mut memory_map, &mut address_space)?
+    } else {
+        None
+    };
+
+    let pci_functions = if cli_args.pci() {
+        crate::pci::functions()
+    } else {
+        &[]
+    };
+
+    let boot_info_range = bootinfo::reserve(
+        &kernel,
+        &modules,
+        smp.as_ref().map_or(0, Smp::cpu_count),
+        crate::smbios::get(),
+        pci_functions.len(),
+        &mut memory_map,
+        &mut address_space,
+    )?;
+    let gdt = Gdt::new(cli_args.user_segments(), &mut memory_map, &mut address_space)?;
+    let gdt_pointer = gdt.pointer();
+    let mut handoff = Handoff::new(
+        address_space.root(),
+        kernel.entry(),
+        gdt,
+        stack_size,
+        !cli_args.unmap_phipsboot(),
+        &mut memory_map,
+        &mut address_space,
+    )?;
+    if let Some(size) = cli_args.identity_map() {
+        let mut alloc =
+            memory_map.frame_allocator(MemoryRegionKind::PageTables, IDENTITY_MAPPING_LIMIT);
+        mappings::map_identity(size, &mut address_space, &mut alloc)?;
+    }
+
+    info.stack_base = handoff.stack().start();
+    info.stack_size = handoff.stack().len();
+    let cpu_features = crate::cpu::select(cli_args.cpu_features().union(requests.cpu_features));
+    handoff.set_cpu_features(cpu_features);
+    info.cpu_features = cpu_features.bits();
+
+    // No more allocations from here on, as the memory map is handed over.
+    let cpus = smp
+        .as_ref()
+        .map(|smp| smp.cpus(handoff.stack()))
+        .unwrap_or_default();
+    let boot_info = bootinfo::write(
+        boot_info_range,
+        info,
+        framebuffer.as_ref(),
+        &kernel,
+        &modules,
+        &bootinfo::Platform {
+            cpus: &cpus,
+            rsdp: crate::acpi::rsdp(),
+            smbios: crate::smbios::get(),
+            pci_functions,
+        },
+        &memory_map,
+    );
+    handoff.set_boot_info(boot_info);
+
+    if let Some(smp) = smp {
+        let mut cr4 = crate::cpu::cr4_with(cpu_features).bits() as u64;
+        if paging_levels == Level::Five {
+            cr4 |= x86::controlregs::Cr4::CR4_ENABLE_LA57.bits() as u64;
+        }
+        smp.start(
+            boot_info,
+            &ApState {
+                root_page_table: address_space.root(),
+                cr0: unsafe { x86::controlregs::cr0() }.bits() as u64,
+                cr4,
+                xcr0: crate::cpu::xcr0_for(cpu_features).map_or(0, |xcr0| xcr0.bits()),
+                gdt: gdt_pointer,
+            },
+        );
+    }
+
+    log::debug!("Physical memory map:");
+    for region in memory_map.regions() {
+        log::debug!("  {region}");
+    }
+
+    Ok(handoff)
+}
+
+/// Checks the requests of the kernel that PhipsBoot can't fall back from.
+fn check_requests(
+    requests: &KernelRequests,
+    framebuffer: Option<&Framebuffer>,
+) -> Result<(), Error> {
+    if let Some(version) = requests.min_version.filter(|v| *v > lib::bootinfo::VERSION) {
+        return Err(Error::UnsatisfiableRequest {
+            request: "a minimum boot information version",
+            reason: format!(
+                "{version} is newer than {}; update PhipsBoot",
+                lib::bootinfo::VERSION
+            ),
+        });
+    }
+
+    let missing = requests.cpu_features.difference(crate::cpu::supported());
+    if !missing.is_empty() {
+        return Err(Error::UnsatisfiableRequest {
+            request: "CPU features",
+            reason: format!("the CPU doesn't support {missing}"),
+        });
+    }
+
+    if let Some(kind) = requests.framebuffer {
+        match framebuffer {
+            Some(framebuffer) if framebuffer.kind == kind => {}
+            Some(framebuffer) => {
+                return Err(Error::UnsatisfiableRequest {
+                    request: "a framebuffer",
+                    reason: format!(
+                        "the bootloader set up a framebuffer of kind {} instead of {kind}",
+                        framebuffer.kind
+                    ),
+                })
+            }
+            None => {
+                return Err(Error::UnsatisfiableRequest {
+                    request: "a framebuffer",
+                    reason: "the bootloader provided none".into(),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the offset of the direct map from `--direct-map` or from the
+/// request of the kernel. Both must agree.
+fn direct_map_offset(cli: Option<u64>, requested: Option<u64>) -> Result<Option<u64>, Error> {
+    match (cli, requested) {
+        (Some(cli), Some(requested)) if cli != requested => Err(Error::UnsatisfiableRequest {
+            request: "a direct map",
+            reason: format!("at {requested:#x} conflicts with --direct-map={cli:#x}"),
+        }),
+        (cli, requested) => Ok(cli.or(requested)),
+    }
+}
+
+/// Returns the number of paging levels of the kernel's address space: 5 if the
+/// kernel requests it and the CPU supports it, 4 otherwise.
+fn paging_levels(kernel: &Kernel) -> Level {
+    match kernel.requests().paging_levels {
+        Some(Level::Five) if crate::cpu::has_la57() => Level::Five,
+        Some(Level::Five) => {
+            log::warn!("The CPU doesn't support 5-level paging, falling back to 4-level paging");
+            Level::Four
+        }
+        _ => Level::Four,
+    }
+}
+
+/// Creates the memory map from the information of the bootloader and marks
+/// all memory that is in use.
+fn memory_map(mbi: &multiboot2::BootInformation) -> Result<MemoryMap, Error> {
+    let tag = mbi.memory_map_tag().ok_or(Error::NoMemoryMap)?;
+    let mut memory_map = MemoryMap::new();
+    for area in tag.memory_areas() {
+        let kind = match area.typ() {
+            MemoryAreaType::Available => MemoryRegionKind::Usable,
+            MemoryAreaType::Reserved => MemoryRegionKind::Reserved,
+            MemoryAreaType::AcpiAvailable => MemoryRegionKind::AcpiReclaimable,
+            MemoryAreaType::ReservedHibernate => MemoryRegionKind::AcpiNvs,
+            MemoryAreaType::Defective => MemoryRegionKind::BadMemory,
+        };
+        let range = PhysRange::new(area.start_address(), area.end_address());
+        memory_map.add_firmware_region(range, kind);
+    }
+
+    // The first MiB holds real-mode data structures and legacy MMIO. Only the
+    // conventional memory that the firmware reports as usable stays usable,
+    // for the AP startup page. Allocations are served from the top, so it is
+    // not used for anything else in practice.
+    let conventional = PhysRange::new(smp::STARTUP_PAGE_MIN, smp::STARTUP_PAGE_MAX);
+    let low_usable = memory_map
+        .overlapping(conventional)
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .filter_map(|region| region.range.intersection(&conventional))
+        .collect::<Vec<_>>();
+    memory_map.mark(PhysRange::new(0, 0x100000), MemoryRegionKind::Reserved);
+    for range in low_usable {
+        memory_map.mark(range, MemoryRegionKind::Usable);
+    }
+
+    let loader = crate::mem::loader_range().page_aligned();
+    memory_map.mark(loader, MemoryRegionKind::PhipsBoot);
+
+    let mbi_range = PhysRange::from_len(mbi.start_address() as u64, mbi.total_size() as u64);
+    memory_map.mark(mbi_range.page_aligned(), MemoryRegionKind::BootloaderInfo);
+
+    Ok(memory_map)
+}
+
+/// Moves PhipsBoot to the top of physical memory, out of the way of kernels
+/// with fixed load addresses.
+fn relocate_phipsboot(memory_map: &mut MemoryMap) -> Result<(), Error> {
+    let old = crate::mem::loader_range().page_aligned();
+    let new = memory_map
+        .allocate(
+            old.len(),
+            PageSize::Size2MiB.val(),
+            MemoryRegionKind::PhipsBoot,
+            RELOCATION_LIMIT,
+        )
+        .ok_or(Error::OutOfMemory)?;
+    crate::mem::relocate(PhysAddr::new(new.start()));
+    memory_map.free(old, MemoryRegionKind::PhipsBoot);
+    log::info!("Relocated PhipsBoot from {old} to {new}");
+    Ok(())
+}
@@ -0,0 +1,146 @@
+//! Boot modules that the bootloader loaded alongside the loader.
+
+use super::Error;
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lib::cli::KernelSelector;
+use lib::mem::map::{MemoryMap, MemoryRegionKind, PhysRange};
+use lib::mem::paging::{flags, AddressSpace, FrameAllocator, MapError, PageSize, VirtAddr};
+use lib::mem::paging::{PhysAddr, PAGE_SIZE};
+use multiboot2::BootInformation;
+
+/// A boot module in physical memory.
+#[derive(Debug)]
+pub struct Module {
+    cmdline: String,
+    range: PhysRange,
+    /// Address in the module window of the kernel's address space.
+    virt_addr: Option<u64>,
+}
+
+impl Module {
+    /// Returns the command line string of the module.
+    pub fn cmdline(&self) -> &str {
+        &self.cmdline
+    }
+
+    /// Returns the name of the module, which is the first word of its command
+    /// line string.
+    pub fn name(&self) -> &str {
+        self.cmdline.split(' ').next().unwrap_or("")
+    }
+
+    /// Returns the physical location of the module.
+    pub fn range(&self) -> PhysRange {
+        self.range
+    }
+
+    /// Returns the address of the module in the kernel's address space, if it
+    /// is mapped.
+    pub fn virt_addr(&self) -> Option<u64> {
+        self.virt_addr
+    }
+
+    /// Returns the content of the module.
+    pub fn bytes(&self) -> &[u8] {
+        if self.range.is_empty() {
+            return &[];
+        }
+        // The module is within the identity mapping.
+        unsafe {
+            core::slice::from_raw_parts(self.range.start() as *const u8, self.range.len() as usize)
+        }
+    }
+
+    /// Returns true if any page of the module overlaps with one of the ranges.
+    pub fn collides_with(&self, ranges: &[PhysRange]) -> bool {
+        let range = self.range.page_aligned();
+        ranges.iter().any(|other| other.overlaps(&range))
+    }
+
+    /// Moves the module to newly allocated memory and frees its old location.
+    pub fn relocate(&mut self, memory_map: &mut MemoryMap) -> Result<(), Error> {
+        let len = self.range.len().next_multiple_of(PAGE_SIZE);
+        let new = memory_map
+            .allocate(len, PAGE_SIZE, MemoryRegionKind::Module, IDENTITY_MAPPING_LIMIT)
+            .ok_or(Error::OutOfMemory)?;
+        let new = PhysRange::from_len(new.start(), self.range.len());
+        log::debug!("Relocating module {:?}: {} -> {}", self.name(), self.range, new);
+
+        // Ranges never overlap, as the old range is still marked as module or
+        // as kernel.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.range.start() as *const u8,
+                new.start() as *mut u8,
+                self.range.len() as usize,
+            );
+        }
+        memory_map.free(self.range.page_aligned(), MemoryRegionKind::Module);
+        self.range = new;
+        Ok(())
+    }
+}
+
+/// Collects all boot modules from the Multiboot2 information and marks them
+/// in the memory map.
+pub fn from_mbi(mbi: &BootInformation, memory_map: &mut MemoryMap) -> Result<Vec<Module>, Error> {
+    let mut modules = Vec::new();
+    for tag in mbi.module_tags() {
+        let module = Module {
+            cmdline: tag.cmdline().unwrap_or("").into(),
+            range: PhysRange::new(tag.start_address() as u64, tag.end_address() as u64),
+            virt_addr: None,
+        };
+        if module.range.end() > IDENTITY_MAPPING_LIMIT {
+            return Err(Error::NotAccessible(module.range));
+        }
+        memory_map.mark(module.range.page_aligned(), MemoryRegionKind::Module);
+        log::debug!("Boot module {:?} at {}", module.cmdline, module.range);
+        modules.push(module);
+    }
+    Ok(modules)
+}
+
+/// Removes the first module that matches the selector from the list and
+/// returns it.
+pub fn take_kernel(modules: &mut Vec<Module>, selector: &KernelSelector) -> Result<Module, Error> {
+    let index = modules
+        .iter()
+        .enumerate()
+        .position(|(index, m)| selector.matches(index, m.cmdline(), m.bytes()))
+        .ok_or_else(|| Error::KernelNotFound {
+            selector: selector.clone(),
+            available: modules.iter().map(|m| (m.name().into(), m.range)).collect(),
+        })?;
+    let kernel = modules.remove(index);
+    log::info!("Selected module {index} ({:?}) as kernel", kernel.name());
+    Ok(kernel)
+}
+
+/// Maps all modules consecutively into the module window at `base`. Each
+/// module starts at a page boundary and is mapped read-write and
+/// non-executable.
+pub fn map_window(
+    modules: &mut [Module],
+    base: u64,
+    address_space: &mut AddressSpace,
+    alloc: &mut impl FrameAllocator,
+) -> Result<(), MapError> {
+    let mut vaddr = base;
+    for module in modules.iter_mut().filter(|m| !m.range.is_empty()) {
+        let pages = module.range.page_aligned();
+        address_space.map_range(
+            VirtAddr::new(vaddr),
+            PhysAddr::new(pages.start()),
+            pages.len(),
+            PageSize::Size4KiB,
+            flags::WRITABLE | flags::NO_EXECUTE,
+            alloc,
+        )?;
+        module.virt_addr = Some(vaddr + (module.range.start() - pages.start()));
+        vaddr += pages.len();
+    }
+    Ok(())
+}
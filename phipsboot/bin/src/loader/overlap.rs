@@ -0,0 +1,153 @@
+//! Resolution of overlaps between the kernel's LOAD segments and memory that
+//! is in use. As the bootloader may have placed PhipsBoot, its boot
+//! information, and the boot modules anywhere, a kernel with fixed physical
+//! addresses may collide with them. These are moved out of the way; all other
+//! collisions are reported.
+
+use super::modules::Module;
+use super::Error;
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use lib::mem::map::{MemoryMap, MemoryRegion, MemoryRegionKind, PhysRange};
+use lib::mem::paging::PAGE_SIZE;
+
+/// What a kernel segment collides with.
+#[derive(Debug)]
+pub enum Obstacle {
+    /// A region of the memory map that can't be moved, such as firmware
+    /// memory.
+    Region(MemoryRegion),
+    /// Memory that the memory map doesn't describe.
+    NotRam,
+    /// A boot module, but `--relocate-modules` is not set.
+    Module(String),
+}
+
+impl Display for Obstacle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Region(region) => write!(f, "{region}"),
+            Self::NotRam => write!(f, "memory that is not RAM"),
+            Self::Module(name) => write!(f, "module {name:?} (see --relocate-modules)"),
+        }
+    }
+}
+
+/// A collision of a kernel segment that can't be resolved.
+#[derive(Debug)]
+pub struct Collision {
+    pub segment: PhysRange,
+    /// The colliding part of the segment.
+    pub overlap: PhysRange,
+    pub with: Obstacle,
+}
+
+impl Display for Collision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} of kernel segment {} collides with {}",
+            self.overlap, self.segment, self.with
+        )
+    }
+}
+
+/// Moves PhipsBoot, the Multiboot2 information, and the boot modules out of
+/// the way of the kernel's LOAD segments and marks the segments as
+/// [`MemoryRegionKind::Kernel`]. Fails with all collisions that can't be
+/// resolved before anything is moved. Fails as well if there is no memory
+/// for a move; what was moved until then stays moved.
+pub fn resolve(
+    segments: &[PhysRange],
+    kernel: &mut Module,
+    modules: &mut [Module],
+    memory_map: &mut MemoryMap,
+    relocate_modules: bool,
+) -> Result<(), Error> {
+    let mut collisions = Vec::new();
+    let mut move_loader = false;
+    let mut move_mbi = false;
+    for &segment in segments {
+        if segment.end() > IDENTITY_MAPPING_LIMIT {
+            return Err(Error::NotAccessible(segment));
+        }
+
+        for region in memory_map.overlapping(segment) {
+            match region.kind {
+                MemoryRegionKind::Usable | MemoryRegionKind::Module => {}
+                MemoryRegionKind::PhipsBoot => move_loader = true,
+                MemoryRegionKind::BootloaderInfo => move_mbi = true,
+                _ => collisions.push(Collision {
+                    segment,
+                    overlap: segment.intersection(&region.range).unwrap(),
+                    with: Obstacle::Region(*region),
+                }),
+            }
+        }
+        collisions.extend(memory_map.gaps(segment).into_iter().map(|gap| Collision {
+            segment,
+            overlap: gap,
+            with: Obstacle::NotRam,
+        }));
+        // The collision with the kernel's own ELF file is always resolved.
+        if !relocate_modules {
+            for module in modules.iter() {
+                if let Some(overlap) = segment.intersection(&module.range().page_aligned()) {
+                    collisions.push(Collision {
+                        segment,
+                        overlap,
+                        with: Obstacle::Module(module.cmdline().into()),
+                    });
+                }
+            }
+        }
+    }
+    if !collisions.is_empty() {
+        return Err(Error::Collisions(collisions));
+    }
+
+    mark_segments(memory_map, segments);
+    if move_loader {
+        log::info!("PhipsBoot collides with the kernel");
+        super::relocate_phipsboot(memory_map)?;
+    }
+    if move_mbi {
+        relocate_mbi(memory_map)?;
+    }
+    if kernel.collides_with(segments) {
+        kernel.relocate(memory_map)?;
+    }
+    for module in modules.iter_mut().filter(|m| m.collides_with(segments)) {
+        module.relocate(memory_map)?;
+    }
+    Ok(())
+}
+
+/// Moves the Multiboot2 information to newly allocated memory and frees its
+/// old location.
+fn relocate_mbi(memory_map: &mut MemoryMap) -> Result<(), Error> {
+    let mbi = crate::env::mbi().ok_or(Error::UnsupportedBootVariant)?;
+    let old = PhysRange::from_len(mbi.start_address() as u64, mbi.total_size() as u64);
+    let new = memory_map
+        .allocate(
+            old.page_aligned().len(),
+            PAGE_SIZE,
+            MemoryRegionKind::BootloaderInfo,
+            IDENTITY_MAPPING_LIMIT,
+        )
+        .ok_or(Error::OutOfMemory)?;
+    let new = PhysRange::from_len(new.start(), old.len());
+    log::debug!("Relocating the Multiboot2 information: {old} -> {new}");
+
+    crate::env::relocate_mbi(new.start());
+    memory_map.free(old.page_aligned(), MemoryRegionKind::BootloaderInfo);
+    Ok(())
+}
+
+fn mark_segments(memory_map: &mut MemoryMap, segments: &[PhysRange]) {
+    for segment in segments {
+        memory_map.mark(*segment, MemoryRegionKind::Kernel);
+    }
+}
@@ -0,0 +1,267 @@
+//! Start-up of the application processors (APs) for `--smp`.
+//!
+//! The processors are enumerated from the ACPI MADT. Each AP gets its own
+//! stack and is started with the INIT-SIPI-SIPI sequence, one after another.
+//! The startup code (`ap_startup.S`) runs from a page below 1 MiB and brings
+//! the AP into 64-bit mode on the kernel's page tables. There, the AP polls
+//! the `goto_address` of its [`Cpu`] entry in the boot information until the
+//! kernel sends it somewhere.
+//!
+//! All APs share the kernel's GDT with the BSP, but none loads a TSS: the TSS
+//! in the GDT belongs to the BSP. The kernel sets up a TSS per AP itself.
+
+use super::handoff::allocate_stack;
+use super::Error;
+use crate::driver::lapic::{self, Mode};
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use crate::time::{self, Deadline};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::time::Duration;
+use lib::acpi::MADT_SIGNATURE;
+use lib::bootinfo::{BootInformation, Cpu, CPU_BSP, CPU_ONLINE};
+use lib::mem::map::{MemoryMap, MemoryRegionKind, PhysRange};
+use lib::mem::paging::{flags, AddressSpace, PageSize, PhysAddr, VirtAddr, PAGE_SIZE};
+
+/// The startup page must be in conventional memory: above the real-mode IVT
+/// and below the EBDA.
+pub const STARTUP_PAGE_MIN: u64 = 0x1000;
+pub const STARTUP_PAGE_MAX: u64 = 0x9f000;
+
+/// Delay between the INIT IPI and the first SIPI.
+const INIT_DELAY: Duration = Duration::from_millis(10);
+/// Delay after which the SIPI is repeated if the AP didn't start yet.
+const SIPI_DELAY: Duration = Duration::from_micros(200);
+/// Time that an AP has to report that it started.
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Parameters for the startup code at the end of the startup page.
+/// KEEP IN SYNC WITH `ap_startup.S`.
+#[repr(C)]
+struct StartupParams {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    /// Value of `XCR0`, or `0` to leave it unchanged.
+    xcr0: u64,
+    /// Aligns the base of the GDT pointer.
+    gdtr_padding: [u16; 3],
+    gdtr_limit: u16,
+    gdtr_base: u64,
+    rsp: u64,
+    /// Address of the [`Cpu`] entry of the AP.
+    cpu: u64,
+    /// Set by the AP once it no longer needs the parameters.
+    started: u64,
+}
+
+/// The machine state of the kernel that the APs get as well.
+#[derive(Debug)]
+pub struct ApState {
+    pub root_page_table: PhysAddr,
+    pub cr0: u64,
+    pub cr4: u64,
+    pub xcr0: u64,
+    /// Limit and base of the kernel's GDT.
+    pub gdt: (u16, u64),
+}
+
+/// The processors of the machine and what is needed to start the APs.
+#[derive(Debug)]
+pub struct Smp {
+    startup_page: PhysAddr,
+    /// The processors from the MADT. The stacks of the APs are allocated.
+    cpus: Vec<Cpu>,
+}
+
+impl Smp {
+    /// Enumerates the processors, allocates a stack of `stack_size` bytes for
+    /// each AP, and reserves the startup page. Like the kernel stack, each AP
+    /// stack has an unmapped guard page below it. Returns `None` with a warning
+    /// if the APs can't be started.
+    pub fn new(
+        stack_size: u64,
+        memory_map: &mut MemoryMap,
+        address_space: &mut AddressSpace,
+    ) -> Result<Option<Self>, Error> {
+        let mode = lapic::mode();
+        let lapic_usable = match mode {
+            Mode::Disabled => false,
+            Mode::XApic => lapic::xapic_base() < IDENTITY_MAPPING_LIMIT,
+            Mode::X2Apic => true,
+        };
+        if !lapic_usable || !time::is_available() {
+            log::warn!("The LAPIC or its timer is not usable, ignoring --smp");
+            return Ok(None);
+        }
+        let Some(madt) = crate::acpi::find_table(MADT_SIGNATURE) else {
+            log::warn!("There is no ACPI MADT, ignoring --smp");
+            return Ok(None);
+        };
+        let Some(startup_page) = find_startup_page(memory_map) else {
+            log::warn!("There is no free page below 1 MiB for the AP startup code, ignoring --smp");
+            return Ok(None);
+        };
+        let startup_range = PhysRange::from_len(startup_page.val(), PAGE_SIZE);
+        memory_map.mark(startup_range, MemoryRegionKind::Kernel);
+        let mut alloc =
+            memory_map.frame_allocator(MemoryRegionKind::PageTables, IDENTITY_MAPPING_LIMIT);
+        address_space.map_page(
+            VirtAddr::new(startup_page.val()),
+            startup_page,
+            PageSize::Size4KiB,
+            flags::WRITABLE,
+            &mut alloc,
+        )?;
+
+        let bsp_apic_id = lapic::apic_id();
+        let mut cpus = Vec::new();
+        for madt_cpu in lib::acpi::madt_cpus(&madt) {
+            let mut cpu = Cpu {
+                processor_uid: madt_cpu.processor_uid,
+                apic_id: madt_cpu.apic_id,
+                flags: 0,
+                reserved: 0,
+                goto_address: 0,
+                extra_argument: 0,
+                stack_base: 0,
+                stack_size: 0,
+            };
+            if cpu.apic_id == bsp_apic_id {
+                cpu.flags = CPU_BSP | CPU_ONLINE;
+            } else if mode == Mode::XApic && cpu.apic_id >= 0xff {
+                log::warn!(
+                    "Skipping the CPU with APIC ID {:#x}, which is not addressable in xAPIC mode",
+                    cpu.apic_id
+                );
+                continue;
+            } else {
+                let stack = allocate_stack(stack_size, memory_map, address_space)?;
+                cpu.stack_base = stack.start();
+                cpu.stack_size = stack.len();
+            }
+            cpus.push(cpu);
+        }
+        log::debug!(
+            "{} processors in the MADT, AP startup page at {startup_range}",
+            cpus.len()
+        );
+
+        Ok(Some(Self { startup_page, cpus }))
+    }
+
+    /// Returns the number of processors.
+    pub fn cpu_count(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// Returns the entries of all processors for the boot information. The
+    /// BSP gets the kernel stack.
+    pub fn cpus(&self, bsp_stack: PhysRange) -> Vec<Cpu> {
+        let mut cpus = self.cpus.clone();
+        for cpu in cpus.iter_mut().filter(|cpu| cpu.flags & CPU_BSP != 0) {
+            cpu.stack_base = bsp_stack.start();
+            cpu.stack_size = bsp_stack.len();
+        }
+        cpus
+    }
+
+    /// Starts the APs one after another and marks the ones that reported
+    /// themselves as online. `boot_info` is the written boot information, in
+    /// whose [`Cpu`] entries the APs wait for the kernel.
+    pub fn start(&self, boot_info: u64, state: &ApState) {
+        let code = crate::extern_symbols::ap_startup();
+        let len = crate::extern_symbols::ap_startup_end() as usize - code as usize;
+        assert!(len + size_of::<StartupParams>() <= PAGE_SIZE as usize);
+        let page = self.startup_page.val();
+        // The startup page is within the identity mapping of the loader.
+        unsafe { core::ptr::copy_nonoverlapping(code, page as *mut u8, len) };
+        let params = (page + PAGE_SIZE - size_of::<StartupParams>() as u64) as *mut StartupParams;
+        let vector = (page / PAGE_SIZE) as u8;
+
+        // The boot information is identity-mapped in both address spaces.
+        let cpus = unsafe {
+            let info = &*(boot_info as *const BootInformation);
+            core::slice::from_raw_parts_mut(info.cpus_ptr as *mut Cpu, info.cpus_count as usize)
+        };
+        let mut aps = 0;
+        let mut online = 0;
+        for cpu in cpus.iter_mut().filter(|cpu| cpu.flags & CPU_BSP == 0) {
+            aps += 1;
+            unsafe {
+                params.write_volatile(StartupParams {
+                    cr0: state.cr0,
+                    cr3: state.root_page_table.val(),
+                    cr4: state.cr4,
+                    xcr0: state.xcr0,
+                    gdtr_padding: [0; 3],
+                    gdtr_limit: state.gdt.0,
+                    gdtr_base: state.gdt.1,
+                    // As if the goto address was called.
+                    rsp: cpu.stack_base + cpu.stack_size - 8,
+                    cpu: cpu as *mut Cpu as u64,
+                    started: 0,
+                })
+            };
+            match start_ap(cpu.apic_id, vector, params) {
+                Some(true) => {
+                    cpu.flags |= CPU_ONLINE;
+                    online += 1;
+                }
+                Some(false) => log::warn!(
+                    "The CPU with APIC ID {:#x} didn't respond to the startup IPIs",
+                    cpu.apic_id
+                ),
+                None => {
+                    log::warn!("The clock is not available, not starting the remaining APs");
+                    break;
+                }
+            }
+        }
+        log::info!("Started {online} of {aps} application processors");
+    }
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to the AP and waits until it reports
+/// that it started. Returns `None` if the clock for the delays is not
+/// available.
+fn start_ap(apic_id: u32, vector: u8, params: *const StartupParams) -> Option<bool> {
+    let started = || unsafe { addr_of!((*params).started).read_volatile() != 0 };
+
+    lapic::send_init(apic_id);
+    time::sleep(INIT_DELAY)?;
+    lapic::send_startup(apic_id, vector);
+    time::sleep(SIPI_DELAY)?;
+    if !started() {
+        lapic::send_startup(apic_id, vector);
+    }
+
+    let deadline = Deadline::after(STARTUP_TIMEOUT)?;
+    while !started() {
+        if deadline.has_expired() {
+            // Put the AP back into the wait-for-SIPI state, so that it can't
+            // run with the parameters of the next AP.
+            lapic::send_init(apic_id);
+            return Some(false);
+        }
+        core::hint::spin_loop();
+    }
+    Some(true)
+}
+
+/// Returns the highest usable page of conventional memory below 1 MiB.
+fn find_startup_page(memory_map: &MemoryMap) -> Option<PhysAddr> {
+    let conventional = PhysRange::new(STARTUP_PAGE_MIN, STARTUP_PAGE_MAX);
+    memory_map
+        .overlapping(conventional)
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .filter_map(|region| region.range.intersection(&conventional))
+        .filter_map(|range| {
+            let start = range.start().next_multiple_of(PAGE_SIZE);
+            let end = range.end() & !(PAGE_SIZE - 1);
+            (end >= start + PAGE_SIZE).then(|| end - PAGE_SIZE)
+        })
+        .max()
+        .map(PhysAddr::new)
+}
@@ -1,4 +1,3 @@
-#![feature(abi_x86_interrupt)]
 #![no_main]
 #![no_std]
 
@@ -8,16 +7,24 @@
 
 extern crate alloc;
 
+mod acpi;
 mod asm;
+mod backtrace;
+mod cpu;
 mod driver;
 mod env;
 mod extern_symbols;
+mod failure;
+mod gdt;
 mod idt;
+mod loader;
 mod mem;
+mod pci;
+mod smbios;
+mod time;
 mod xen_pvh;
 
 use crate::mem::stack;
-use core::fmt::Write;
 use core::hint::black_box;
 use core::panic::PanicInfo;
 use lib::logger;
@@ -32,7 +39,8 @@ use lib::logger;
 ///
 /// # Paging
 /// The hole loader is reachable via its link address (2 MiB mapping) and via
-/// an identity mapping of the physical location in memory.
+/// an identity mapping of the physical location in memory. After
+/// [`mem::init`], the first 4 GiB of physical memory are identity-mapped.
 #[no_mangle]
 extern "C" fn rust_entry64(
     bootloader_magic: u64,
@@ -40,28 +48,37 @@ extern "C" fn rust_entry64(
     load_addr_offset: i64,
 ) -> ! {
     // The order of the init functions mostly reflect actual dependencies!
+    gdt::init();
     idt::init();
+    driver::pic::init();
     mem::init(load_addr_offset);
     logger::init(); // after mem init; logger depends on heap!
     logger::add_backend(driver::DebugconLogger::default()).unwrap();
     logger::add_backend(driver::SerialLogger::default()).unwrap();
     logger::flush(); // flush all buffered messages
+    driver::lapic::mask_all(); // after mem init; the registers are MMIO
 
     env::init(bootloader_magic, bootloader_info_ptr);
+    failure::init(env::cli_args().on_error());
     env::print();
+    acpi::init();
+    time::init(); // after acpi init; the HPET is described by ACPI
+    smbios::init();
+    pci::init();
 
     stack::assert_sanity_checks();
 
     log::info!("Now loading your kernel into 64-bit mode...");
-    log::info!("Not implemented yet! =(");
+    let handoff =
+        loader::load_kernel().unwrap_or_else(|e| panic!("Failed to load the kernel: {e}"));
 
     // break_stack();
-    create_pagefault();
+    // create_pagefault();
 
-    loop {}
+    handoff.jump()
 }
 
-/// Sometimes useful to test the stack + stack canary.
+/// Sometimes useful to test the stack guard page and the stack canary.
 #[allow(unused, unconditional_recursion)]
 #[inline(never)]
 fn break_stack() {
@@ -83,15 +100,13 @@ fn create_pagefault() {
 
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
-    // If a panic happens, we are screwed anyways. We do some additional
-    // emergency logging without the whole log-stack
-    let _ = writeln!(&mut driver::DebugconLogger, "PANIC: {info:#?}");
-
-    // log::error!("PANIC: {info:#?}");
-
-    unsafe {
-        // TODO only do this when no logging is initialized?!
-        core::arch::asm!("ud2", in("rax") 0xbadb001);
+    // The regular logging might be the cause of the panic, so the message is
+    // written via the emergency path of the logger.
+    failure::emergency_println(format_args!("PANIC: {info:#?}"));
+    failure::emergency_println(format_args!("backtrace:"));
+    for (i, frame) in backtrace::Backtrace::here().enumerate() {
+        failure::emergency_println(format_args!("  #{i:<2} {frame}"));
     }
-    loop {}
+
+    failure::fail()
 }
@@ -1,28 +1,47 @@
 //! Abstraction for managing memory of the system and the loader.
 
-use core::cell::OnceCell;
+use core::sync::atomic::{AtomicI64, Ordering};
+use lib::mem::map::PhysRange;
 use lib::mem::paging::{PhysAddr, VirtAddr};
-use lib::safe::Safe;
 
 mod heap;
+pub mod paging;
+mod relocation;
 pub mod stack;
 
-/// Stores the load offset of the loader in physical memory.
-static ONCE: Safe<OnceCell<i64>> = Safe::new(OnceCell::new());
+pub use relocation::relocate;
+
+/// Stores the load offset of the loader in physical memory. It only changes
+/// if the loader relocates itself.
+static LOAD_OFFSET: AtomicI64 = AtomicI64::new(0);
 
 pub fn init(load_offset: i64) {
-    let _ = ONCE.get_or_init(|| load_offset);
+    LOAD_OFFSET.store(load_offset, Ordering::Relaxed);
     stack::init();
     heap::init();
+    paging::init();
 }
 
 /// Returns the load offset of the loader in physical memory.
 pub fn load_offset() -> i64 {
-    *ONCE.get().expect("should have been configured")
+    LOAD_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Returns the physical memory that the loader occupies.
+pub fn loader_range() -> PhysRange {
+    let start = virt_to_phys(crate::extern_symbols::link_addr_boot().into());
+    PhysRange::from_len(start.val(), crate::extern_symbols::bin_size())
 }
 
-/// Translates the virtual link address to a physical address in memory.
+/// Translates the virtual link address to a physical address in memory. This
+/// works for the low link addresses of the boot code and for the high link
+/// addresses of the high-level code.
 pub fn virt_to_phys(virt: VirtAddr) -> PhysAddr {
-    // assert_eq!(virt >= );
-    (virt.val() as i64 + load_offset()).into()
+    let high_base = crate::extern_symbols::link_addr_high_base() as u64;
+    let low_link_addr = if virt.val() >= high_base {
+        virt.val() - high_base + crate::extern_symbols::link_addr_boot() as u64
+    } else {
+        virt.val()
+    };
+    (low_link_addr as i64 + load_offset()).into()
 }
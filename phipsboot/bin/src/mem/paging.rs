@@ -0,0 +1,203 @@
+//! Runtime page tables of the loader.
+//!
+//! The page tables of the boot code only map the loader itself. To access the
+//! boot information of the bootloader, the boot modules, and the memory of the
+//! kernel, the loader switches to its own page tables, which additionally
+//! identity-map the first 4 GiB of physical memory with 2 MiB pages. The
+//! mappings of the high-level code are taken over from the boot page tables.
+//! If the loader relocates itself above 4 GiB, its new location is
+//! identity-mapped as well, so that all page tables stay accessible.
+
+use crate::mem::virt_to_phys;
+use core::ptr::addr_of_mut;
+use lib::mem::map::PhysRange;
+use lib::mem::paging::{
+    flags, AddressSpace, Level, PageSize, PageTable, PhysAddr, VirtAddr, ENTRY_ADDR_BITMASK,
+    PAGE_SIZE,
+};
+
+/// Physical memory below this address is identity-mapped and thus accessible
+/// by the loader.
+pub const IDENTITY_MAPPING_LIMIT: u64 = 0x100000000 /* 4 GiB */;
+
+/// The loader can only relocate itself below this address, as its new
+/// location must be identity-mapped in the lower half of the address space.
+pub const RELOCATION_LIMIT: u64 = 1 << 47;
+
+/// One level 2 table per GiB.
+const L2_TABLE_COUNT: usize = 4;
+
+const EMPTY_TABLE: PageTable = PageTable::new();
+
+static mut PT_L4: PageTable = PageTable::new();
+static mut PT_L3_LO: PageTable = PageTable::new();
+static mut PT_L2_LO: [PageTable; L2_TABLE_COUNT] = [EMPTY_TABLE; L2_TABLE_COUNT];
+static mut PT_L3_RELOC: PageTable = PageTable::new();
+static mut PT_L2_RELOC: PageTable = PageTable::new();
+/// Splits the 2 MiB page of the identity mapping that holds the trampoline.
+static mut PT_L1_TRAMPOLINE: PageTable = PageTable::new();
+
+/// Creates and activates the runtime page tables.
+pub fn init() {
+    let (l4, l3, l2s) = unsafe {
+        (
+            &mut *addr_of_mut!(PT_L4),
+            &mut *addr_of_mut!(PT_L3_LO),
+            &mut *addr_of_mut!(PT_L2_LO),
+        )
+    };
+
+    // The boot page tables live in the boot section, which is identity-mapped.
+    let boot_l4 = unsafe { x86::controlregs::cr3() } as *const PageTable;
+    *l4 = unsafe { boot_l4.read() };
+
+    for (i, l2) in l2s.iter_mut().enumerate() {
+        for (j, entry) in l2.entries_mut().iter_mut().enumerate() {
+            let addr = (i as u64 * PageSize::Size1GiB.val()) + j as u64 * PageSize::Size2MiB.val();
+            *entry = addr | flags::PRESENT | flags::WRITABLE | flags::HUGE_PAGE | flags::NO_EXECUTE;
+        }
+        l3.entries_mut()[i] = table_entry(l2);
+    }
+    // The identity mapping of the boot code is covered by the new mapping.
+    l4.entries_mut()[0] = table_entry(l3);
+
+    let l4_phys = virt_to_phys(VirtAddr::from(l4 as *const PageTable as u64));
+    unsafe { x86::controlregs::cr3_write(l4_phys.val()) };
+}
+
+/// Makes the 4 KiB page of the identity mapping at `addr` executable. This is
+/// required to run the trampoline into the kernel. The 2 MiB page that
+/// contains it is split, so that the rest of it stays non-executable. Can only
+/// be called once, after the loader is at its final location.
+pub fn make_executable(addr: PhysAddr) {
+    assert!(addr.val() < IDENTITY_MAPPING_LIMIT);
+    assert!(addr.is_aligned(PAGE_SIZE));
+    let (l2s, l1) = unsafe {
+        (
+            &mut *addr_of_mut!(PT_L2_LO),
+            &mut *addr_of_mut!(PT_L1_TRAMPOLINE),
+        )
+    };
+    assert_eq!(l1.entries()[0], 0, "only one page can be made executable");
+
+    let vaddr = VirtAddr::new(addr.val());
+    let base = addr.val() & !(PageSize::Size2MiB.val() - 1);
+    for (i, entry) in l1.entries_mut().iter_mut().enumerate() {
+        let page = base + i as u64 * PAGE_SIZE;
+        *entry = page | flags::PRESENT | flags::WRITABLE | flags::NO_EXECUTE;
+    }
+    l1.entries_mut()[vaddr.pt_index(Level::One) as usize] &= !flags::NO_EXECUTE;
+    let l2 = &mut l2s[(addr.val() / PageSize::Size1GiB.val()) as usize];
+    l2.entries_mut()[vaddr.pt_index(Level::Two) as usize] = table_entry(l1);
+    unsafe { x86::tlb::flush_all() };
+}
+
+/// Identity-maps the 2 MiB page at `base`, which is the new location of the
+/// loader (see [`crate::mem::relocate`]). Nothing is done if the page is
+/// already covered by the identity mapping of the first 4 GiB.
+pub fn map_relocation_target(base: PhysAddr) {
+    assert!(base.is_aligned(PageSize::Size2MiB.val()));
+    assert!(base.val() < RELOCATION_LIMIT);
+    if base.val() < IDENTITY_MAPPING_LIMIT {
+        return;
+    }
+
+    let (l4, l3_lo, l3_reloc, l2_reloc) = unsafe {
+        (
+            &mut *addr_of_mut!(PT_L4),
+            &mut *addr_of_mut!(PT_L3_LO),
+            &mut *addr_of_mut!(PT_L3_RELOC),
+            &mut *addr_of_mut!(PT_L2_RELOC),
+        )
+    };
+    let vaddr = VirtAddr::new(base.val());
+    let l3 = match vaddr.pt_index(Level::Four) {
+        0 => l3_lo,
+        index => {
+            l4.entries_mut()[index as usize] = table_entry(l3_reloc);
+            l3_reloc
+        }
+    };
+    l3.entries_mut()[vaddr.pt_index(Level::Three) as usize] = table_entry(l2_reloc);
+    l2_reloc.entries_mut()[vaddr.pt_index(Level::Two) as usize] =
+        base.val() | flags::PRESENT | flags::WRITABLE | flags::HUGE_PAGE | flags::NO_EXECUTE;
+}
+
+/// Updates the copies of the page tables after the loader moved from `old` by
+/// `delta` bytes and activates them. All entries that reference the old
+/// location are moved by `delta`, except for the identity mapping of the
+/// first 4 GiB. Must be called after the load offset was updated.
+pub fn rebase(old: PhysRange, delta: u64) {
+    use crate::extern_symbols::*;
+
+    // The page tables of the boot code are only reachable via the identity
+    // mapping.
+    let boot_table = |symbol: *const u8| {
+        let phys = virt_to_phys(VirtAddr::from(symbol as u64));
+        unsafe { &mut *(phys.val() as *mut PageTable) }
+    };
+    let tables = unsafe {
+        [
+            &mut *addr_of_mut!(PT_L4),
+            &mut *addr_of_mut!(PT_L3_LO),
+            &mut *addr_of_mut!(PT_L3_RELOC),
+            boot_table(boot_mem_pt_l3_hi()),
+            boot_table(boot_mem_pt_l2_hi()),
+            boot_table(boot_mem_pt_l1_hi()),
+        ]
+    };
+    for table in tables {
+        for entry in table.entries_mut() {
+            let addr = *entry & ENTRY_ADDR_BITMASK;
+            if *entry & flags::PRESENT != 0 && addr >= old.start() && addr < old.end() {
+                *entry = entry.wrapping_add(delta);
+            }
+        }
+    }
+
+    let l4 = unsafe { &*addr_of_mut!(PT_L4) };
+    let l4_phys = virt_to_phys(VirtAddr::from(l4 as *const PageTable as u64));
+    unsafe { x86::controlregs::cr3_write(l4_phys.val()) };
+}
+
+/// Returns the physical memory `addr..addr + len` as slice, if it is within
+/// the identity mapping. This is how the firmware tables are read.
+pub fn phys_slice(addr: u64, len: u64) -> Option<&'static [u8]> {
+    if addr == 0 || addr.checked_add(len)? > IDENTITY_MAPPING_LIMIT {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+/// Returns true if the range `addr..addr + len` is mapped in the currently
+/// active page tables. This is used to read memory of unknown validity in
+/// diagnostics, such as the code around a faulting instruction, and works with
+/// the boot page tables as well.
+pub fn is_mapped(addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    if len == 0 || !is_canonical(addr) || !is_canonical(end - 1) {
+        return false;
+    }
+    // The active page tables are identity-mapped.
+    let root = unsafe { x86::controlregs::cr3() } & !0xfff;
+    let address_space = unsafe { AddressSpace::from_root(PhysAddr::new(root)) };
+    (addr / PAGE_SIZE..=(end - 1) / PAGE_SIZE).all(|page| {
+        address_space
+            .translate(VirtAddr::new(page * PAGE_SIZE))
+            .is_some()
+    })
+}
+
+/// Returns true if the address is canonical with 48-bit virtual addresses.
+fn is_canonical(addr: u64) -> bool {
+    let upper = addr >> 47;
+    upper == 0 || upper == 0x1ffff
+}
+
+/// Returns a page-table entry that references the given table.
+fn table_entry(table: &PageTable) -> u64 {
+    let phys = virt_to_phys(VirtAddr::from(table as *const PageTable as u64));
+    phys.val() | flags::PRESENT | flags::WRITABLE
+}
@@ -0,0 +1,61 @@
+//! Relocation of the loader in physical memory (`--relocate-phipsboot`).
+//!
+//! The boot code runs in 32-bit mode, so the bootloader must place the loader
+//! below 4 GiB, where it may be in the way of kernels with fixed load
+//! addresses. The high-level code only uses its link addresses, so the loader
+//! can move itself anywhere once it runs in 64-bit mode: only the page tables
+//! hold physical addresses of the loader.
+
+use super::{paging, LOAD_OFFSET};
+use core::sync::atomic::Ordering;
+use lib::mem::paging::{PageSize, PhysAddr, VirtAddr, PAGE_TABLE_ENTRY_COUNT};
+
+/// Copies the loader to `dest`, which must be 2 MiB-aligned free memory, and
+/// continues execution there. The old location is unused afterwards.
+pub fn relocate(dest: PhysAddr) {
+    use crate::extern_symbols::*;
+
+    let old = super::loader_range();
+    assert!(dest.is_aligned(PageSize::Size2MiB.val()));
+    assert!(dest.val() >= old.end() || dest.val() + old.len() <= old.start());
+    let delta = dest.val().wrapping_sub(old.start());
+
+    paging::map_relocation_target(dest);
+
+    let l1_hi = super::virt_to_phys(VirtAddr::from(boot_mem_pt_l1_hi() as u64));
+    // Everything that the high-level code writes between the copy and the
+    // switch of the mapping would be lost, hence no memory is touched except
+    // for the page table that maps the high-level code. This also covers the
+    // stack. Interrupts are disabled in the loader.
+    unsafe {
+        core::arch::asm!(
+            "rep movsb",
+            "2:",
+            "mov ({l1}), {entry}",
+            "test $1, {entry}",
+            "jz 3f",
+            "add {delta}, {entry}",
+            "mov {entry}, ({l1})",
+            "3:",
+            "add $8, {l1}",
+            "dec {count:e}",
+            "jnz 2b",
+            // Flush the TLB.
+            "mov %cr3, {entry}",
+            "mov {entry}, %cr3",
+            inout("rsi") old.start() => _,
+            inout("rdi") dest.val() => _,
+            inout("rcx") old.len() => _,
+            l1 = inout(reg) l1_hi.val() => _,
+            count = inout(reg) PAGE_TABLE_ENTRY_COUNT as u32 => _,
+            delta = in(reg) delta,
+            entry = out(reg) _,
+            options(att_syntax, nostack)
+        );
+    }
+
+    // The high-level code now runs from the copy, but the page tables of the
+    // old location are still active.
+    LOAD_OFFSET.fetch_add(delta as i64, Ordering::Relaxed);
+    paging::rebase(old, delta);
+}
@@ -1,9 +1,12 @@
+use core::ops::Range;
+use lib::mem::paging::PAGE_SIZE;
 use lib::mem::stack::{Stack, DEFAULT_STACK_SIZE};
 use lib::safe::Safe;
 
-/// Backing memory for the stack. This is mutable and lands in the `.data`
-/// section.
+/// Backing memory for the stack. It lands in the `.stack` section, which is
+/// placed right above an unmapped guard page by the linker script.
 #[no_mangle] // Useful to find the stack location with readelf.
+#[link_section = ".stack"]
 static mut STACK: Stack<DEFAULT_STACK_SIZE> = Stack::new();
 
 /// Symbol that holds the pointer to the actual ready-to-use top of the stack.
@@ -47,6 +50,12 @@ pub fn bottom() -> *mut u8 {
     unsafe { STACK.bottom() }
 }
 
+/// Returns the address range of the unmapped guard page below the stack.
+pub fn guard_page() -> Range<u64> {
+    let start = crate::extern_symbols::stack_guard_page() as u64;
+    start..start + PAGE_SIZE
+}
+
 /// Returns the current stack usage in bytes.
 #[inline(never)]
 pub fn usage() -> u64 {
@@ -0,0 +1,93 @@
+//! Enumeration of the PCI functions with [`lib::pci`].
+//!
+//! The configuration space is accessed via the ECAM regions of the ACPI MCFG.
+//! Segment 0 falls back to the legacy configuration mechanism via port I/O if
+//! the MCFG doesn't cover it. All functions are logged at trace level; the
+//! kernel gets them with `--pci`.
+
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use alloc::vec::Vec;
+use core::cell::OnceCell;
+use lib::acpi::{McfgEntry, MCFG_SIGNATURE};
+use lib::bootinfo::PciFunction;
+use lib::pci::PciAddress;
+use lib::safe::Safe;
+use x86::io::{inl, outl};
+
+/// Ports of the legacy configuration mechanism.
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+static FUNCTIONS: Safe<OnceCell<Vec<PciFunction>>> = Safe::new(OnceCell::new());
+
+/// Enumerates all PCI functions and logs them.
+pub fn init() {
+    let mcfg_entries = crate::acpi::find_table(MCFG_SIGNATURE)
+        .map(|mcfg| lib::acpi::mcfg_entries(&mcfg))
+        .unwrap_or_default();
+
+    let mut functions = Vec::new();
+    let mut legacy = true;
+    for entry in mcfg_entries {
+        let end = entry.base_address + ((entry.end_bus as u64 + 1) << 20);
+        if end > IDENTITY_MAPPING_LIMIT {
+            log::warn!(
+                "The ECAM of PCI segment {} at {:#x} is not accessible",
+                entry.segment,
+                entry.base_address
+            );
+            continue;
+        }
+        log::debug!(
+            "PCI segment {}: ECAM at {:#x} for buses {:#04x} to {:#04x}",
+            entry.segment,
+            entry.base_address,
+            entry.start_bus,
+            entry.end_bus
+        );
+        legacy &= entry.segment != 0;
+        functions.extend(lib::pci::enumerate(
+            entry.segment,
+            entry.start_bus..=entry.end_bus,
+            |address, offset| ecam_read(&entry, address, offset),
+        ));
+    }
+    if legacy {
+        log::debug!("PCI segment 0: legacy configuration mechanism");
+        functions.extend(lib::pci::enumerate(0, 0..=u8::MAX, legacy_read));
+    }
+
+    log::debug!("Found {} PCI functions", functions.len());
+    for function in &functions {
+        log::trace!(
+            "  {} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} ({})",
+            PciAddress::from(function),
+            function.vendor_id,
+            function.device_id,
+            function.class,
+            function.subclass,
+            function.prog_if,
+            lib::pci::class_name(function.class)
+        );
+    }
+    let _ = FUNCTIONS.set(functions);
+}
+
+/// Returns all PCI functions.
+pub fn functions() -> &'static [PciFunction] {
+    FUNCTIONS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Reads a dword of the configuration space via the identity mapping.
+fn ecam_read(entry: &McfgEntry, address: PciAddress, offset: u8) -> u32 {
+    let addr = entry.base_address + address.ecam_offset() + offset as u64;
+    unsafe { (addr as *const u32).read_volatile() }
+}
+
+/// Reads a dword of the configuration space of segment 0 via port I/O.
+fn legacy_read(address: PciAddress, offset: u8) -> u32 {
+    unsafe {
+        outl(CONFIG_ADDRESS, address.legacy_config_address(offset));
+        inl(CONFIG_DATA)
+    }
+}
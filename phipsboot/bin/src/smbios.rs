@@ -0,0 +1,184 @@
+//! Discovery of the SMBIOS tables of the firmware. They identify the machine
+//! in the log, which helps to debug reports from the field. The tables are
+//! parsed with [`lib::smbios`].
+//!
+//! The tables are found via the entry point in the Multiboot2 information or
+//! in the legacy BIOS area, in this order. If the Multiboot2 information holds
+//! the structure table itself, that is used.
+
+use crate::mem::paging;
+use alloc::vec::Vec;
+use core::cell::OnceCell;
+use core::fmt::{Display, Formatter};
+use lib::safe::Safe;
+use lib::smbios::{
+    BiosInfo, EntryPoint, MemoryDevice, ProcessorInfo, SmbiosError, SystemInfo, ENTRY_POINT_MAX_LEN,
+};
+use multiboot2::SmbiosTag;
+
+/// The BIOS read-only memory area that may hold the entry point.
+const BIOS_AREA_START: u64 = 0xf0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+static SMBIOS: Safe<OnceCell<Smbios>> = Safe::new(OnceCell::new());
+
+/// The SMBIOS tables of the firmware.
+#[derive(Debug)]
+pub enum Smbios {
+    /// The entry point in the BIOS area.
+    Firmware { addr: u64, entry_point: EntryPoint },
+    /// A copy of the entry point from the Multiboot2 information, zero-padded
+    /// to [`ENTRY_POINT_MAX_LEN`] bytes.
+    EntryPointCopy {
+        entry_point: EntryPoint,
+        bytes: [u8; ENTRY_POINT_MAX_LEN],
+    },
+    /// A copy of the structure table from the Multiboot2 information, which
+    /// has no entry point.
+    Tables {
+        major: u8,
+        minor: u8,
+        tables: Vec<u8>,
+    },
+}
+
+/// Finds the SMBIOS tables and logs a summary of the machine.
+pub fn init() {
+    let Some(smbios) = find() else {
+        log::debug!("No SMBIOS tables found");
+        return;
+    };
+    let (major, minor, table) = match &smbios {
+        Smbios::Firmware { entry_point, .. } | Smbios::EntryPointCopy { entry_point, .. } => {
+            log::debug!(
+                "SMBIOS {}.{}: structure table at {:#x}",
+                entry_point.major,
+                entry_point.minor,
+                entry_point.table_address
+            );
+            let table = paging::phys_slice(entry_point.table_address, entry_point.table_len as u64);
+            (entry_point.major, entry_point.minor, table)
+        }
+        Smbios::Tables {
+            major,
+            minor,
+            tables,
+        } => {
+            log::debug!("SMBIOS {major}.{minor} from the Multiboot2 information");
+            (*major, *minor, Some(tables.as_slice()))
+        }
+    };
+    match table {
+        Some(table) => log_summary(table),
+        None => log::warn!("SMBIOS {major}.{minor}: the structure table is not accessible"),
+    }
+    let _ = SMBIOS.set(smbios);
+}
+
+/// Returns the SMBIOS tables, if there are any.
+pub fn get() -> Option<&'static Smbios> {
+    SMBIOS.get()
+}
+
+fn find() -> Option<Smbios> {
+    crate::env::mbi()
+        .and_then(|mbi| mbi.smbios_tag())
+        .and_then(from_mbi)
+        .or_else(from_bios_area)
+}
+
+/// Copies the entry point or the structure table from the Multiboot2
+/// information, which may be relocated. Bootloaders such as GRUB put the
+/// entry point into the tag, others the structure table.
+fn from_mbi(tag: &SmbiosTag) -> Option<Smbios> {
+    match EntryPoint::parse(&tag.tables) {
+        Ok(entry_point) => {
+            let len = entry_point.length as usize;
+            let mut bytes = [0; ENTRY_POINT_MAX_LEN];
+            bytes.get_mut(..len)?.copy_from_slice(&tag.tables[..len]);
+            Some(Smbios::EntryPointCopy { entry_point, bytes })
+        }
+        Err(SmbiosError::InvalidAnchor) => Some(Smbios::Tables {
+            major: tag.major,
+            minor: tag.minor,
+            tables: tag.tables.to_vec(),
+        }),
+        Err(e) => {
+            log::warn!("Ignoring the SMBIOS entry point from the Multiboot2 information: {e}");
+            None
+        }
+    }
+}
+
+fn from_bios_area() -> Option<Smbios> {
+    let area = paging::phys_slice(BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START)?;
+    let offset = lib::smbios::scan_entry_point(area)?;
+    Some(Smbios::Firmware {
+        addr: BIOS_AREA_START + offset as u64,
+        entry_point: EntryPoint::parse(&area[offset..]).ok()?,
+    })
+}
+
+/// Logs the BIOS, the system, the processors, and the memory devices.
+fn log_summary(table: &[u8]) {
+    let mut memory_devices = 0;
+    let mut memory_size = 0;
+    for structure in lib::smbios::structures(table) {
+        if let Some(bios) = BiosInfo::parse(&structure) {
+            log::info!(
+                "BIOS:   {} {} ({})",
+                Unknown(bios.vendor),
+                Unknown(bios.version),
+                Unknown(bios.release_date)
+            );
+        } else if let Some(system) = SystemInfo::parse(&structure) {
+            log::info!(
+                "System: {} {} ({})",
+                Unknown(system.manufacturer),
+                Unknown(system.product),
+                Unknown(system.version)
+            );
+        } else if let Some(cpu) = ProcessorInfo::parse(&structure).filter(|cpu| cpu.populated) {
+            log::info!(
+                "CPU:    {} in {}: {} cores, {} threads, max. {} MHz",
+                Unknown(cpu.version),
+                Unknown(cpu.socket),
+                Unknown(cpu.core_count),
+                Unknown(cpu.thread_count),
+                Unknown(cpu.max_speed_mhz)
+            );
+        } else if let Some(memory) = MemoryDevice::parse(&structure) {
+            if memory.size == Some(0) {
+                continue;
+            }
+            memory_devices += 1;
+            memory_size += memory.size.unwrap_or(0);
+            log::debug!(
+                "Memory device {}: {} MiB, {} MT/s, {} {}",
+                Unknown(memory.locator),
+                Unknown(memory.size.map(|size| size / (1024 * 1024))),
+                Unknown(memory.speed),
+                Unknown(memory.manufacturer),
+                Unknown(memory.part_number)
+            );
+        }
+    }
+    if memory_devices != 0 {
+        log::info!(
+            "Memory: {} MiB in {memory_devices} devices",
+            memory_size / (1024 * 1024)
+        );
+    }
+}
+
+/// Displays an optional SMBIOS value, which firmware often leaves out.
+struct Unknown<T>(Option<T>);
+
+impl<T: Display> Display for Unknown<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => write!(f, "?"),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Time keeping of the loader: a monotonic clock, deadlines, and sleeping.
+//!
+//! The clock is the LAPIC timer, which runs freely with its interrupt masked.
+//! Its frequency is calibrated against the HPET or, if there is none, against
+//! the PIT. The HPET is taken from the ACPI HPET table; only without ACPI
+//! tables, it is probed at its usual address. As the loader runs with
+//! interrupts disabled, all waiting is done by polling the clock. The clock
+//! must be read at least once per period of the timer (about a minute in QEMU)
+//! to not lose time.
+
+use crate::driver::hpet::{self, Hpet};
+use crate::driver::{lapic, pit};
+use crate::mem::paging::IDENTITY_MAPPING_LIMIT;
+use core::cell::{Cell, OnceCell};
+use core::time::Duration;
+use lib::acpi::HPET_SIGNATURE;
+use lib::safe::Safe;
+use lib::time::{duration_to_ticks, frequency, ticks_to_duration};
+
+/// Duration of the calibration against the reference clock. The PIT limits it
+/// to 55 ms.
+const CALIBRATION_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Upper bound of polls of the HPET during the calibration, in case the
+/// HPET doesn't count.
+const MAX_HPET_POLLS: u64 = 100_000_000;
+
+static CLOCK: Safe<OnceCell<Clock>> = Safe::new(OnceCell::new());
+
+/// Reference clock for the calibration of the LAPIC timer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReferenceClock {
+    Hpet,
+    Pit,
+}
+
+#[derive(Debug)]
+struct Clock {
+    /// Frequency of the LAPIC timer in Hz.
+    frequency: u64,
+    /// Last count of the LAPIC timer.
+    last_count: Cell<u32>,
+    /// Ticks of the LAPIC timer since the initialization.
+    ticks: Cell<u64>,
+}
+
+/// A point in time, after which something is considered to have timed out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(Duration);
+
+impl Deadline {
+    /// Returns the deadline that is `timeout` from now, or `None` if the
+    /// clock is not available.
+    pub fn after(timeout: Duration) -> Option<Self> {
+        Some(Self(now()? + timeout))
+    }
+
+    /// Returns true if the deadline has passed.
+    pub fn has_expired(&self) -> bool {
+        // A deadline only exists if the clock does.
+        !now().is_some_and(|now| now < self.0)
+    }
+}
+
+/// Starts and calibrates the LAPIC timer. If this fails, a warning is logged
+/// and [`is_available`] returns false.
+pub fn init() {
+    let mode = lapic::enable();
+    log::debug!(
+        "LAPIC: mode={mode:?}, x2APIC supported={}",
+        lapic::has_x2apic()
+    );
+    if !lapic::start_timer() {
+        log::warn!("LAPIC timer is not usable, timeouts are not available");
+        return;
+    }
+    let Some((frequency, reference)) = calibrate().filter(|(frequency, _)| *frequency > 0) else {
+        log::warn!("Can't calibrate the LAPIC timer: neither HPET nor PIT respond");
+        return;
+    };
+    log::debug!(
+        "LAPIC timer: {} kHz (calibrated against the {reference:?})",
+        frequency / 1000
+    );
+    let _ = CLOCK.set(Clock {
+        frequency,
+        last_count: Cell::new(lapic::timer_count()),
+        ticks: Cell::new(0),
+    });
+}
+
+/// Returns true if the clock was initialized successfully.
+pub fn is_available() -> bool {
+    CLOCK.get().is_some()
+}
+
+/// Returns the time since [`init`], or `None` if the clock is not available.
+pub fn now() -> Option<Duration> {
+    let clock = CLOCK.get()?;
+    let count = lapic::timer_count();
+    // The timer counts down and restarts after 2^32 ticks.
+    let elapsed = clock.last_count.replace(count).wrapping_sub(count);
+    let ticks = clock.ticks.get() + elapsed as u64;
+    clock.ticks.set(ticks);
+    Some(ticks_to_duration(ticks, clock.frequency))
+}
+
+/// Busy-waits for the given duration. Returns `None` without waiting if the
+/// clock is not available.
+pub fn sleep(duration: Duration) -> Option<()> {
+    let deadline = Deadline::after(duration)?;
+    while !deadline.has_expired() {
+        core::hint::spin_loop();
+    }
+    Some(())
+}
+
+/// Measures the frequency of the LAPIC timer against the HPET or the PIT.
+fn calibrate() -> Option<(u64, ReferenceClock)> {
+    if let Some(hpet) = hpet_base().and_then(Hpet::probe) {
+        let hpet_ticks = duration_to_ticks(CALIBRATION_INTERVAL, hpet.frequency());
+        let start = hpet.counter();
+        let lapic_start = lapic::timer_count();
+        let done = (0..MAX_HPET_POLLS).any(|_| hpet.ticks_since(start) >= hpet_ticks);
+        let lapic_ticks = lapic_start.wrapping_sub(lapic::timer_count());
+        let interval = ticks_to_duration(hpet.ticks_since(start), hpet.frequency());
+        if done {
+            return Some((
+                frequency(lapic_ticks as u64, interval)?,
+                ReferenceClock::Hpet,
+            ));
+        }
+    }
+
+    pit::start_oneshot(CALIBRATION_INTERVAL);
+    let lapic_start = lapic::timer_count();
+    if !pit::wait_oneshot() {
+        return None;
+    }
+    let lapic_ticks = lapic_start.wrapping_sub(lapic::timer_count());
+    Some((
+        frequency(lapic_ticks as u64, CALIBRATION_INTERVAL)?,
+        ReferenceClock::Pit,
+    ))
+}
+
+/// Returns the base address of the HPET from the ACPI HPET table. Without ACPI
+/// tables, the HPET is assumed at its usual address.
+fn hpet_base() -> Option<u64> {
+    if crate::acpi::rsdp().is_none() {
+        return Some(hpet::DEFAULT_BASE);
+    }
+    let base = lib::acpi::hpet_base(&crate::acpi::find_table(HPET_SIGNATURE)?)?;
+    (base < IDENTITY_MAPPING_LIMIT).then_some(base)
+}
@@ -1,5 +1,17 @@
 //! Module for Xen PVH boot.
 
+/// Offset of `rsdp_paddr` in `struct hvm_start_info`. Taken from
+/// <xen/include/public/arch-x86/hvm/start_info.h>.
+const START_INFO_RSDP_PADDR: u64 = 24;
+
+/// Returns the physical address of the RSDP from the `hvm_start_info`
+/// structure at `start_info`, if the VMM provided one.
+pub fn rsdp_paddr(start_info: u64) -> Option<u64> {
+    // The start info lives below 4 GiB and is identity-mapped.
+    let addr = unsafe { ((start_info + START_INFO_RSDP_PADDR) as *const u64).read_unaligned() };
+    (addr != 0).then_some(addr)
+}
+
 mod elf_note {
     use core::mem::size_of;
 
@@ -0,0 +1,511 @@
+//! Parsing of the ACPI tables that PhipsBoot needs. All functions work on the
+//! raw bytes of the structures, so that they are independent of how the
+//! memory is accessed.
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+/// Signature of the RSDP.
+pub const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Length of the RSDP of ACPI 1.0.
+pub const RSDP_V1_LEN: usize = 20;
+/// Length of the RSDP of ACPI 2.0 and later.
+pub const RSDP_V2_LEN: usize = 36;
+/// Length of the header of all system description tables.
+pub const SDT_HEADER_LEN: usize = 36;
+
+/// Signature of the FADT.
+pub const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+/// Signature of the MADT.
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// Offset of the interrupt controller structures in the MADT.
+const MADT_ENTRIES_OFFSET: usize = 44;
+/// MADT entry: Processor Local APIC.
+const MADT_LAPIC: u8 = 0;
+/// MADT entry: Processor Local x2APIC.
+const MADT_X2APIC: u8 = 9;
+/// Flags of the MADT processor entries: the processor is usable.
+const MADT_CPU_ENABLED: u32 = 1 << 0;
+/// Signature of the MCFG, which describes the PCI Express enhanced
+/// configuration space (ECAM).
+pub const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+/// Offset of the configuration space base address allocations in the MCFG.
+const MCFG_ENTRIES_OFFSET: usize = 44;
+/// Length of the configuration space base address allocations.
+const MCFG_ENTRY_LEN: usize = 16;
+/// Signature of the HPET table.
+pub const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+/// Length of the HPET table up to the base address of the first HPET.
+const HPET_MIN_LEN: usize = 52;
+/// Address space ID of a Generic Address Structure: system memory.
+const GAS_SYSTEM_MEMORY: u8 = 0;
+
+/// Errors of malformed ACPI structures.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The structure doesn't start with the expected signature.
+    InvalidSignature,
+    /// The bytes of the structure don't sum up to zero.
+    InvalidChecksum,
+    /// The structure is shorter than its header claims or than the minimum.
+    InvalidLength,
+}
+
+impl Display for AcpiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidSignature => write!(f, "invalid signature"),
+            Self::InvalidChecksum => write!(f, "invalid checksum"),
+            Self::InvalidLength => write!(f, "invalid length"),
+        }
+    }
+}
+
+/// Returns true if all bytes sum up to zero, as required for all ACPI
+/// structures.
+pub fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// The Root System Description Pointer, which locates the RSDT or XSDT.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rsdp {
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    /// Length of the RSDP in bytes: [`RSDP_V1_LEN`] before revision 2.
+    pub length: u32,
+    /// Physical address of the RSDT.
+    pub rsdt_address: u32,
+    /// Physical address of the XSDT. Only present since ACPI 2.0.
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    /// Parses and validates an RSDP. `bytes` must hold at least
+    /// [`RSDP_V1_LEN`] bytes, or [`RSDP_V2_LEN`] bytes since revision 2.
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        if bytes.len() < RSDP_V1_LEN {
+            return Err(AcpiError::InvalidLength);
+        }
+        if &bytes[..8] != RSDP_SIGNATURE {
+            return Err(AcpiError::InvalidSignature);
+        }
+        if !checksum_is_valid(&bytes[..RSDP_V1_LEN]) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+        let oem_id = bytes[9..15].try_into().unwrap();
+        let revision = bytes[15];
+        let rsdt_address = read_u32(bytes, 16);
+        if revision < 2 {
+            return Ok(Self {
+                oem_id,
+                revision,
+                length: RSDP_V1_LEN as u32,
+                rsdt_address,
+                xsdt_address: None,
+            });
+        }
+
+        if bytes.len() < RSDP_V2_LEN {
+            return Err(AcpiError::InvalidLength);
+        }
+        let len = read_u32(bytes, 20) as usize;
+        if !(RSDP_V2_LEN..=bytes.len()).contains(&len) {
+            return Err(AcpiError::InvalidLength);
+        }
+        if !checksum_is_valid(&bytes[..len]) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+        Ok(Self {
+            oem_id,
+            revision,
+            length: len as u32,
+            rsdt_address,
+            xsdt_address: Some(read_u64(bytes, 24)).filter(|addr| *addr != 0),
+        })
+    }
+
+    /// Returns the physical address of the root table, preferably the XSDT,
+    /// and the size of its entries.
+    pub fn root_table(&self) -> (u64, usize) {
+        match self.xsdt_address {
+            Some(xsdt) => (xsdt, 8),
+            None => (self.rsdt_address as u64, 4),
+        }
+    }
+}
+
+/// Returns the offset of the first valid RSDP in `bytes`. The RSDP is
+/// searched on 16-byte boundaries, as in the legacy BIOS areas.
+pub fn scan_rsdp(bytes: &[u8]) -> Option<usize> {
+    (0..bytes.len())
+        .step_by(16)
+        .find(|offset| Rsdp::parse(&bytes[*offset..]).is_ok())
+}
+
+/// The header of a system description table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    /// Length of the table including the header.
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+}
+
+impl SdtHeader {
+    /// Parses the header. `bytes` must hold at least [`SDT_HEADER_LEN`]
+    /// bytes.
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        if bytes.len() < SDT_HEADER_LEN {
+            return Err(AcpiError::InvalidLength);
+        }
+        let header = Self {
+            signature: bytes[..4].try_into().unwrap(),
+            length: read_u32(bytes, 4),
+            revision: bytes[8],
+            oem_id: bytes[10..16].try_into().unwrap(),
+            oem_table_id: bytes[16..24].try_into().unwrap(),
+        };
+        if (header.length as usize) < SDT_HEADER_LEN {
+            return Err(AcpiError::InvalidLength);
+        }
+        Ok(header)
+    }
+}
+
+/// A validated system description table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sdt<'a> {
+    pub header: SdtHeader,
+    /// All bytes of the table, including the header.
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Sdt<'a> {
+    /// Parses and validates a table. `bytes` may be longer than the table.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, AcpiError> {
+        let header = SdtHeader::parse(bytes)?;
+        let bytes = bytes
+            .get(..header.length as usize)
+            .ok_or(AcpiError::InvalidLength)?;
+        if !checksum_is_valid(bytes) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+        Ok(Self { header, bytes })
+    }
+
+    /// Returns the physical addresses of the tables that a root table (RSDT
+    /// or XSDT) references. `entry_size` is 4 for the RSDT and 8 for the
+    /// XSDT.
+    pub fn root_table_entries(&self, entry_size: usize) -> impl Iterator<Item = u64> + 'a {
+        self.bytes[SDT_HEADER_LEN..]
+            .chunks_exact(entry_size)
+            .map(move |entry| match entry_size {
+                4 => read_u32(entry, 0) as u64,
+                _ => read_u64(entry, 0),
+            })
+    }
+}
+
+/// Returns the physical address of the DSDT, which the FADT references
+/// instead of the root table.
+pub fn fadt_dsdt(fadt: &Sdt) -> Option<u64> {
+    let bytes = fadt.bytes;
+    let x_dsdt = (bytes.len() >= 148).then(|| read_u64(bytes, 140));
+    let dsdt = (bytes.len() >= 44).then(|| read_u32(bytes, 40) as u64);
+    x_dsdt
+        .filter(|addr| *addr != 0)
+        .or(dsdt)
+        .filter(|addr| *addr != 0)
+}
+
+/// A processor of the MADT.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MadtCpu {
+    /// The ACPI processor UID.
+    pub processor_uid: u32,
+    /// The (x2)APIC ID.
+    pub apic_id: u32,
+}
+
+/// Returns all enabled processors of the MADT in table order. Processors that
+/// are listed as local APIC and local x2APIC are only reported once.
+pub fn madt_cpus(madt: &Sdt) -> Vec<MadtCpu> {
+    let mut cpus = Vec::<MadtCpu>::new();
+    let mut entries = madt.bytes.get(MADT_ENTRIES_OFFSET..).unwrap_or(&[]);
+    while let [kind, len, ..] = *entries {
+        let len = len as usize;
+        let Some(entry) = entries.get(..len).filter(|_| len >= 2) else {
+            break;
+        };
+        let cpu = match kind {
+            MADT_LAPIC if len >= 8 => Some((
+                MadtCpu {
+                    processor_uid: entry[2] as u32,
+                    apic_id: entry[3] as u32,
+                },
+                read_u32(entry, 4),
+            )),
+            MADT_X2APIC if len >= 16 => Some((
+                MadtCpu {
+                    processor_uid: read_u32(entry, 12),
+                    apic_id: read_u32(entry, 4),
+                },
+                read_u32(entry, 8),
+            )),
+            _ => None,
+        };
+        if let Some((cpu, flags)) = cpu {
+            if flags & MADT_CPU_ENABLED != 0 && !cpus.iter().any(|c| c.apic_id == cpu.apic_id) {
+                cpus.push(cpu);
+            }
+        }
+        entries = &entries[len..];
+    }
+    cpus
+}
+
+/// The ECAM of a PCI segment, from the MCFG.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of bus 0, even if
+    /// `start_bus` is higher.
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Returns the ECAM regions of the MCFG.
+pub fn mcfg_entries(mcfg: &Sdt) -> Vec<McfgEntry> {
+    mcfg.bytes
+        .get(MCFG_ENTRIES_OFFSET..)
+        .unwrap_or(&[])
+        .chunks_exact(MCFG_ENTRY_LEN)
+        .map(|entry| McfgEntry {
+            base_address: read_u64(entry, 0),
+            segment: u16::from_le_bytes([entry[8], entry[9]]),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+        .filter(|entry| entry.start_bus <= entry.end_bus)
+        .collect()
+}
+
+/// Returns the physical address of the registers of the first HPET from the
+/// HPET table.
+pub fn hpet_base(hpet: &Sdt) -> Option<u64> {
+    let bytes = hpet.bytes.get(..HPET_MIN_LEN)?;
+    (bytes[40] == GAS_SYSTEM_MEMORY).then(|| read_u64(bytes, 44))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Sets the checksum byte at `offset` so that `bytes` sum up to zero.
+    fn fix_checksum(bytes: &mut [u8], offset: usize) {
+        bytes[offset] = 0;
+        let sum = bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes[offset] = sum.wrapping_neg();
+    }
+
+    fn table(signature: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; SDT_HEADER_LEN];
+        bytes[..4].copy_from_slice(signature);
+        bytes[4..8].copy_from_slice(&((SDT_HEADER_LEN + data.len()) as u32).to_le_bytes());
+        bytes[10..16].copy_from_slice(b"PHIPS ");
+        bytes.extend_from_slice(data);
+        fix_checksum(&mut bytes, 9);
+        bytes
+    }
+
+    #[test]
+    fn rsdp() {
+        let mut v1 = vec![0; RSDP_V1_LEN];
+        v1[..8].copy_from_slice(RSDP_SIGNATURE);
+        v1[16..20].copy_from_slice(&0x7fe0000_u32.to_le_bytes());
+        fix_checksum(&mut v1, 8);
+        let rsdp = Rsdp::parse(&v1).unwrap();
+        assert_eq!(rsdp.revision, 0);
+        assert_eq!(rsdp.length, RSDP_V1_LEN as u32);
+        assert_eq!(rsdp.root_table(), (0x7fe0000, 4));
+
+        let mut v2 = v1.clone();
+        v2.resize(RSDP_V2_LEN, 0);
+        v2[15] = 2;
+        v2[20..24].copy_from_slice(&(RSDP_V2_LEN as u32).to_le_bytes());
+        v2[24..32].copy_from_slice(&0x1_0000_0000_u64.to_le_bytes());
+        fix_checksum(&mut v2[..RSDP_V1_LEN], 8);
+        fix_checksum(&mut v2, 32);
+        let rsdp = Rsdp::parse(&v2).unwrap();
+        assert_eq!(rsdp.length, RSDP_V2_LEN as u32);
+        assert_eq!(&rsdp.oem_id, &[0; 6]);
+        assert_eq!(rsdp.xsdt_address, Some(0x1_0000_0000));
+        assert_eq!(rsdp.root_table(), (0x1_0000_0000, 8));
+        assert_eq!(
+            Rsdp::parse(&v2[..RSDP_V1_LEN]),
+            Err(AcpiError::InvalidLength)
+        );
+
+        v2[24] = 1;
+        assert_eq!(Rsdp::parse(&v2), Err(AcpiError::InvalidChecksum));
+        v1[0] = b'X';
+        assert_eq!(Rsdp::parse(&v1), Err(AcpiError::InvalidSignature));
+    }
+
+    #[test]
+    fn rsdp_scan() {
+        let mut area = vec![0; 0x100];
+        // Not on a 16-byte boundary
+        area[0x28..0x30].copy_from_slice(RSDP_SIGNATURE);
+        // Invalid checksum
+        area[0x40..0x48].copy_from_slice(RSDP_SIGNATURE);
+        area[0x50] = 1;
+        assert_eq!(scan_rsdp(&area), None);
+
+        area[0x80..0x88].copy_from_slice(RSDP_SIGNATURE);
+        area[0x8f] = 0;
+        fix_checksum(&mut area[0x80..0x80 + RSDP_V1_LEN], 8);
+        assert_eq!(scan_rsdp(&area), Some(0x80));
+        assert_eq!(scan_rsdp(&area[..0x90]), None);
+    }
+
+    #[test]
+    fn fadt() {
+        let mut data = vec![0; 148 - SDT_HEADER_LEN];
+        data[40 - SDT_HEADER_LEN..44 - SDT_HEADER_LEN].copy_from_slice(&0x1000_u32.to_le_bytes());
+        let fadt = table(FADT_SIGNATURE, &data);
+        assert_eq!(fadt_dsdt(&Sdt::parse(&fadt).unwrap()), Some(0x1000));
+
+        data[140 - SDT_HEADER_LEN..].copy_from_slice(&0x2_0000_0000_u64.to_le_bytes());
+        let fadt = table(FADT_SIGNATURE, &data);
+        assert_eq!(fadt_dsdt(&Sdt::parse(&fadt).unwrap()), Some(0x2_0000_0000));
+
+        // ACPI 1.0 FADT without X_DSDT
+        let fadt = table(FADT_SIGNATURE, &data[..116 - SDT_HEADER_LEN]);
+        assert_eq!(fadt_dsdt(&Sdt::parse(&fadt).unwrap()), Some(0x1000));
+    }
+
+    #[test]
+    fn sdt() {
+        let mut xsdt = table(
+            b"XSDT",
+            &[0x00, 0x10, 0, 0, 0, 0, 0, 0, 0x00, 0x20, 0, 0, 1, 0, 0, 0],
+        );
+        let sdt = Sdt::parse(&xsdt).unwrap();
+        assert_eq!(&sdt.header.signature, b"XSDT");
+        assert_eq!(&sdt.header.oem_id, b"PHIPS ");
+        assert_eq!(
+            sdt.root_table_entries(8).collect::<Vec<_>>(),
+            [0x1000, 0x1_0000_2000]
+        );
+        assert_eq!(
+            sdt.root_table_entries(4).collect::<Vec<_>>(),
+            [0x1000, 0, 0x2000, 1]
+        );
+
+        assert_eq!(Sdt::parse(&xsdt[..40]), Err(AcpiError::InvalidLength));
+        xsdt[40] = 1;
+        assert_eq!(Sdt::parse(&xsdt), Err(AcpiError::InvalidChecksum));
+    }
+
+    #[test]
+    fn madt() {
+        let mut data = vec![0; MADT_ENTRIES_OFFSET - SDT_HEADER_LEN];
+        // LAPIC: UID 0, ID 0, enabled
+        data.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+        // I/O APIC
+        data.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+        // LAPIC: UID 1, ID 2, disabled
+        data.extend_from_slice(&[0, 8, 1, 2, 0, 0, 0, 0]);
+        // LAPIC: UID 2, ID 4, enabled
+        data.extend_from_slice(&[0, 8, 2, 4, 1, 0, 0, 0]);
+        // x2APIC: ID 4 (duplicate), enabled, UID 2
+        data.extend_from_slice(&[9, 16, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+        // x2APIC: ID 0x100, enabled, UID 3
+        data.extend_from_slice(&[9, 16, 0, 0, 0, 1, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0]);
+        // Truncated entry
+        data.extend_from_slice(&[0, 8, 4]);
+        let madt = table(MADT_SIGNATURE, &data);
+
+        let cpus = madt_cpus(&Sdt::parse(&madt).unwrap());
+        assert_eq!(
+            cpus,
+            [
+                MadtCpu {
+                    processor_uid: 0,
+                    apic_id: 0
+                },
+                MadtCpu {
+                    processor_uid: 2,
+                    apic_id: 4
+                },
+                MadtCpu {
+                    processor_uid: 3,
+                    apic_id: 0x100
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn hpet() {
+        let mut data = vec![0; HPET_MIN_LEN + 4 - SDT_HEADER_LEN];
+        data[44 - SDT_HEADER_LEN..52 - SDT_HEADER_LEN]
+            .copy_from_slice(&0xfed0_0000_u64.to_le_bytes());
+        let hpet = table(HPET_SIGNATURE, &data);
+        assert_eq!(hpet_base(&Sdt::parse(&hpet).unwrap()), Some(0xfed0_0000));
+
+        // System I/O instead of memory
+        data[40 - SDT_HEADER_LEN] = 1;
+        let hpet = table(HPET_SIGNATURE, &data);
+        assert_eq!(hpet_base(&Sdt::parse(&hpet).unwrap()), None);
+
+        let hpet = table(HPET_SIGNATURE, &data[..8]);
+        assert_eq!(hpet_base(&Sdt::parse(&hpet).unwrap()), None);
+    }
+
+    #[test]
+    fn mcfg() {
+        let mut data = vec![0; MCFG_ENTRIES_OFFSET - SDT_HEADER_LEN];
+        // Segment 0, buses 0 to 0xff
+        data.extend_from_slice(&[0, 0, 0, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0, 0, 0, 0]);
+        // Segment 1, buses 0x10 to 0x1f
+        data.extend_from_slice(&[0, 0, 0, 0, 0x10, 0, 0, 0, 1, 0, 0x10, 0x1f, 0, 0, 0, 0]);
+        // Invalid bus range
+        data.extend_from_slice(&[0, 0, 0, 0xc0, 0, 0, 0, 0, 2, 0, 0x10, 0x0f, 0, 0, 0, 0]);
+        // Truncated entry
+        data.extend_from_slice(&[0, 0, 0, 0xd0]);
+        let mcfg = table(MCFG_SIGNATURE, &data);
+
+        assert_eq!(
+            mcfg_entries(&Sdt::parse(&mcfg).unwrap()),
+            [
+                McfgEntry {
+                    base_address: 0xb000_0000,
+                    segment: 0,
+                    start_bus: 0,
+                    end_bus: 0xff,
+                },
+                McfgEntry {
+                    base_address: 0x10_0000_0000,
+                    segment: 1,
+                    start_bus: 0x10,
+                    end_bus: 0x1f,
+                },
+            ]
+        );
+    }
+}
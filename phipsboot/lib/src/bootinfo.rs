@@ -0,0 +1,437 @@
+//! The boot information that PhipsBoot hands over to the kernel in `%rdi`.
+//!
+//! All types have a stable C ABI. Kernels written in Rust can use this module
+//! directly, others have to replicate the types. The boot information and all
+//! data it references live in one contiguous memory region that is mapped
+//! into the kernel's address space. Unless stated otherwise, pointers are
+//! virtual addresses that are valid in this address space.
+
+use crate::mem::map::MemoryRegion;
+use core::mem::{align_of, size_of, size_of_val};
+
+/// Magic value of [`BootInformation::magic`]: `"PhipsBI\0"`.
+pub const MAGIC: u64 = u64::from_le_bytes(*b"PhipsBI\0");
+
+/// Current version of the boot information. Newer versions only append
+/// fields to [`BootInformation`].
+pub const VERSION: u32 = 1;
+
+/// The boot information.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootInformation {
+    /// Always [`MAGIC`].
+    pub magic: u64,
+    /// Version of the structure. Always [`VERSION`].
+    pub version: u32,
+    /// Size of this structure in bytes.
+    pub size: u32,
+    /// Pointer to the UTF-8 command line of the kernel, which is the command
+    /// line string of the kernel's boot module.
+    pub cmdline_ptr: u64,
+    /// Length of the command line in bytes.
+    pub cmdline_len: u64,
+    /// Pointer to an array of [`BootModule`]s. These are all boot modules
+    /// except for the kernel itself.
+    pub modules_ptr: u64,
+    /// Number of entries behind [`BootInformation::modules_ptr`].
+    pub modules_count: u64,
+    /// Offset of the direct map of physical memory, or `0` if there is none.
+    /// Physical address `p` is mapped at `direct_map_offset + p`.
+    pub direct_map_offset: u64,
+    /// Pointer to an array of [`MemoryMapEntry`]s, sorted by address.
+    pub memory_map_ptr: u64,
+    /// Number of entries behind [`BootInformation::memory_map_ptr`].
+    pub memory_map_count: u64,
+    /// Lowest address of the kernel stack. The page below is unmapped as
+    /// guard page.
+    pub stack_base: u64,
+    /// Size of the kernel stack in bytes.
+    pub stack_size: u64,
+    /// Bits of the optional CPU features that PhipsBoot enabled, see
+    /// [`CpuFeature`](crate::cpu::CpuFeature).
+    pub cpu_features: u64,
+    /// Number of paging levels of the kernel's address space: 4 or 5.
+    pub paging_levels: u64,
+    /// Pointer to the [`Framebuffer`], or `0` if there is none.
+    pub framebuffer_ptr: u64,
+    /// Pointer to an array of [`Cpu`]s, or `0` if PhipsBoot didn't start the
+    /// application processors (`--smp`).
+    pub cpus_ptr: u64,
+    /// Number of entries behind [`BootInformation::cpus_ptr`].
+    pub cpus_count: u64,
+    /// Physical address of the validated ACPI RSDP, or `0` if PhipsBoot
+    /// found none. If the bootloader only provided a copy of the RSDP, this
+    /// is a 16-byte aligned copy in the memory of the boot information.
+    pub rsdp_addr: u64,
+    /// Physical address of the SMBIOS entry point, or `0` if there is none.
+    /// If the bootloader only provided a copy of the entry point, this is a
+    /// copy in the memory of the boot information. If it only provided a copy
+    /// of the structure table, this is a 64-bit entry point that PhipsBoot
+    /// created in the memory of the boot information, which also holds the
+    /// copy.
+    pub smbios_addr: u64,
+    /// Pointer to an array of [`PciFunction`]s, or `0` without `--pci`.
+    pub pci_functions_ptr: u64,
+    /// Number of entries behind [`BootInformation::pci_functions_ptr`].
+    pub pci_functions_count: u64,
+}
+
+impl BootInformation {
+    /// Creates a new boot information with all pointers and counts set to
+    /// zero.
+    pub fn new() -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            size: size_of::<Self>() as u32,
+            cmdline_ptr: 0,
+            cmdline_len: 0,
+            modules_ptr: 0,
+            modules_count: 0,
+            direct_map_offset: 0,
+            memory_map_ptr: 0,
+            memory_map_count: 0,
+            stack_base: 0,
+            stack_size: 0,
+            cpu_features: 0,
+            paging_levels: 4,
+            framebuffer_ptr: 0,
+            cpus_ptr: 0,
+            cpus_count: 0,
+            rsdp_addr: 0,
+            smbios_addr: 0,
+            pci_functions_ptr: 0,
+            pci_functions_count: 0,
+        }
+    }
+
+    /// Returns the command line.
+    ///
+    /// # Safety
+    /// Must only be called in the address space that PhipsBoot created.
+    pub unsafe fn cmdline(&self) -> &str {
+        str_from_raw(self.cmdline_ptr, self.cmdline_len)
+    }
+
+    /// Returns the boot modules.
+    ///
+    /// # Safety
+    /// Must only be called in the address space that PhipsBoot created.
+    pub unsafe fn modules(&self) -> &[BootModule] {
+        slice_from_raw(self.modules_ptr, self.modules_count)
+    }
+
+    /// Returns the framebuffer, if there is one.
+    ///
+    /// # Safety
+    /// Must only be called in the address space that PhipsBoot created.
+    pub unsafe fn framebuffer(&self) -> Option<&Framebuffer> {
+        (self.framebuffer_ptr as *const Framebuffer).as_ref()
+    }
+
+    /// Returns the memory map.
+    ///
+    /// # Safety
+    /// Must only be called in the address space that PhipsBoot created.
+    pub unsafe fn memory_map(&self) -> &[MemoryMapEntry] {
+        slice_from_raw(self.memory_map_ptr, self.memory_map_count)
+    }
+
+    /// Returns the processors.
+    ///
+    /// # Safety
+    /// Must only be called in the address space that PhipsBoot created.
+    pub unsafe fn cpus(&self) -> &[Cpu] {
+        slice_from_raw(self.cpus_ptr, self.cpus_count)
+    }
+
+    /// Returns the PCI functions.
+    ///
+    /// # Safety
+    /// Must only be called in the address space that PhipsBoot created.
+    pub unsafe fn pci_functions(&self) -> &[PciFunction] {
+        slice_from_raw(self.pci_functions_ptr, self.pci_functions_count)
+    }
+}
+
+impl Default for BootInformation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A boot module, such as an initial ramdisk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootModule {
+    /// Physical address of the module.
+    pub phys_addr: u64,
+    /// Size of the module in bytes.
+    pub size: u64,
+    /// Virtual address of the module in the module window, or `0` if the
+    /// module is not mapped.
+    pub virt_addr: u64,
+    /// Pointer to the UTF-8 command line of the module.
+    pub cmdline_ptr: u64,
+    /// Length of the command line in bytes.
+    pub cmdline_len: u64,
+}
+
+impl BootModule {
+    /// Returns the command line.
+    ///
+    /// # Safety
+    /// Must only be called in the address space that PhipsBoot created.
+    pub unsafe fn cmdline(&self) -> &str {
+        str_from_raw(self.cmdline_ptr, self.cmdline_len)
+    }
+}
+
+/// An entry of the memory map that PhipsBoot hands over to the kernel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryMapEntry {
+    /// Physical start address.
+    pub base: u64,
+    /// Length in bytes.
+    pub len: u64,
+    /// The value of a [`MemoryRegionKind`](crate::mem::map::MemoryRegionKind).
+    pub kind: u32,
+    pub reserved: u32,
+}
+
+impl From<&MemoryRegion> for MemoryMapEntry {
+    fn from(region: &MemoryRegion) -> Self {
+        Self {
+            base: region.range.start(),
+            len: region.range.len(),
+            kind: region.kind as u32,
+            reserved: 0,
+        }
+    }
+}
+
+/// [`Framebuffer::kind`]: Colors from a palette.
+pub const FRAMEBUFFER_INDEXED: u8 = 0;
+/// [`Framebuffer::kind`]: Direct RGB colors.
+pub const FRAMEBUFFER_RGB: u8 = 1;
+/// [`Framebuffer::kind`]: EGA text mode. Width and height are in characters.
+pub const FRAMEBUFFER_TEXT: u8 = 2;
+
+/// The framebuffer that the bootloader set up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Framebuffer {
+    /// Physical address. The framebuffer is identity-mapped if the kernel
+    /// requested it, see [`crate::note::TYPE_FRAMEBUFFER`].
+    pub phys_addr: u64,
+    /// Bytes per line.
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel.
+    pub bpp: u8,
+    /// One of the `FRAMEBUFFER_*` kinds.
+    pub kind: u8,
+    /// Bit position and size of the color channels. Only valid for
+    /// [`FRAMEBUFFER_RGB`].
+    pub red_position: u8,
+    pub red_size: u8,
+    pub green_position: u8,
+    pub green_size: u8,
+    pub blue_position: u8,
+    pub blue_size: u8,
+    pub reserved: u32,
+}
+
+impl Framebuffer {
+    /// Returns the size of the framebuffer in bytes.
+    pub fn size(&self) -> u64 {
+        self.pitch as u64 * self.height as u64
+    }
+}
+
+/// [`Cpu::flags`]: The processor is the bootstrap processor (BSP), which runs
+/// the kernel entry.
+pub const CPU_BSP: u32 = 1 << 0;
+/// [`Cpu::flags`]: The application processor is parked and waits for its
+/// [`Cpu::goto_address`]. Processors without this flag didn't respond.
+pub const CPU_ONLINE: u32 = 1 << 1;
+
+/// A processor from the ACPI MADT.
+///
+/// Each started application processor (AP) polls [`Cpu::goto_address`] of
+/// its entry. Once the kernel writes a non-zero address with an atomic store,
+/// the AP jumps there with a pointer to its entry in `%rdi`, see the README.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Cpu {
+    /// The ACPI processor UID.
+    pub processor_uid: u32,
+    /// The (x2)APIC ID.
+    pub apic_id: u32,
+    /// `CPU_*` flags.
+    pub flags: u32,
+    pub reserved: u32,
+    /// Address at which the AP continues. Written by the kernel.
+    pub goto_address: u64,
+    /// Free for use by the kernel, for example to pass data to the AP.
+    pub extra_argument: u64,
+    /// Lowest address of the stack of the processor. The page below is
+    /// unmapped as guard page.
+    pub stack_base: u64,
+    /// Size of the stack in bytes.
+    pub stack_size: u64,
+}
+
+/// A function of a PCI device, as PhipsBoot found it when it enumerated the
+/// configuration space.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct PciFunction {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub revision: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// The header type without the multi-function bit: `0` for devices, `1`
+    /// for PCI-to-PCI bridges.
+    pub header_type: u8,
+    pub reserved: u16,
+}
+
+unsafe fn slice_from_raw<'a, T>(ptr: u64, len: u64) -> &'a [T] {
+    if len == 0 {
+        &[]
+    } else {
+        core::slice::from_raw_parts(ptr as *const T, len as usize)
+    }
+}
+
+unsafe fn str_from_raw<'a>(ptr: u64, len: u64) -> &'a str {
+    core::str::from_utf8_unchecked(slice_from_raw(ptr, len))
+}
+
+/// Lays out the boot information and all data it references in one
+/// contiguous memory region. Each write returns the virtual address of the
+/// written data in the kernel's address space.
+#[derive(Debug)]
+pub struct BootInfoWriter<'a> {
+    buf: &'a mut [u8],
+    /// Virtual address of `buf` in the kernel's address space.
+    vaddr: u64,
+    pos: usize,
+}
+
+impl<'a> BootInfoWriter<'a> {
+    /// Creates a new writer. `vaddr` is the virtual address of `buf` in the
+    /// address space of the kernel.
+    pub fn new(buf: &'a mut [u8], vaddr: u64) -> Self {
+        Self { buf, vaddr, pos: 0 }
+    }
+
+    /// Writes a string.
+    pub fn write_str(&mut self, str: &str) -> Option<u64> {
+        self.write_slice(str.as_bytes())
+    }
+
+    /// Writes a single value.
+    pub fn write<T: Copy>(&mut self, val: &T) -> Option<u64> {
+        self.write_slice(core::slice::from_ref(val))
+    }
+
+    /// Writes all elements of a slice. An empty slice returns `Some(0)`.
+    pub fn write_slice<T: Copy>(&mut self, vals: &[T]) -> Option<u64> {
+        if vals.is_empty() {
+            return Some(0);
+        }
+        let start = self.pos.next_multiple_of(align_of::<T>());
+        let len = size_of_val(vals);
+        let dest = self.buf.get_mut(start..start + len)?;
+        // SAFETY: The destination has the right size and all types are plain
+        // old data.
+        unsafe {
+            core::ptr::copy_nonoverlapping(vals.as_ptr().cast::<u8>(), dest.as_mut_ptr(), len);
+        }
+        self.pos = start + len;
+        Some(self.vaddr + start as u64)
+    }
+
+    /// Returns the number of bytes written so far, including padding.
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn abi() {
+        assert_eq!(size_of::<BootInformation>(), 160);
+        assert_eq!(size_of::<BootModule>(), 40);
+        assert_eq!(size_of::<MemoryMapEntry>(), 24);
+        assert_eq!(size_of::<Framebuffer>(), 32);
+        assert_eq!(size_of::<Cpu>(), 48);
+        assert_eq!(size_of::<PciFunction>(), 16);
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        // u64 buffer to have a properly aligned backing memory.
+        let mut buf = vec![0_u64; 64];
+        let buf_u8 =
+            unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), 64 * 8) };
+        let vaddr = buf_u8.as_ptr() as u64;
+        let mut writer = BootInfoWriter::new(buf_u8, vaddr);
+
+        let cmdline = "initrd";
+        let cmdline_ptr = writer.write_str(cmdline).unwrap();
+        assert_eq!(cmdline_ptr, vaddr);
+        let module = BootModule {
+            phys_addr: 0x1000,
+            size: 0x2000,
+            virt_addr: 0,
+            cmdline_ptr,
+            cmdline_len: cmdline.len() as u64,
+        };
+        let modules_ptr = writer.write_slice(&[module]).unwrap();
+        assert_eq!(modules_ptr, vaddr + 8, "must be aligned");
+        let info_ptr = writer
+            .write(&BootInformation {
+                modules_ptr,
+                modules_count: 1,
+                ..BootInformation::new()
+            })
+            .unwrap();
+
+        let info = unsafe { &*(info_ptr as *const BootInformation) };
+        assert_eq!(info.magic, MAGIC);
+        unsafe {
+            assert_eq!(info.cmdline(), "");
+            assert_eq!(info.modules(), [module]);
+            assert_eq!(info.modules()[0].cmdline(), "initrd");
+        }
+    }
+
+    #[test]
+    fn write_out_of_space() {
+        let mut buf = [0_u8; 4];
+        let mut writer = BootInfoWriter::new(&mut buf, 0x1000);
+        assert_eq!(writer.write_str("abc"), Some(0x1000));
+        assert_eq!(writer.write_str("de"), None);
+        assert_eq!(writer.len(), 3);
+    }
+}
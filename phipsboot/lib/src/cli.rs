@@ -1,15 +1,64 @@
 //! CLI parsing of the loader. The CLI looks like this:
 //!
-//! `[--load=module-id-if-kernel] [--loggers=serial,debugcon]`
+//! `[--load=name|"name"|index|*] [--loggers=serial,debugcon]
+//! [--relocate-modules] [--module-window=0xffff900000000000]
+//! [--direct-map=0xffff800000000000] [--direct-map-mmio] [--identity-map=4G]
+//! [--unmap-phipsboot] [--relocate-phipsboot] [--user-segments] [--stack-size=128K]
+//! [--on-error=halt|reboot|qemu-exit]
+//! [--cpu-features=xsave,avx,smep,smap,umip,pcid,fsgsbase] [--smp] [--pci]`
 
+use crate::cpu::CpuFeatures;
 use ::regex::Regex;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
 use core::str::FromStr;
 
 mod regex {
-    pub const LOAD: &str = "--load=(?P<load>[A-z0-9-_.]+)+";
+    pub const LOAD: &str = "--load=(?P<load>\"[^\"]*\"|[A-Za-z0-9_.*-]+)";
     pub const LOGGERS: &str = "--loggers=(?P<loggers>[a-z]+(,[a-z]+)*)?";
+    pub const RELOCATE_MODULES: &str = "(^|[ ])--relocate-modules($|[ ])";
+    pub const MODULE_WINDOW: &str = "--module-window=(?P<addr>(0x)?[0-9a-fA-F]+)";
+    pub const DIRECT_MAP: &str = "--direct-map=(?P<addr>(0x)?[0-9a-fA-F]+)";
+    pub const DIRECT_MAP_MMIO: &str = "(^|[ ])--direct-map-mmio($|[ ])";
+    pub const UNMAP_PHIPSBOOT: &str = "(^|[ ])--unmap-phipsboot($|[ ])";
+    pub const RELOCATE_PHIPSBOOT: &str = "(^|[ ])--relocate-phipsboot($|[ ])";
+    pub const USER_SEGMENTS: &str = "(^|[ ])--user-segments($|[ ])";
+    pub const IDENTITY_MAP: &str = "--identity-map=(?P<size>(0x)?[0-9a-fA-F]+[KMG]?)";
+    pub const STACK_SIZE: &str = "--stack-size=(?P<size>(0x)?[0-9a-fA-F]+[KMG]?)";
+    pub const ON_ERROR: &str = "--on-error=(?P<policy>[a-z-]+)";
+    pub const CPU_FEATURES: &str = "--cpu-features=(?P<features>[a-z]+(,[a-z]+)*)";
+    pub const SMP: &str = "(^|[ ])--smp($|[ ])";
+    pub const PCI: &str = "(^|[ ])--pci($|[ ])";
+}
+
+/// The largest stack size for `--stack-size`. The stack lives in the identity
+/// mapping below 4 GiB, so larger stacks can't be allocated anyway.
+const MAX_STACK_SIZE: u64 = 1 << 30;
+
+/// The largest size for `--identity-map`: the lower half of the address space
+/// with 5-level paging. The loader limits it further to the lower half of the
+/// address space of the kernel.
+const MAX_IDENTITY_MAP_SIZE: u64 = 1 << 56;
+
+/// Parses a number that is either decimal or hexadecimal with a `0x` prefix.
+fn parse_u64(str: &str) -> Option<u64> {
+    match str.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => str.parse().ok(),
+    }
+}
+
+/// Parses a size such as `4G`: a number as accepted by [`parse_u64`] with an
+/// optional `K`, `M`, or `G` suffix.
+fn parse_size(str: &str) -> Option<u64> {
+    let (num, shift) = match str.as_bytes().last()? {
+        b'K' => (&str[..str.len() - 1], 10),
+        b'M' => (&str[..str.len() - 1], 20),
+        b'G' => (&str[..str.len() - 1], 30),
+        _ => (str, 0),
+    };
+    parse_u64(num)?.checked_mul(1 << shift)
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -30,10 +79,205 @@ impl FromStr for SupportedLogger {
     }
 }
 
+/// Selects the boot module that holds the kernel (`--load=`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum KernelSelector {
+    /// The first boot module that is a valid ELF64 x86_64 executable. This is
+    /// the default and `--load=*`.
+    #[default]
+    FirstElf,
+    /// The boot module with the given 0-based index, such as `--load=1`.
+    Index(usize),
+    /// The boot module whose command line string or name (first word of the
+    /// command line string) equals the value. Names with spaces or only
+    /// digits are quoted, such as `--load="kernel --foo"` or `--load="1"`.
+    Name(String),
+}
+
+impl KernelSelector {
+    /// Returns true if the boot module with the given index, command line
+    /// string, and content matches.
+    pub fn matches(&self, index: usize, cmdline: &str, bytes: &[u8]) -> bool {
+        match self {
+            Self::FirstElf => crate::elf::Elf::parse(bytes).is_ok(),
+            Self::Index(i) => *i == index,
+            Self::Name(name) => cmdline == name || cmdline.split(' ').next() == Some(name),
+        }
+    }
+}
+
+impl FromStr for KernelSelector {
+    type Err = ();
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = str.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Ok(Self::Name(name.to_string()))
+        } else if str == "*" {
+            Ok(Self::FirstElf)
+        } else if let Ok(index) = str.parse() {
+            Ok(Self::Index(index))
+        } else {
+            Ok(Self::Name(str.to_string()))
+        }
+    }
+}
+
+impl Display for KernelSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FirstElf => write!(f, "*"),
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Name(name) if name.parse::<usize>().is_err() && is_plain_name(name) => {
+                write!(f, "{name}")
+            }
+            Self::Name(name) => write!(f, "\"{name}\""),
+        }
+    }
+}
+
+/// Returns true if the name can be given to `--load=` without quotes.
+fn is_plain_name(name: &str) -> bool {
+    name != "*"
+        && !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_.*-".contains(&b))
+}
+
+/// What PhipsBoot does after a fatal error, such as a panic or an exception
+/// (`--on-error=`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Halt the CPU with interrupts disabled. This is the default.
+    #[default]
+    Halt,
+    /// Reset the machine.
+    Reboot,
+    /// Exit QEMU via the `isa-debug-exit` device. Halts if the device is not
+    /// present.
+    QemuExit,
+}
+
+impl FromStr for OnError {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "halt" => Ok(Self::Halt),
+            "reboot" => Ok(Self::Reboot),
+            "qemu-exit" => Ok(Self::QemuExit),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for OnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Halt => write!(f, "halt"),
+            Self::Reboot => write!(f, "reboot"),
+            Self::QemuExit => write!(f, "qemu-exit"),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct CliArgs {
     loggers: Vec<SupportedLogger>,
-    load: String,
+    load: KernelSelector,
+    relocate_modules: bool,
+    module_window: Option<u64>,
+    direct_map: Option<u64>,
+    direct_map_mmio: bool,
+    identity_map: Option<u64>,
+    unmap_phipsboot: bool,
+    relocate_phipsboot: bool,
+    user_segments: bool,
+    stack_size: Option<u64>,
+    on_error: OnError,
+    cpu_features: CpuFeatures,
+    smp: bool,
+    pci: bool,
+}
+
+impl CliArgs {
+    /// Returns the selector of the boot module that holds the kernel.
+    pub fn load(&self) -> &KernelSelector {
+        &self.load
+    }
+
+    /// Returns whether boot modules that collide with the kernel's LOAD
+    /// segments are moved to free memory.
+    pub fn relocate_modules(&self) -> bool {
+        self.relocate_modules
+    }
+
+    /// Returns the virtual base address at which all boot modules are mapped
+    /// into the kernel's address space, if any.
+    pub fn module_window(&self) -> Option<u64> {
+        self.module_window
+    }
+
+    /// Returns the offset at which all physical RAM is mapped into the
+    /// kernel's address space, if any.
+    pub fn direct_map(&self) -> Option<u64> {
+        self.direct_map
+    }
+
+    /// Returns whether the direct map also covers the reserved ranges of the
+    /// memory map, such as MMIO.
+    pub fn direct_map_mmio(&self) -> bool {
+        self.direct_map_mmio
+    }
+
+    /// Returns the size of the low physical memory that is identity-mapped
+    /// into the kernel's address space, if any.
+    pub fn identity_map(&self) -> Option<u64> {
+        self.identity_map
+    }
+
+    /// Returns whether PhipsBoot is left out of the kernel's address space.
+    pub fn unmap_phipsboot(&self) -> bool {
+        self.unmap_phipsboot
+    }
+
+    /// Returns whether PhipsBoot moves itself to the top of physical memory
+    /// before it loads the kernel.
+    pub fn relocate_phipsboot(&self) -> bool {
+        self.relocate_phipsboot
+    }
+
+    /// Returns whether the GDT of the kernel contains user code and data
+    /// segments.
+    pub fn user_segments(&self) -> bool {
+        self.user_segments
+    }
+
+    /// Returns the size of the kernel stack, if it differs from the default.
+    pub fn stack_size(&self) -> Option<u64> {
+        self.stack_size
+    }
+
+    /// Returns what PhipsBoot does after a fatal error.
+    pub fn on_error(&self) -> OnError {
+        self.on_error
+    }
+
+    /// Returns the optional CPU features that the kernel requests.
+    pub fn cpu_features(&self) -> CpuFeatures {
+        self.cpu_features
+    }
+
+    /// Returns whether PhipsBoot starts the application processors and parks
+    /// them for the kernel.
+    pub fn smp(&self) -> bool {
+        self.smp
+    }
+
+    /// Returns whether the kernel gets the list of PCI functions.
+    pub fn pci(&self) -> bool {
+        self.pci
+    }
 }
 
 impl FromStr for CliArgs {
@@ -44,13 +288,23 @@ impl FromStr for CliArgs {
 
         let regex_load = Regex::new(regex::LOAD).unwrap();
         let regex_loggers = Regex::new(regex::LOGGERS).unwrap();
+        let regex_relocate_modules = Regex::new(regex::RELOCATE_MODULES).unwrap();
+        let regex_module_window = Regex::new(regex::MODULE_WINDOW).unwrap();
+        let regex_direct_map = Regex::new(regex::DIRECT_MAP).unwrap();
+        let regex_direct_map_mmio = Regex::new(regex::DIRECT_MAP_MMIO).unwrap();
+        let regex_identity_map = Regex::new(regex::IDENTITY_MAP).unwrap();
+        let regex_unmap_phipsboot = Regex::new(regex::UNMAP_PHIPSBOOT).unwrap();
+        let regex_relocate_phipsboot = Regex::new(regex::RELOCATE_PHIPSBOOT).unwrap();
+        let regex_user_segments = Regex::new(regex::USER_SEGMENTS).unwrap();
+        let regex_stack_size = Regex::new(regex::STACK_SIZE).unwrap();
+        let regex_on_error = Regex::new(regex::ON_ERROR).unwrap();
+        let regex_cpu_features = Regex::new(regex::CPU_FEATURES).unwrap();
+        let regex_smp = Regex::new(regex::SMP).unwrap();
+        let regex_pci = Regex::new(regex::PCI).unwrap();
 
         if let Some(mtch) = regex_load.captures(cmdline) {
-            args.load = mtch
-                .name("load")
-                .map(|m| m.as_str())
-                .unwrap_or("")
-                .to_string();
+            let load = mtch.name("load").map(|m| m.as_str()).unwrap_or("");
+            args.load = KernelSelector::from_str(load)?;
         }
 
         if let Some(mtch) = regex_loggers.captures(cmdline) {
@@ -63,36 +317,225 @@ impl FromStr for CliArgs {
             }
         }
 
+        args.relocate_modules = regex_relocate_modules.is_match(cmdline);
+
+        if let Some(mtch) = regex_module_window.captures(cmdline) {
+            let addr = mtch.name("addr").map(|m| m.as_str()).unwrap_or("");
+            args.module_window = Some(parse_u64(addr).ok_or(())?);
+        }
+
+        if let Some(mtch) = regex_direct_map.captures(cmdline) {
+            let addr = mtch.name("addr").map(|m| m.as_str()).unwrap_or("");
+            args.direct_map = Some(parse_u64(addr).ok_or(())?);
+        }
+        args.direct_map_mmio = regex_direct_map_mmio.is_match(cmdline);
+
+        if let Some(mtch) = regex_identity_map.captures(cmdline) {
+            let size = mtch.name("size").map(|m| m.as_str()).unwrap_or("");
+            let size = parse_size(size).filter(|size| (1..=MAX_IDENTITY_MAP_SIZE).contains(size));
+            args.identity_map = Some(size.ok_or(())?);
+        }
+
+        args.unmap_phipsboot = regex_unmap_phipsboot.is_match(cmdline);
+        args.relocate_phipsboot = regex_relocate_phipsboot.is_match(cmdline);
+        args.user_segments = regex_user_segments.is_match(cmdline);
+
+        if let Some(mtch) = regex_stack_size.captures(cmdline) {
+            let size = mtch.name("size").map(|m| m.as_str()).unwrap_or("");
+            let size = parse_size(size).filter(|size| (1..=MAX_STACK_SIZE).contains(size));
+            args.stack_size = Some(size.ok_or(())?);
+        }
+
+        if let Some(mtch) = regex_on_error.captures(cmdline) {
+            let policy = mtch.name("policy").map(|m| m.as_str()).unwrap_or("");
+            args.on_error = OnError::from_str(policy)?;
+        }
+
+        if let Some(mtch) = regex_cpu_features.captures(cmdline) {
+            let features = mtch.name("features").map(|m| m.as_str()).unwrap_or("");
+            args.cpu_features = CpuFeatures::from_str(features)?;
+        }
+
+        args.smp = regex_smp.is_match(cmdline);
+        args.pci = regex_pci.is_match(cmdline);
+
         Ok(args)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cli::{CliArgs, SupportedLogger};
+    use crate::cli::{CliArgs, KernelSelector, OnError, SupportedLogger};
+    use crate::cpu::{CpuFeature, CpuFeatures};
+    use alloc::string::ToString;
     use core::str::FromStr;
 
     #[test]
     fn test_cli_empty() {
         let cmdline = "";
         let args = CliArgs::from_str(cmdline).unwrap();
-        assert_eq!(args.load.as_str(), "");
+        assert_eq!(args.load, KernelSelector::FirstElf);
         assert!(args.loggers.is_empty());
+        assert!(!args.relocate_modules());
+        assert_eq!(args.module_window(), None);
+        assert_eq!(args.direct_map(), None);
+        assert!(!args.direct_map_mmio());
+        assert_eq!(args.identity_map(), None);
+        assert!(!args.unmap_phipsboot());
+        assert!(!args.relocate_phipsboot());
+        assert!(!args.user_segments());
+        assert_eq!(args.stack_size(), None);
+        assert_eq!(args.on_error(), OnError::Halt);
+        assert_eq!(args.cpu_features(), CpuFeatures::NONE);
+        assert!(!args.smp());
+        assert!(!args.pci());
     }
 
     #[test]
     fn test_cli_normal() {
         let cmdline = "--load=foobar --loggers=serial";
         let args = CliArgs::from_str(cmdline).unwrap();
-        assert_eq!(args.load.as_str(), "foobar");
+        assert_eq!(args.load, KernelSelector::Name("foobar".to_string()));
         assert_eq!(args.loggers, [SupportedLogger::Serial]);
 
         let cmdline = "--load=foobar --loggers=serial,debugcon";
         let args = CliArgs::from_str(cmdline).unwrap();
-        assert_eq!(args.load.as_str(), "foobar");
+        assert_eq!(args.load, KernelSelector::Name("foobar".to_string()));
         assert_eq!(
             args.loggers,
             [SupportedLogger::Serial, SupportedLogger::Debugcon]
         );
     }
+
+    #[test]
+    fn test_cli_modules() {
+        let cmdline = "--load=kernel --relocate-modules --module-window=0xffff900000000000";
+        let args = CliArgs::from_str(cmdline).unwrap();
+        assert_eq!(args.load(), &KernelSelector::Name("kernel".to_string()));
+        assert!(args.relocate_modules());
+        assert_eq!(args.module_window(), Some(0xffff900000000000));
+
+        let args = CliArgs::from_str("--load=kernel --unmap-phipsboot --user-segments").unwrap();
+        assert!(args.unmap_phipsboot());
+        assert!(args.user_segments());
+        assert!(!args.relocate_phipsboot());
+
+        let args = CliArgs::from_str("--relocate-phipsboot --load=kernel").unwrap();
+        assert!(args.relocate_phipsboot());
+        assert!(!args.unmap_phipsboot());
+
+        let cmdline = "--relocate-modules-not";
+        let args = CliArgs::from_str(cmdline).unwrap();
+        assert!(!args.relocate_modules());
+
+        let cmdline = "--module-window=0xfffffffffffffffff";
+        assert!(CliArgs::from_str(cmdline).is_err());
+    }
+
+    #[test]
+    fn test_cli_direct_map() {
+        let args = CliArgs::from_str("--direct-map=0xffff800000000000").unwrap();
+        assert_eq!(args.direct_map(), Some(0xffff800000000000));
+        assert!(!args.direct_map_mmio());
+
+        let args = CliArgs::from_str("--direct-map=0xffff800000000000 --direct-map-mmio").unwrap();
+        assert_eq!(args.direct_map(), Some(0xffff800000000000));
+        assert!(args.direct_map_mmio());
+    }
+
+    #[test]
+    fn test_cli_identity_map() {
+        let args = CliArgs::from_str("--identity-map=4G").unwrap();
+        assert_eq!(args.identity_map(), Some(0x100000000));
+        let args = CliArgs::from_str("--identity-map=512M").unwrap();
+        assert_eq!(args.identity_map(), Some(0x20000000));
+        let args = CliArgs::from_str("--identity-map=0x200000").unwrap();
+        assert_eq!(args.identity_map(), Some(0x200000));
+        let args = CliArgs::from_str("--identity-map=0x4000000G").unwrap();
+        assert_eq!(args.identity_map(), Some(0x100000000000000));
+        assert!(CliArgs::from_str("--identity-map=99999999999G").is_err());
+        assert!(CliArgs::from_str("--identity-map=0").is_err());
+        assert!(CliArgs::from_str("--identity-map=0x0").is_err());
+        assert!(CliArgs::from_str("--identity-map=0x4000001G").is_err());
+        assert!(CliArgs::from_str("--identity-map=0xffffffffffffffff").is_err());
+    }
+
+    #[test]
+    fn test_cli_stack_size() {
+        let args = CliArgs::from_str("--stack-size=1M").unwrap();
+        assert_eq!(args.stack_size(), Some(0x100000));
+        let args = CliArgs::from_str("--stack-size=0x8000").unwrap();
+        assert_eq!(args.stack_size(), Some(0x8000));
+        assert!(CliArgs::from_str("--stack-size=0").is_err());
+        let args = CliArgs::from_str("--stack-size=1G").unwrap();
+        assert_eq!(args.stack_size(), Some(0x40000000));
+        assert!(CliArgs::from_str("--stack-size=2G").is_err());
+        assert!(CliArgs::from_str("--stack-size=0xffffffffffffffff").is_err());
+    }
+
+    #[test]
+    fn test_cli_on_error() {
+        let args = CliArgs::from_str("--on-error=reboot").unwrap();
+        assert_eq!(args.on_error(), OnError::Reboot);
+        let args = CliArgs::from_str("--load=kernel --on-error=qemu-exit").unwrap();
+        assert_eq!(args.on_error(), OnError::QemuExit);
+        assert_eq!(args.on_error().to_string(), "qemu-exit");
+        assert!(CliArgs::from_str("--on-error=explode").is_err());
+    }
+
+    #[test]
+    fn test_cli_cpu_features() {
+        let args = CliArgs::from_str("--cpu-features=xsave,avx --load=kernel").unwrap();
+        assert!(args.cpu_features().contains(CpuFeature::Xsave));
+        assert!(args.cpu_features().contains(CpuFeature::Avx));
+        assert!(!args.cpu_features().contains(CpuFeature::Pcid));
+        let args = CliArgs::from_str("--cpu-features=smep,smap,umip,pcid,fsgsbase").unwrap();
+        assert_eq!(
+            args.cpu_features().to_string(),
+            "smep,smap,umip,pcid,fsgsbase"
+        );
+        assert!(CliArgs::from_str("--cpu-features=sse").is_err());
+
+        let args = CliArgs::from_str("--load=kernel --smp").unwrap();
+        assert!(args.smp());
+        assert!(!CliArgs::from_str("--smp-off").unwrap().smp());
+
+        let args = CliArgs::from_str("--pci --smp").unwrap();
+        assert!(args.pci());
+        assert!(!CliArgs::from_str("--pcie").unwrap().pci());
+    }
+
+    #[test]
+    fn test_cli_kernel_selector() {
+        let args = CliArgs::from_str("--load=2").unwrap();
+        assert_eq!(args.load(), &KernelSelector::Index(2));
+        let args = CliArgs::from_str("--load=*").unwrap();
+        assert_eq!(args.load(), &KernelSelector::FirstElf);
+        let args = CliArgs::from_str("--load=my-kernel_1.elf --relocate-modules").unwrap();
+        assert_eq!(args.load(), &KernelSelector::Name("my-kernel_1.elf".into()));
+
+        // Quoted names may contain spaces or consist of digits only.
+        let args = CliArgs::from_str("--load=\"kernel --foo\" --relocate-modules").unwrap();
+        let selector = KernelSelector::Name("kernel --foo".to_string());
+        assert_eq!(args.load(), &selector);
+        assert!(args.relocate_modules());
+        assert!(selector.matches(0, "kernel --foo", &[]));
+        assert!(!selector.matches(0, "kernel", &[]));
+        assert_eq!(selector.to_string(), "\"kernel --foo\"");
+        let args = CliArgs::from_str("--load=\"2\"").unwrap();
+        assert_eq!(args.load(), &KernelSelector::Name("2".to_string()));
+        assert_eq!(args.load().to_string(), "\"2\"");
+        assert_eq!(KernelSelector::Index(2).to_string(), "2");
+        assert_eq!(KernelSelector::Name("kernel".into()).to_string(), "kernel");
+
+        let elf = crate::elf::tests::build_elf(0x1000, &[]);
+        let selector = KernelSelector::Name("kernel".to_string());
+        assert!(selector.matches(0, "kernel", &[]));
+        assert!(selector.matches(0, "kernel --foo", &[]));
+        assert!(!selector.matches(0, "kernel2", &[]));
+        assert!(KernelSelector::Index(1).matches(1, "", &[]));
+        assert!(!KernelSelector::Index(1).matches(0, "", &[]));
+        assert!(KernelSelector::FirstElf.matches(0, "", &elf));
+        assert!(!KernelSelector::FirstElf.matches(0, "", &[0; 64]));
+    }
 }
@@ -0,0 +1,196 @@
+//! Optional CPU features that PhipsBoot enables for the kernel
+//! (`--cpu-features=`).
+
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+
+/// An optional CPU feature. The discriminant is the bit in [`CpuFeatures`]
+/// and in [`BootInformation::cpu_features`](crate::bootinfo::BootInformation::cpu_features).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuFeature {
+    /// `CR4.OSXSAVE`: `xsave`, `xrstor`, and `xgetbv`. `XCR0` enables the
+    /// x87 and SSE state.
+    Xsave = 0,
+    /// The AVX state in `XCR0`. Implies [`CpuFeature::Xsave`].
+    Avx = 1,
+    /// `CR4.SMEP`: supervisor mode execution prevention.
+    Smep = 2,
+    /// `CR4.SMAP`: supervisor mode access prevention.
+    Smap = 3,
+    /// `CR4.UMIP`: user-mode instruction prevention.
+    Umip = 4,
+    /// `CR4.PCIDE`: process-context identifiers.
+    Pcid = 5,
+    /// `CR4.FSGSBASE`: `rdfsbase`, `wrfsbase`, `rdgsbase`, and `wrgsbase`.
+    Fsgsbase = 6,
+}
+
+impl CpuFeature {
+    /// All features in the order of their bits.
+    pub const ALL: [Self; 7] = [
+        Self::Xsave,
+        Self::Avx,
+        Self::Smep,
+        Self::Smap,
+        Self::Umip,
+        Self::Pcid,
+        Self::Fsgsbase,
+    ];
+
+    /// Returns the bit of the feature in [`CpuFeatures`].
+    pub const fn bit(self) -> u64 {
+        1 << self as u8
+    }
+
+    /// Returns the name of the feature on the command line.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Xsave => "xsave",
+            Self::Avx => "avx",
+            Self::Smep => "smep",
+            Self::Smap => "smap",
+            Self::Umip => "umip",
+            Self::Pcid => "pcid",
+            Self::Fsgsbase => "fsgsbase",
+        }
+    }
+}
+
+impl FromStr for CpuFeature {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.name() == name)
+            .ok_or(())
+    }
+}
+
+impl Display for CpuFeature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A set of [`CpuFeature`]s.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures(u64);
+
+impl CpuFeatures {
+    /// The empty set.
+    pub const NONE: Self = Self(0);
+
+    /// Creates a set from its bits. Unknown bits are dropped.
+    pub const fn from_bits(bits: u64) -> Self {
+        let mut all = 0;
+        let mut i = 0;
+        while i < CpuFeature::ALL.len() {
+            all |= CpuFeature::ALL[i].bit();
+            i += 1;
+        }
+        Self(bits & all)
+    }
+
+    /// Returns the bits of the set.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, feature: CpuFeature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+
+    pub fn insert(&mut self, feature: CpuFeature) {
+        self.0 |= feature.bit();
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the features that are in `self` or in `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns the features of `self` that are not in `other`.
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Iterates over the features of the set in the order of their bits.
+    pub fn iter(self) -> impl Iterator<Item = CpuFeature> {
+        CpuFeature::ALL
+            .into_iter()
+            .filter(move |feature| self.contains(*feature))
+    }
+}
+
+impl FromIterator<CpuFeature> for CpuFeatures {
+    fn from_iter<T: IntoIterator<Item = CpuFeature>>(iter: T) -> Self {
+        let mut features = Self::NONE;
+        for feature in iter {
+            features.insert(feature);
+        }
+        features
+    }
+}
+
+/// Parses a comma-separated list, such as `xsave,avx`.
+impl FromStr for CpuFeatures {
+    type Err = ();
+
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        list.split(',').map(CpuFeature::from_str).collect()
+    }
+}
+
+/// Formats the set as comma-separated list.
+impl Display for CpuFeatures {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for (i, feature) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{feature}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn parse_and_format() {
+        let features = CpuFeatures::from_str("avx,smep,xsave").unwrap();
+        assert!(features.contains(CpuFeature::Xsave));
+        assert!(features.contains(CpuFeature::Avx));
+        assert!(features.contains(CpuFeature::Smep));
+        assert!(!features.contains(CpuFeature::Smap));
+        assert_eq!(features.bits(), 0b111);
+        assert_eq!(features.to_string(), "xsave,avx,smep");
+        assert_eq!(CpuFeatures::NONE.to_string(), "");
+
+        assert!(CpuFeatures::from_str("xsave,avx512").is_err());
+        assert!(CpuFeatures::from_str("").is_err());
+    }
+
+    #[test]
+    fn set_operations() {
+        let all = CpuFeature::ALL.into_iter().collect::<CpuFeatures>();
+        assert_eq!(CpuFeatures::from_bits(u64::MAX), all);
+        let some = CpuFeatures::from_str("pcid,umip").unwrap();
+        assert_eq!(
+            all.difference(some).to_string(),
+            "xsave,avx,smep,smap,fsgsbase"
+        );
+        assert!(some.difference(all).is_empty());
+        assert_eq!(some.union(CpuFeatures::NONE), some);
+        assert_eq!(some.union(all), all);
+    }
+}
@@ -0,0 +1,725 @@
+//! Minimal parser for ELF64 executables, as far as the loader needs it to load
+//! a kernel.
+//!
+//! All fields are read byte-wise, so the ELF file can be located at any
+//! address in memory.
+
+use crate::mem::paging::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+/// Size of the ELF64 file header.
+const HEADER_SIZE: usize = 64;
+/// Size of an ELF64 program header.
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// Size of an ELF64 section header.
+const SECTION_HEADER_SIZE: usize = 64;
+/// Size of an ELF64 symbol.
+const SYMBOL_SIZE: usize = 24;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_X86_64: u16 = 62;
+
+/// ELF file type `ET_EXEC`.
+pub const TYPE_EXEC: u16 = 2;
+/// ELF file type `ET_DYN`.
+pub const TYPE_DYN: u16 = 3;
+
+/// Program header type `PT_LOAD`.
+pub const PT_LOAD: u32 = 1;
+/// Program header type `PT_NOTE`.
+pub const PT_NOTE: u32 = 4;
+
+/// Section header type `SHT_SYMTAB`.
+pub const SHT_SYMTAB: u32 = 2;
+
+/// Symbol type `STT_FUNC`.
+pub const STT_FUNC: u8 = 2;
+
+/// Segment flag: executable.
+pub const PF_X: u32 = 1 << 0;
+/// Segment flag: writeable.
+pub const PF_W: u32 = 1 << 1;
+/// Segment flag: readable.
+pub const PF_R: u32 = 1 << 2;
+
+/// Errors when parsing an ELF file.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum ElfError {
+    /// The file is smaller than the structures it describes.
+    TooSmall,
+    /// The file doesn't start with the ELF magic.
+    BadMagic,
+    /// The file is not an ELF64 file.
+    Not64Bit,
+    /// The file is not little endian.
+    NotLittleEndian,
+    /// The file is not for x86_64.
+    NotX86_64(u16),
+    /// The file is neither `ET_EXEC` nor `ET_DYN`.
+    NotExecutable(u16),
+    /// The program headers have an unexpected size.
+    InvalidProgramHeaderSize(u16),
+    /// A segment's file content is out of bounds of the file.
+    SegmentOutOfBounds(usize),
+    /// A segment's file content is larger than its memory image.
+    SegmentFileSizeTooLarge(usize),
+    /// A segment's memory image wraps around the address space.
+    SegmentAddressOverflow(usize),
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TooSmall => write!(f, "file too small"),
+            Self::BadMagic => write!(f, "not an ELF file"),
+            Self::Not64Bit => write!(f, "not an ELF64 file"),
+            Self::NotLittleEndian => write!(f, "not little endian"),
+            Self::NotX86_64(machine) => write!(f, "not for x86_64 (machine={machine})"),
+            Self::NotExecutable(typ) => write!(f, "not an executable (type={typ})"),
+            Self::InvalidProgramHeaderSize(size) => {
+                write!(f, "invalid program header size ({size})")
+            }
+            Self::SegmentOutOfBounds(index) => {
+                write!(f, "segment {index} is out of bounds of the file")
+            }
+            Self::SegmentFileSizeTooLarge(index) => {
+                write!(f, "segment {index} is larger in the file than in memory")
+            }
+            Self::SegmentAddressOverflow(index) => {
+                write!(f, "segment {index} wraps around the address space")
+            }
+        }
+    }
+}
+
+/// An ELF64 program header.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct ProgramHeader {
+    pub typ: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.typ == PT_LOAD
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+}
+
+/// A page that LOAD segments occupy.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SegmentPage {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// Returns the pages that the segments occupy, sorted by address. If segments
+/// share a page, the page is writable if any of them is writable and
+/// executable if any of them is executable.
+pub fn segment_pages(segments: &[ProgramHeader]) -> Vec<SegmentPage> {
+    let mut pages = BTreeMap::<(u64, u64), SegmentPage>::new();
+    for segment in segments {
+        let vaddr = segment.vaddr & !(PAGE_SIZE - 1);
+        let paddr = segment.paddr & !(PAGE_SIZE - 1);
+        let len = segment.vaddr + segment.memsz - vaddr;
+        for offset in (0..len).step_by(PAGE_SIZE as usize) {
+            let page = pages
+                .entry((vaddr + offset, paddr + offset))
+                .or_insert(SegmentPage {
+                    vaddr: vaddr + offset,
+                    paddr: paddr + offset,
+                    writable: false,
+                    executable: false,
+                });
+            page.writable |= segment.is_writable();
+            page.executable |= segment.is_executable();
+        }
+    }
+    pages.into_values().collect()
+}
+
+/// An ELF64 section header.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct SectionHeader {
+    /// Offset of the name in the section header string table.
+    pub name: u32,
+    pub typ: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub entsize: u64,
+}
+
+/// A note of a `PT_NOTE` segment.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct Note<'a> {
+    /// Name of the owner, without the terminating NUL.
+    pub name: &'a str,
+    pub typ: u32,
+    pub desc: &'a [u8],
+}
+
+/// Iterator over the notes of a `PT_NOTE` segment. Stops at the first
+/// malformed note.
+#[derive(Debug)]
+struct Notes<'a> {
+    data: &'a [u8],
+    /// Alignment of the name and the description: 4 or 8.
+    align: usize,
+}
+
+impl<'a> Iterator for Notes<'a> {
+    type Item = Note<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.data.get(..12)?;
+        let name_size = read_u32(header, 0) as usize;
+        let desc_size = read_u32(header, 4) as usize;
+        let typ = read_u32(header, 8);
+        let desc_start = 12_usize
+            .checked_add(name_size)?
+            .next_multiple_of(self.align);
+        let desc_end = desc_start.checked_add(desc_size)?;
+        let (Some(name), Some(desc)) = (
+            self.data.get(12..12 + name_size),
+            self.data.get(desc_start..desc_end),
+        ) else {
+            self.data = &[];
+            return None;
+        };
+        let name = core::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name)).ok()?;
+        let next = desc_end.next_multiple_of(self.align).min(self.data.len());
+        self.data = &self.data[next..];
+        Some(Note { name, typ, desc })
+    }
+}
+
+/// A function symbol of the symbol table.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub value: u64,
+    pub size: u64,
+}
+
+/// A parsed and validated ELF64 x86_64 executable.
+#[derive(Debug)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    typ: u16,
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+    shoff: usize,
+    shnum: usize,
+    shstrndx: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Parses and validates the file. All program headers are checked to be
+    /// within the file.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ElfError::TooSmall);
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != CLASS_64 {
+            return Err(ElfError::Not64Bit);
+        }
+        if bytes[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::NotLittleEndian);
+        }
+        let typ = read_u16(bytes, 16);
+        if typ != TYPE_EXEC && typ != TYPE_DYN {
+            return Err(ElfError::NotExecutable(typ));
+        }
+        let machine = read_u16(bytes, 18);
+        if machine != MACHINE_X86_64 {
+            return Err(ElfError::NotX86_64(machine));
+        }
+        let phentsize = read_u16(bytes, 54);
+        let phnum = read_u16(bytes, 56) as usize;
+        if phnum > 0 && phentsize as usize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::InvalidProgramHeaderSize(phentsize));
+        }
+
+        let elf = Self {
+            bytes,
+            typ,
+            entry: read_u64(bytes, 24),
+            phoff: read_u64(bytes, 32) as usize,
+            phnum,
+            shoff: read_u64(bytes, 40) as usize,
+            // Section headers are optional for executables.
+            shnum: if read_u16(bytes, 58) as usize == SECTION_HEADER_SIZE {
+                read_u16(bytes, 60) as usize
+            } else {
+                0
+            },
+            shstrndx: read_u16(bytes, 62) as usize,
+        };
+
+        let phdrs_end = elf
+            .phoff
+            .checked_add(phnum * PROGRAM_HEADER_SIZE)
+            .ok_or(ElfError::TooSmall)?;
+        if phdrs_end > bytes.len() {
+            return Err(ElfError::TooSmall);
+        }
+        for (i, phdr) in elf.program_headers().enumerate() {
+            let end = phdr.offset.checked_add(phdr.filesz);
+            if !phdr.is_load() {
+                continue;
+            }
+            if !end.is_some_and(|end| end <= bytes.len() as u64) {
+                return Err(ElfError::SegmentOutOfBounds(i));
+            }
+            if phdr.filesz > phdr.memsz {
+                return Err(ElfError::SegmentFileSizeTooLarge(i));
+            }
+            if phdr.vaddr.checked_add(phdr.memsz).is_none()
+                || phdr.paddr.checked_add(phdr.memsz).is_none()
+            {
+                return Err(ElfError::SegmentAddressOverflow(i));
+            }
+        }
+
+        Ok(elf)
+    }
+
+    /// Returns the ELF file type, such as [`TYPE_EXEC`].
+    pub fn typ(&self) -> u16 {
+        self.typ
+    }
+
+    /// Returns the virtual address of the entry point.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns an iterator over all program headers.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let bytes = self.bytes;
+        let phoff = self.phoff;
+        (0..self.phnum).map(move |i| {
+            let base = phoff + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                typ: read_u32(bytes, base),
+                flags: read_u32(bytes, base + 4),
+                offset: read_u64(bytes, base + 8),
+                vaddr: read_u64(bytes, base + 16),
+                paddr: read_u64(bytes, base + 24),
+                filesz: read_u64(bytes, base + 32),
+                memsz: read_u64(bytes, base + 40),
+                align: read_u64(bytes, base + 48),
+            }
+        })
+    }
+
+    /// Returns an iterator over all `PT_LOAD` program headers.
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers().filter(ProgramHeader::is_load)
+    }
+
+    /// Returns the file content of a segment.
+    pub fn segment_data(&self, phdr: &ProgramHeader) -> &'a [u8] {
+        let start = phdr.offset as usize;
+        &self.bytes[start..start + phdr.filesz as usize]
+    }
+
+    /// Returns an iterator over the notes of all `PT_NOTE` segments that are
+    /// within the file.
+    pub fn notes(&self) -> impl Iterator<Item = Note<'a>> + 'a {
+        let bytes = self.bytes;
+        self.program_headers()
+            .filter(|phdr| phdr.typ == PT_NOTE)
+            .filter_map(move |phdr| {
+                let start = phdr.offset as usize;
+                let data = bytes.get(start..start.checked_add(phdr.filesz as usize)?)?;
+                let align = if phdr.align == 8 { 8 } else { 4 };
+                Some(Notes { data, align })
+            })
+            .flatten()
+    }
+
+    /// Returns an iterator over all section headers that are within the file.
+    pub fn section_headers(&self) -> impl Iterator<Item = SectionHeader> + 'a {
+        let bytes = self.bytes;
+        let shoff = self.shoff;
+        (0..self.shnum)
+            .map(move |i| shoff.checked_add(i * SECTION_HEADER_SIZE))
+            .map_while(move |base| base.filter(|b| b + SECTION_HEADER_SIZE <= bytes.len()))
+            .map(move |base| SectionHeader {
+                name: read_u32(bytes, base),
+                typ: read_u32(bytes, base + 4),
+                flags: read_u64(bytes, base + 8),
+                addr: read_u64(bytes, base + 16),
+                offset: read_u64(bytes, base + 24),
+                size: read_u64(bytes, base + 32),
+                link: read_u32(bytes, base + 40),
+                entsize: read_u64(bytes, base + 56),
+            })
+    }
+
+    /// Returns the file content of a section, if it is within the file.
+    pub fn section_data(&self, shdr: &SectionHeader) -> Option<&'a [u8]> {
+        let start = shdr.offset as usize;
+        let end = start.checked_add(shdr.size as usize)?;
+        self.bytes.get(start..end)
+    }
+
+    /// Returns the name of a section.
+    pub fn section_name(&self, shdr: &SectionHeader) -> Option<&'a str> {
+        let strtab = self.section_headers().nth(self.shstrndx)?;
+        read_str(self.section_data(&strtab)?, shdr.name as usize)
+    }
+
+    /// Returns the section header with the given name.
+    pub fn section_by_name(&self, name: &str) -> Option<SectionHeader> {
+        self.section_headers()
+            .find(|shdr| self.section_name(shdr) == Some(name))
+    }
+
+    /// Returns an iterator over all function symbols of the symbol table
+    /// (`.symtab`). The iterator is empty if the file has no symbol table.
+    pub fn function_symbols(&self) -> impl Iterator<Item = Symbol<'a>> + 'a {
+        let symtab = self.section_headers().find(|shdr| shdr.typ == SHT_SYMTAB);
+        let strtab = symtab.and_then(|symtab| self.section_headers().nth(symtab.link as usize));
+        let symbols = symtab.and_then(|shdr| self.section_data(&shdr)).unwrap_or(&[]);
+        let names = strtab.and_then(|shdr| self.section_data(&shdr)).unwrap_or(&[]);
+        symbols
+            .chunks_exact(SYMBOL_SIZE)
+            .filter(|symbol| symbol[4] & 0xf == STT_FUNC)
+            .filter_map(move |symbol| {
+                Some(Symbol {
+                    name: read_str(names, read_u32(symbol, 0) as usize)?,
+                    value: read_u64(symbol, 8),
+                    size: read_u64(symbol, 16),
+                })
+            })
+    }
+}
+
+/// Reads a NUL-terminated string from a string table.
+fn read_str(strtab: &[u8], offset: usize) -> Option<&str> {
+    let bytes = strtab.get(offset..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// Builds a minimal ELF64 x86_64 executable with the given LOAD segments
+    /// `(flags, vaddr, paddr, data, memsz)`.
+    pub(crate) fn build_elf(entry: u64, segments: &[(u32, u64, u64, &[u8], u64)]) -> Vec<u8> {
+        let phoff = HEADER_SIZE;
+        let data_start = phoff + segments.len() * PROGRAM_HEADER_SIZE;
+
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&MAGIC);
+        elf.extend_from_slice(&[CLASS_64, DATA_LITTLE_ENDIAN, 1]);
+        elf.resize(16, 0);
+        elf.extend_from_slice(&TYPE_EXEC.to_le_bytes());
+        elf.extend_from_slice(&MACHINE_X86_64.to_le_bytes());
+        elf.extend_from_slice(&1_u32.to_le_bytes());
+        elf.extend_from_slice(&entry.to_le_bytes());
+        elf.extend_from_slice(&(phoff as u64).to_le_bytes());
+        elf.extend_from_slice(&0_u64.to_le_bytes()); // shoff
+        elf.extend_from_slice(&0_u32.to_le_bytes()); // flags
+        elf.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        elf.extend_from_slice(&[0; 6]); // shentsize, shnum, shstrndx
+        assert_eq!(elf.len(), HEADER_SIZE);
+
+        let mut offset = data_start as u64;
+        for (flags, vaddr, paddr, data, memsz) in segments {
+            elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+            elf.extend_from_slice(&flags.to_le_bytes());
+            elf.extend_from_slice(&offset.to_le_bytes());
+            elf.extend_from_slice(&vaddr.to_le_bytes());
+            elf.extend_from_slice(&paddr.to_le_bytes());
+            elf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            elf.extend_from_slice(&memsz.to_le_bytes());
+            elf.extend_from_slice(&0x1000_u64.to_le_bytes());
+            offset += data.len() as u64;
+        }
+        for (_, _, _, data, _) in segments {
+            elf.extend_from_slice(data);
+        }
+        elf
+    }
+
+    /// Appends a symbol table with the given symbols `(name, value, size,
+    /// type)` and the section headers to an ELF file from [`build_elf`].
+    pub(crate) fn append_symbols(elf: &mut Vec<u8>, symbols: &[(&str, u64, u64, u8)]) {
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYMBOL_SIZE];
+        for (name, value, size, typ) in symbols {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&[*typ, 0, 0, 0]);
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&size.to_le_bytes());
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let shstrtab = b"\0.symtab\0.strtab\0.shstrtab\0";
+
+        // (name, type, link, data)
+        let sections: [(u32, u32, u32, &[u8]); 3] = [
+            (1, SHT_SYMTAB, 2, &symtab),
+            (9, 3, 0, &strtab),
+            (17, 3, 0, shstrtab),
+        ];
+        let mut offsets = Vec::new();
+        for (_, _, _, data) in &sections {
+            offsets.push(elf.len() as u64);
+            elf.extend_from_slice(data);
+        }
+        let shoff = elf.len() as u64;
+        elf.extend_from_slice(&[0; SECTION_HEADER_SIZE]);
+        for ((name, typ, link, data), offset) in sections.iter().zip(offsets) {
+            elf.extend_from_slice(&name.to_le_bytes());
+            elf.extend_from_slice(&typ.to_le_bytes());
+            elf.extend_from_slice(&[0; 16]); // flags, addr
+            elf.extend_from_slice(&offset.to_le_bytes());
+            elf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            elf.extend_from_slice(&link.to_le_bytes());
+            elf.extend_from_slice(&[0; 20]); // info, addralign, entsize
+        }
+        elf[40..48].copy_from_slice(&shoff.to_le_bytes());
+        elf[58..60].copy_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+        elf[60..62].copy_from_slice(&4_u16.to_le_bytes());
+        elf[62..64].copy_from_slice(&3_u16.to_le_bytes());
+    }
+
+    #[test]
+    fn parse_valid() {
+        let bytes = build_elf(
+            0xffffffff80001000,
+            &[
+                (PF_R | PF_X, 0xffffffff80000000, 0x200000, &[0x90; 16], 16),
+                (PF_R | PF_W, 0xffffffff80001000, 0x201000, &[1, 2, 3], 0x2000),
+            ],
+        );
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.typ(), TYPE_EXEC);
+        assert_eq!(elf.entry(), 0xffffffff80001000);
+
+        let segments = elf.load_segments().collect::<Vec<_>>();
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].is_executable());
+        assert!(!segments[0].is_writable());
+        assert_eq!(segments[1].paddr, 0x201000);
+        assert_eq!(segments[1].memsz, 0x2000);
+        assert_eq!(elf.segment_data(&segments[1]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn sections_and_symbols() {
+        let mut bytes = build_elf(0, &[]);
+        assert_eq!(Elf::parse(&bytes).unwrap().function_symbols().count(), 0);
+
+        append_symbols(
+            &mut bytes,
+            &[
+                ("main", 0x1000, 0x20, STT_FUNC),
+                ("DATA", 0x2000, 0x8, 1),
+                ("helper", 0x1020, 0x10, STT_FUNC),
+            ],
+        );
+        let elf = Elf::parse(&bytes).unwrap();
+        let symtab = elf.section_by_name(".symtab").unwrap();
+        assert_eq!(symtab.typ, SHT_SYMTAB);
+        assert!(elf.section_by_name(".text").is_none());
+
+        let symbols = elf.function_symbols().collect::<Vec<_>>();
+        assert_eq!(
+            symbols,
+            [
+                Symbol {
+                    name: "main",
+                    value: 0x1000,
+                    size: 0x20
+                },
+                Symbol {
+                    name: "helper",
+                    value: 0x1020,
+                    size: 0x10
+                },
+            ]
+        );
+    }
+
+    /// Encodes an ELF note with 4-byte alignment.
+    pub(crate) fn encode_note(name: &str, typ: u32, desc: &[u8]) -> Vec<u8> {
+        let mut note = Vec::new();
+        note.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+        note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        note.extend_from_slice(&typ.to_le_bytes());
+        note.extend_from_slice(name.as_bytes());
+        note.push(0);
+        note.resize(note.len().next_multiple_of(4), 0);
+        note.extend_from_slice(desc);
+        note.resize(note.len().next_multiple_of(4), 0);
+        note
+    }
+
+    /// Builds an ELF file with a single `PT_NOTE` segment.
+    pub(crate) fn build_elf_with_notes(notes: &[u8]) -> Vec<u8> {
+        let mut elf = build_elf(0, &[(PF_R, 0, 0, notes, notes.len() as u64)]);
+        elf[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&PT_NOTE.to_le_bytes());
+        elf[HEADER_SIZE + 48..HEADER_SIZE + 56].copy_from_slice(&4_u64.to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn notes() {
+        let mut data = encode_note("Xen", 18, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        data.extend(encode_note("PhipsBoot", 1, &[4, 0, 0, 0]));
+        data.extend(encode_note("GNU", 3, &[1, 2, 3]));
+        let bytes = build_elf_with_notes(&data);
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.load_segments().count(), 0);
+        let notes = elf.notes().collect::<Vec<_>>();
+        assert_eq!(
+            notes,
+            [
+                Note {
+                    name: "Xen",
+                    typ: 18,
+                    desc: &[1, 2, 3, 4, 5, 6, 7, 8]
+                },
+                Note {
+                    name: "PhipsBoot",
+                    typ: 1,
+                    desc: &[4, 0, 0, 0]
+                },
+                Note {
+                    name: "GNU",
+                    typ: 3,
+                    desc: &[1, 2, 3]
+                },
+            ]
+        );
+
+        // Truncated description
+        let bytes = build_elf_with_notes(&data[..data.len() - 8]);
+        assert_eq!(Elf::parse(&bytes).unwrap().notes().count(), 2);
+    }
+
+    #[test]
+    fn segment_pages_merge_permissions() {
+        let segment = |flags, vaddr, memsz| ProgramHeader {
+            typ: PT_LOAD,
+            flags,
+            offset: 0,
+            vaddr,
+            paddr: vaddr - 0xffffffff80000000,
+            filesz: memsz,
+            memsz,
+            align: PAGE_SIZE,
+        };
+        // `.text` ends in the middle of the page on which `.data` starts.
+        let text = segment(PF_X, 0xffffffff80001000, 0x1800);
+        let data = segment(PF_W, 0xffffffff80002800, 0x1000);
+        let pages = segment_pages(&[text, data]);
+        let page = |vaddr, writable, executable| SegmentPage {
+            vaddr,
+            paddr: vaddr - 0xffffffff80000000,
+            writable,
+            executable,
+        };
+        assert_eq!(
+            pages,
+            [
+                page(0xffffffff80001000, false, true),
+                page(0xffffffff80002000, true, true),
+                page(0xffffffff80003000, true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(Elf::parse(&[0; 10]).unwrap_err(), ElfError::TooSmall);
+        assert_eq!(Elf::parse(&[0; 64]).unwrap_err(), ElfError::BadMagic);
+
+        let mut bytes = build_elf(0, &[]);
+        bytes[4] = 1;
+        assert_eq!(Elf::parse(&bytes).unwrap_err(), ElfError::Not64Bit);
+
+        let mut bytes = build_elf(0, &[]);
+        bytes[18] = 3; // i386
+        assert_eq!(Elf::parse(&bytes).unwrap_err(), ElfError::NotX86_64(3));
+
+        let mut bytes = build_elf(0, &[(PF_R, 0x1000, 0x1000, &[1, 2, 3, 4], 4)]);
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(
+            Elf::parse(&bytes).unwrap_err(),
+            ElfError::SegmentOutOfBounds(0)
+        );
+
+        let bytes = build_elf(0, &[(PF_R, 0x1000, 0x1000, &[1, 2, 3, 4], 2)]);
+        assert_eq!(
+            Elf::parse(&bytes).unwrap_err(),
+            ElfError::SegmentFileSizeTooLarge(0)
+        );
+
+        let bytes = build_elf(
+            0,
+            &[
+                (PF_R, 0x1000, 0x1000, &[], 0x1000),
+                (PF_R, 0xfffffffffffff000, 0x2000, &[], 0x2000),
+            ],
+        );
+        assert_eq!(
+            Elf::parse(&bytes).unwrap_err(),
+            ElfError::SegmentAddressOverflow(1)
+        );
+        let bytes = build_elf(0, &[(PF_R, 0x1000, u64::MAX, &[], 2)]);
+        assert_eq!(
+            Elf::parse(&bytes).unwrap_err(),
+            ElfError::SegmentAddressOverflow(0)
+        );
+    }
+}
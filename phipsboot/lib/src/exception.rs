@@ -0,0 +1,188 @@
+//! Human-readable descriptions of x86_64 exceptions for the diagnostics of
+//! the loader.
+
+use core::fmt::{Display, Formatter};
+
+/// Names of the exceptions, indexed by vector.
+const NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "nmi",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid tss",
+    "segment not present",
+    "stack-segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating-point",
+    "alignment check",
+    "machine check",
+    "simd floating-point",
+    "virtualization",
+    "control protection",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection",
+    "vmm communication",
+    "security",
+    "reserved",
+];
+
+/// Returns the name of the exception with the given vector.
+pub fn name(vector: u64) -> &'static str {
+    NAMES.get(vector as usize).copied().unwrap_or("interrupt")
+}
+
+/// Returns true if the CPU pushes an error code for the exception.
+pub fn has_error_code(vector: u64) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// Returns true if the error code of the exception references a segment
+/// selector or an IDT entry.
+pub fn has_selector_error_code(vector: u64) -> bool {
+    matches!(vector, 10..=13)
+}
+
+/// The error code of a page fault.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageFaultError(pub u64);
+
+impl Display for PageFaultError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let bit = |n: u32| self.0 & (1 << n) != 0;
+        let cause = if bit(0) {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+        let access = if bit(4) {
+            "instruction fetch"
+        } else if bit(1) {
+            "write"
+        } else {
+            "read"
+        };
+        let mode = if bit(2) { "user" } else { "supervisor" };
+        write!(f, "{cause} on {access} in {mode} mode")?;
+        if bit(3) {
+            write!(f, ", reserved bit set")?;
+        }
+        if bit(5) {
+            write!(f, ", protection key")?;
+        }
+        if bit(6) {
+            write!(f, ", shadow stack")?;
+        }
+        if bit(15) {
+            write!(f, ", SGX")?;
+        }
+        Ok(())
+    }
+}
+
+/// An error code that references a segment selector or an IDT entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SelectorError(pub u64);
+
+impl Display for SelectorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let index = (self.0 >> 3) & 0x1fff;
+        match (self.0 >> 1) & 0b11 {
+            0b00 => write!(f, "GDT selector {:#x}", self.0 & 0xfff8)?,
+            0b10 => write!(f, "LDT selector {:#x}", self.0 & 0xfff8 | 0b100)?,
+            _ => write!(f, "IDT vector {index:#x}")?,
+        }
+        if self.0 & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats bytes as hex dump in one line, starting with the address of the
+/// first byte. The byte at address `mark` is put in brackets.
+#[derive(Copy, Clone, Debug)]
+pub struct HexDump<'a> {
+    pub addr: u64,
+    pub bytes: &'a [u8],
+    pub mark: u64,
+}
+
+impl Display for HexDump<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#x}:", self.addr)?;
+        for (addr, byte) in (self.addr..).zip(self.bytes) {
+            if addr == self.mark {
+                write!(f, " [{byte:02x}]")?;
+            } else {
+                write!(f, " {byte:02x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn names() {
+        assert_eq!(name(14), "page fault");
+        assert_eq!(name(24), "reserved");
+        assert_eq!(name(32), "interrupt");
+        assert!(has_error_code(8));
+        assert!(!has_error_code(18));
+        assert!(has_selector_error_code(13));
+        assert!(!has_selector_error_code(14));
+    }
+
+    #[test]
+    fn page_fault_error() {
+        assert_eq!(
+            PageFaultError(0).to_string(),
+            "page not present on read in supervisor mode"
+        );
+        assert_eq!(
+            PageFaultError(0b111).to_string(),
+            "protection violation on write in user mode"
+        );
+        assert_eq!(
+            PageFaultError(0b11001).to_string(),
+            "protection violation on instruction fetch in supervisor mode, reserved bit set"
+        );
+    }
+
+    #[test]
+    fn selector_error() {
+        assert_eq!(SelectorError(0x28).to_string(), "GDT selector 0x28");
+        assert_eq!(SelectorError(0x2c).to_string(), "LDT selector 0x2c");
+        assert_eq!(
+            SelectorError(0x0e << 3 | 0b011).to_string(),
+            "IDT vector 0xe, external event"
+        );
+    }
+
+    #[test]
+    fn hex_dump() {
+        let dump = HexDump {
+            addr: 0x1000,
+            bytes: &[0x0f, 0x0b, 0xc3],
+            mark: 0x1001,
+        };
+        assert_eq!(dump.to_string(), "0x1000: 0f [0b] c3");
+    }
+}
@@ -4,7 +4,17 @@ extern crate alloc;
 #[cfg(test)]
 extern crate std;
 
+pub mod acpi;
+pub mod bootinfo;
 pub mod cli;
+pub mod cpu;
+pub mod elf;
+pub mod exception;
 pub mod logger;
 pub mod mem;
+pub mod note;
+pub mod pci;
 pub mod safe;
+pub mod smbios;
+pub mod symbols;
+pub mod time;
@@ -17,12 +17,17 @@ use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::cell::RefCell;
-use core::fmt::{Debug, Write};
+use core::fmt::{Arguments, Debug, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 use log::{LevelFilter, Log, Metadata, Record};
 
 /// Logger instance with `static` lifetime for the [`log::set_logger`] interface.
 static LOGGER: Safe<RefCell<LoggerFacade>> = Safe::new(RefCell::new(LoggerFacade::new()));
 
+/// Set while [`emergency_write`] accesses the logger, to detect a fatal error
+/// inside the emergency path itself.
+static IN_EMERGENCY_WRITE: AtomicBool = AtomicBool::new(false);
+
 /// Initializes the logger. The logger depends on the heap being available!
 ///
 /// At the beginning, log messages are buffered in a vector. A user must call
@@ -46,6 +51,42 @@ pub fn add_backend<B: Backend>(backend: B) -> Result<(), BackendAlreadySpecified
     LOGGER.borrow_mut().add_backend(backend)
 }
 
+/// Emergency path for fatal errors, such as panics. Writes the message to all
+/// backends, even if the regular logging was not flushed yet. Messages that
+/// are still buffered are written first.
+///
+/// The backends are accessed even if the logger is already borrowed, such as
+/// for a panic while logging or an exception in a backend. This aliases the
+/// borrow, which is acceptable only because the interrupted code never
+/// resumes: Use this only if the fatal error ends the execution.
+///
+/// Returns `false` if nothing was written, because there are no backends yet
+/// or because the fatal error happened inside the emergency path itself. The
+/// caller should then fall back to more basic output channels.
+pub fn emergency_write(args: Arguments) -> bool {
+    if IN_EMERGENCY_WRITE.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    // SAFETY: See the function documentation. Re-entrance is excluded above.
+    let logger = unsafe { &mut *LOGGER.as_ptr() };
+    let written = logger.emergency_write(args);
+    IN_EMERGENCY_WRITE.store(false, Ordering::SeqCst);
+    written
+}
+
+/// Writes the message to all backends like [`emergency_write`], but only if the
+/// logger isn't borrowed. This is for code that may interrupt the logger but
+/// after which the interrupted code resumes, such as interrupt handlers.
+///
+/// Returns `false` if nothing was written, because there are no backends yet
+/// or because the logger is borrowed.
+pub fn try_write(args: Arguments) -> bool {
+    match LOGGER.try_borrow_mut() {
+        Ok(mut logger) => logger.emergency_write(args),
+        Err(_) => false,
+    }
+}
+
 /// The provided backend is already specified.
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct BackendAlreadySpecifiedError<B: Backend>(B);
@@ -144,6 +185,21 @@ impl LoggerFacade {
         }
     }
 
+    /// Writes the buffered messages and the message to all backends. Returns
+    /// `false` if there are no backends.
+    fn emergency_write(&mut self, args: Arguments) -> bool {
+        if self.backends.is_empty() {
+            return false;
+        }
+        if let Some(messages) = self.message_buffer.take() {
+            for msg in messages {
+                self.write_to_all_backends(&msg);
+            }
+        }
+        let _ = self.write_fmt(args);
+        true
+    }
+
     #[cfg(test)]
     fn buffered_msg_count(&self) -> Option<usize> {
         self.message_buffer.as_ref().map(|v| v.len())
@@ -354,4 +410,51 @@ mod tests {
             "[DEBUG demo.rs@42]: a=13, b=73\n"
         )
     }
+
+    #[test]
+    fn emergency_write_to_backends() {
+        let mut logger = LoggerFacade::new();
+        assert!(!logger.emergency_write(format_args!("PANIC\n")));
+
+        let backend = BufferingBackend::default();
+        let backend_received = backend.0.clone();
+        logger.add_backend(backend).unwrap();
+        logger.log_or_buffer_record(
+            &Record::builder()
+                .target("target")
+                .level(Level::Debug)
+                .line(Some(142))
+                .file(Some("demo.rs"))
+                .args(format_args!("a={}, b={}", 13, 73))
+                .build(),
+        );
+
+        assert!(logger.emergency_write(format_args!("PANIC: {}\n", "oops")));
+        assert_eq!(logger.buffered_msg_count(), None);
+        assert_eq!(
+            backend_received.borrow().as_str(),
+            "[DEBUG demo.rs@142]: a=13, b=73\nPANIC: oops\n"
+        );
+    }
+
+    #[test]
+    fn emergency_write_while_borrowed() {
+        assert!(!emergency_write(format_args!("PANIC\n")));
+
+        let backend = BufferingBackend::default();
+        let backend_received = backend.0.clone();
+        super::add_backend(backend).unwrap();
+
+        let borrow = LOGGER.borrow_mut();
+        assert!(!try_write(format_args!("interrupt\n")));
+        assert!(emergency_write(format_args!("PANIC: {}\n", "oops")));
+        assert_eq!(backend_received.borrow().as_str(), "PANIC: oops\n");
+
+        drop(borrow);
+        assert!(try_write(format_args!("interrupt\n")));
+        assert_eq!(
+            backend_received.borrow().as_str(),
+            "PANIC: oops\ninterrupt\n"
+        );
+    }
 }
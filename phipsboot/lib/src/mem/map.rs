@@ -0,0 +1,509 @@
+//! Model of the physical memory of the system. The loader builds a
+//! [`MemoryMap`] from the information of the bootloader and marks all memory
+//! that it uses, so that allocations never clash with firmware, boot modules,
+//! or the kernel.
+
+use crate::mem::paging::{FrameAllocator, PhysAddr, PAGE_SIZE};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+/// Half-open range `[start, end)` of physical memory.
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Hash, Eq, Ord)]
+pub struct PhysRange {
+    start: u64,
+    end: u64,
+}
+
+impl PhysRange {
+    /// Constructor.
+    pub fn new(start: u64, end: u64) -> Self {
+        assert!(start <= end, "start must be <= end");
+        Self { start, end }
+    }
+
+    /// Creates a range from a start address and a length.
+    pub fn from_len(start: u64, len: u64) -> Self {
+        Self::new(start, start + len)
+    }
+
+    /// Returns the inclusive start address.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Returns the exclusive end address.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns the length in bytes.
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if both ranges share at least one byte.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    /// Returns the bytes that both ranges share, if any.
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        self.overlaps(other)
+            .then(|| Self::new(self.start.max(other.start), self.end.min(other.end)))
+    }
+
+    /// Returns the range extended to page boundaries.
+    pub fn page_aligned(&self) -> Self {
+        Self::new(
+            self.start & !(PAGE_SIZE - 1),
+            self.end.next_multiple_of(PAGE_SIZE),
+        )
+    }
+}
+
+impl Display for PhysRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#010x}..{:#010x}", self.start, self.end)
+    }
+}
+
+/// The kind of memory of a [`MemoryRegion`].
+///
+/// The values up to [`MemoryRegionKind::BadMemory`] match the memory types of
+/// Multiboot2 and the E820 memory map.
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Hash, Eq, Ord)]
+#[repr(u32)]
+pub enum MemoryRegionKind {
+    /// Free RAM.
+    Usable = 1,
+    /// Reserved by the firmware or the hardware, such as MMIO.
+    Reserved = 2,
+    /// RAM holding ACPI tables. Usable once the tables were parsed.
+    AcpiReclaimable = 3,
+    /// ACPI non-volatile storage.
+    AcpiNvs = 4,
+    /// Defective RAM.
+    BadMemory = 5,
+    /// The binary of PhipsBoot.
+    PhipsBoot = 0x1000,
+    /// The boot information of the bootloader that loaded PhipsBoot, such as
+    /// the Multiboot2 information structure.
+    BootloaderInfo,
+    /// The boot information that PhipsBoot prepares for the kernel.
+    BootInfo,
+    /// LOAD segments and the stack of the kernel.
+    Kernel,
+    /// Boot modules, including the ELF file of the kernel.
+    Module,
+    /// Page tables that PhipsBoot prepared for the kernel.
+    PageTables,
+    /// Memory that PhipsBoot used during boot. The kernel can use it as soon
+    /// as it no longer needs anything from PhipsBoot.
+    BootloaderReclaimable,
+    /// The GDT, the TSS, and the IST stacks that PhipsBoot prepared for the
+    /// kernel.
+    CpuTables,
+}
+
+/// A range of physical memory of a certain [`MemoryRegionKind`].
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Hash, Eq, Ord)]
+pub struct MemoryRegion {
+    pub range: PhysRange,
+    pub kind: MemoryRegionKind,
+}
+
+impl Display for MemoryRegion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} ({:?})", self.range, self.kind)
+    }
+}
+
+/// Map of the physical memory. The regions are sorted, never overlap, and
+/// adjacent regions of the same kind are merged.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    regions: Vec<MemoryRegion>,
+}
+
+impl MemoryMap {
+    /// Creates an empty memory map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a region as reported by the firmware. Firmware memory maps may
+    /// contain overlapping entries. In that case, [`MemoryRegionKind::Usable`]
+    /// never takes precedence over other kinds.
+    pub fn add_firmware_region(&mut self, range: PhysRange, kind: MemoryRegionKind) {
+        if kind != MemoryRegionKind::Usable {
+            self.mark(range, kind);
+            return;
+        }
+
+        // Only fill the gaps that are not described yet.
+        for gap in self.gaps(range) {
+            self.mark(gap, kind);
+        }
+    }
+
+    /// Marks the given range as the given kind, regardless of what was there
+    /// before.
+    pub fn mark(&mut self, range: PhysRange, kind: MemoryRegionKind) {
+        if range.is_empty() {
+            return;
+        }
+
+        let mut regions = Vec::with_capacity(self.regions.len() + 2);
+        for region in &self.regions {
+            if !region.range.overlaps(&range) {
+                regions.push(*region);
+                continue;
+            }
+            // Keep the parts that are not covered by the new range.
+            if region.range.start < range.start {
+                regions.push(MemoryRegion {
+                    range: PhysRange::new(region.range.start, range.start),
+                    kind: region.kind,
+                });
+            }
+            if region.range.end > range.end {
+                regions.push(MemoryRegion {
+                    range: PhysRange::new(range.end, region.range.end),
+                    kind: region.kind,
+                });
+            }
+        }
+        regions.push(MemoryRegion { range, kind });
+        regions.sort_unstable();
+
+        // Merge adjacent regions of the same kind.
+        self.regions.clear();
+        for region in regions {
+            match self.regions.last_mut() {
+                Some(last) if last.kind == region.kind && last.range.end == region.range.start => {
+                    last.range.end = region.range.end;
+                }
+                _ => self.regions.push(region),
+            }
+        }
+    }
+
+    /// Changes the kind of all regions of kind `from` to `to`.
+    pub fn replace_kind(&mut self, from: MemoryRegionKind, to: MemoryRegionKind) {
+        let ranges = self
+            .regions
+            .iter()
+            .filter(|region| region.kind == from)
+            .map(|region| region.range)
+            .collect::<Vec<_>>();
+        for range in ranges {
+            self.mark(range, to);
+        }
+    }
+
+    /// Marks the parts of the given range that are still of kind `kind` as
+    /// [`MemoryRegionKind::Usable`]. Parts that were marked as something else
+    /// in the meantime, such as a kernel segment, stay as they are.
+    pub fn free(&mut self, range: PhysRange, kind: MemoryRegionKind) {
+        let ranges = self
+            .overlapping(range)
+            .filter(|region| region.kind == kind)
+            .filter_map(|region| region.range.intersection(&range))
+            .collect::<Vec<_>>();
+        for range in ranges {
+            self.mark(range, MemoryRegionKind::Usable);
+        }
+    }
+
+    /// Returns all regions.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    /// Returns all regions that overlap with the given range.
+    pub fn overlapping(&self, range: PhysRange) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions
+            .iter()
+            .filter(move |region| region.range.overlaps(&range))
+    }
+
+    /// Returns the parts of the given range that no region describes.
+    pub fn gaps(&self, range: PhysRange) -> Vec<PhysRange> {
+        let mut gaps = Vec::new();
+        let mut cursor = range.start;
+        for region in self.overlapping(range) {
+            if region.range.start > cursor {
+                gaps.push(PhysRange::new(cursor, region.range.start));
+            }
+            cursor = cursor.max(region.range.end);
+        }
+        if cursor < range.end {
+            gaps.push(PhysRange::new(cursor, range.end));
+        }
+        gaps
+    }
+
+    /// Returns the ranges of all regions whose kind matches the filter,
+    /// extended to multiples of `align` and merged where they touch or
+    /// overlap afterwards.
+    pub fn aligned_ranges(
+        &self,
+        align: u64,
+        filter: impl Fn(MemoryRegionKind) -> bool,
+    ) -> Vec<PhysRange> {
+        assert!(align.is_power_of_two());
+        let mut ranges = Vec::<PhysRange>::new();
+        for region in self.regions.iter().filter(|region| filter(region.kind)) {
+            let start = region.range.start & !(align - 1);
+            let end = region.range.end.next_multiple_of(align);
+            match ranges.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ => ranges.push(PhysRange::new(start, end)),
+            }
+        }
+        ranges
+    }
+
+    /// Allocates `len` bytes of usable memory with the given alignment that
+    /// end below `limit`. Memory is allocated from the top, so that low memory
+    /// stays available for kernels with fixed load addresses. The allocated
+    /// range is marked as `kind`.
+    pub fn allocate(
+        &mut self,
+        len: u64,
+        align: u64,
+        kind: MemoryRegionKind,
+        limit: u64,
+    ) -> Option<PhysRange> {
+        assert!(align.is_power_of_two());
+        let range = self
+            .regions
+            .iter()
+            .rev()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .find_map(|region| {
+                let end = region.range.end.min(limit);
+                let start = end.checked_sub(len)? & !(align - 1);
+                (start >= region.range.start).then(|| PhysRange::from_len(start, len))
+            })?;
+        self.mark(range, kind);
+        Some(range)
+    }
+
+    /// Returns a [`FrameAllocator`] that marks all allocated frames as `kind`
+    /// and only hands out frames below `limit`.
+    pub fn frame_allocator(
+        &mut self,
+        kind: MemoryRegionKind,
+        limit: u64,
+    ) -> MemoryMapFrameAllocator<'_> {
+        MemoryMapFrameAllocator {
+            map: self,
+            kind,
+            limit,
+        }
+    }
+}
+
+/// [`FrameAllocator`] on top of a [`MemoryMap`].
+#[derive(Debug)]
+pub struct MemoryMapFrameAllocator<'a> {
+    map: &'a mut MemoryMap,
+    kind: MemoryRegionKind,
+    limit: u64,
+}
+
+impl FrameAllocator for MemoryMapFrameAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysAddr> {
+        self.map
+            .allocate(PAGE_SIZE, PAGE_SIZE, self.kind, self.limit)
+            .map(|range| PhysAddr::new(range.start()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion {
+            range: PhysRange::new(start, end),
+            kind,
+        }
+    }
+
+    #[test]
+    fn range() {
+        let range = PhysRange::new(0x1000, 0x3000);
+        assert_eq!(range.len(), 0x2000);
+        assert!(range.overlaps(&PhysRange::new(0x2fff, 0x4000)));
+        assert!(!range.overlaps(&PhysRange::new(0x3000, 0x4000)));
+        assert_eq!(
+            PhysRange::new(0x1001, 0x2001).page_aligned(),
+            PhysRange::new(0x1000, 0x3000)
+        );
+        assert_eq!(range.to_string(), "0x00001000..0x00003000");
+        assert_eq!(
+            range.intersection(&PhysRange::new(0x2000, 0x8000)),
+            Some(PhysRange::new(0x2000, 0x3000))
+        );
+        assert_eq!(range.intersection(&PhysRange::new(0x0, 0x1000)), None);
+    }
+
+    #[test]
+    fn mark_splits_and_merges() {
+        let mut map = MemoryMap::new();
+        map.add_firmware_region(PhysRange::new(0x0, 0x10000), MemoryRegionKind::Usable);
+        map.mark(PhysRange::new(0x4000, 0x8000), MemoryRegionKind::Kernel);
+        assert_eq!(
+            map.regions(),
+            [
+                region(0x0, 0x4000, MemoryRegionKind::Usable),
+                region(0x4000, 0x8000, MemoryRegionKind::Kernel),
+                region(0x8000, 0x10000, MemoryRegionKind::Usable),
+            ]
+        );
+
+        map.mark(PhysRange::new(0x4000, 0x8000), MemoryRegionKind::Usable);
+        assert_eq!(
+            map.regions(),
+            [region(0x0, 0x10000, MemoryRegionKind::Usable)]
+        );
+
+        map.mark(PhysRange::new(0x1000, 0x2000), MemoryRegionKind::PhipsBoot);
+        map.mark(PhysRange::new(0x2000, 0x3000), MemoryRegionKind::BootloaderInfo);
+        map.replace_kind(
+            MemoryRegionKind::PhipsBoot,
+            MemoryRegionKind::BootloaderReclaimable,
+        );
+        map.replace_kind(
+            MemoryRegionKind::BootloaderInfo,
+            MemoryRegionKind::BootloaderReclaimable,
+        );
+        assert_eq!(
+            map.regions()[1],
+            region(0x1000, 0x3000, MemoryRegionKind::BootloaderReclaimable)
+        );
+    }
+
+    #[test]
+    fn free_keeps_kernel_segments() {
+        let mut map = MemoryMap::new();
+        map.add_firmware_region(PhysRange::new(0x0, 0x10000), MemoryRegionKind::Usable);
+        // A kernel segment overlaps the end of a module, which is moved.
+        let module = PhysRange::new(0x2000, 0x6000);
+        map.mark(module, MemoryRegionKind::Module);
+        map.mark(PhysRange::new(0x4000, 0x8000), MemoryRegionKind::Kernel);
+        let new = map
+            .allocate(module.len(), 0x1000, MemoryRegionKind::Module, u64::MAX)
+            .unwrap();
+        assert_eq!(new, PhysRange::new(0xc000, 0x10000));
+
+        map.free(module, MemoryRegionKind::Module);
+        assert_eq!(
+            map.regions(),
+            [
+                region(0x0, 0x4000, MemoryRegionKind::Usable),
+                region(0x4000, 0x8000, MemoryRegionKind::Kernel),
+                region(0x8000, 0xc000, MemoryRegionKind::Usable),
+                region(0xc000, 0x10000, MemoryRegionKind::Module),
+            ]
+        );
+        // The kernel's pages are never handed out.
+        assert_eq!(
+            map.allocate(0x4000, 0x1000, MemoryRegionKind::Module, 0x8000),
+            Some(PhysRange::new(0x0, 0x4000))
+        );
+        assert_eq!(
+            map.allocate(0x1000, 0x1000, MemoryRegionKind::Module, 0x8000),
+            None
+        );
+    }
+
+    #[test]
+    fn firmware_usable_never_overrides() {
+        let mut map = MemoryMap::new();
+        map.add_firmware_region(PhysRange::new(0x9f000, 0xa0000), MemoryRegionKind::Reserved);
+        map.add_firmware_region(PhysRange::new(0x0, 0x100000), MemoryRegionKind::Usable);
+        assert_eq!(
+            map.regions(),
+            [
+                region(0x0, 0x9f000, MemoryRegionKind::Usable),
+                region(0x9f000, 0xa0000, MemoryRegionKind::Reserved),
+                region(0xa0000, 0x100000, MemoryRegionKind::Usable),
+            ]
+        );
+    }
+
+    #[test]
+    fn gaps() {
+        let mut map = MemoryMap::new();
+        map.add_firmware_region(PhysRange::new(0x1000, 0x3000), MemoryRegionKind::Usable);
+        map.add_firmware_region(PhysRange::new(0x5000, 0x6000), MemoryRegionKind::Reserved);
+        assert_eq!(
+            map.gaps(PhysRange::new(0x0, 0x8000)),
+            [
+                PhysRange::new(0x0, 0x1000),
+                PhysRange::new(0x3000, 0x5000),
+                PhysRange::new(0x6000, 0x8000)
+            ]
+        );
+        assert!(map.gaps(PhysRange::new(0x2000, 0x3000)).is_empty());
+    }
+
+    #[test]
+    fn aligned_ranges() {
+        let mut map = MemoryMap::new();
+        map.add_firmware_region(PhysRange::new(0x0, 0x9f000), MemoryRegionKind::Usable);
+        map.add_firmware_region(PhysRange::new(0xf0000, 0x100000), MemoryRegionKind::Reserved);
+        map.add_firmware_region(PhysRange::new(0x100000, 0x7fe0000), MemoryRegionKind::Usable);
+        map.mark(PhysRange::new(0x200000, 0x300000), MemoryRegionKind::Kernel);
+        map.add_firmware_region(
+            PhysRange::new(0xfee00000, 0xfee01000),
+            MemoryRegionKind::Reserved,
+        );
+
+        let ram = |kind| kind != MemoryRegionKind::Reserved;
+        assert_eq!(
+            map.aligned_ranges(0x200000, ram),
+            [PhysRange::new(0x0, 0x8000000)]
+        );
+        assert_eq!(
+            map.aligned_ranges(0x200000, |_| true),
+            [
+                PhysRange::new(0x0, 0x8000000),
+                PhysRange::new(0xfee00000, 0xff000000)
+            ]
+        );
+    }
+
+    #[test]
+    fn allocate_from_top() {
+        let mut map = MemoryMap::new();
+        map.add_firmware_region(PhysRange::new(0x100000, 0x800000), MemoryRegionKind::Usable);
+        map.add_firmware_region(PhysRange::new(0x1000000, 0x2000000), MemoryRegionKind::Usable);
+
+        let range = map
+            .allocate(0x1800, 0x1000, MemoryRegionKind::Module, 0x1000000)
+            .unwrap();
+        assert_eq!(range, PhysRange::new(0x7fe000, 0x7ff800));
+
+        let mut alloc = map.frame_allocator(MemoryRegionKind::PageTables, u64::MAX);
+        assert_eq!(alloc.allocate_frame(), Some(PhysAddr::new(0x1fff000)));
+        assert_eq!(alloc.allocate_frame(), Some(PhysAddr::new(0x1ffe000)));
+        assert_eq!(
+            map.regions().last(),
+            Some(&region(0x1ffe000, 0x2000000, MemoryRegionKind::PageTables))
+        );
+
+        assert_eq!(
+            map.allocate(0x800000, 0x1000, MemoryRegionKind::Module, 0x1000000),
+            None
+        );
+    }
+}
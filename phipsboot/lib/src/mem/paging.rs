@@ -1,22 +1,103 @@
-//! Types and helpers for x86_64 4-level paging.
+//! Types and helpers for x86_64 4-level and 5-level paging.
+//!
+//! Besides address types, this module provides [`AddressSpace`], a minimal
+//! builder for page-table hierarchies. It is used to prepare the address space
+//! of the kernel.
+
+use alloc::vec::Vec;
+use core::ops::Range;
 
 pub const PAGE_TABLE_ENTRY_SIZE: u64 = core::mem::size_of::<u64>() as u64;
 
+/// Number of entries in a page table.
+pub const PAGE_TABLE_ENTRY_COUNT: usize = 512;
+
+/// Size of a (small) page and of a page table.
+pub const PAGE_SIZE: u64 = 0x1000;
+
 /// 9 bits select the entry of the given page table.
 pub const INDEX_BITMASK: u64 = 0x1ff;
 
+/// Bits of a page-table entry that hold the physical address.
+pub const ENTRY_ADDR_BITMASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Flags of page-table entries. Only the ones relevant for the loader are
+/// listed.
+pub mod flags {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITABLE: u64 = 1 << 1;
+    pub const USER: u64 = 1 << 2;
+    /// Maps a huge page. Only valid for level 2 and level 3 entries.
+    pub const HUGE_PAGE: u64 = 1 << 7;
+    pub const GLOBAL: u64 = 1 << 8;
+    /// Requires `EFER.NXE`.
+    pub const NO_EXECUTE: u64 = 1 << 63;
+}
+
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Hash, Eq, Ord)]
 pub enum Level {
     One = 1,
     Two = 2,
     Three = 3,
     Four = 4,
+    /// Only with 5-level paging (`CR4.LA57`).
+    Five = 5,
 }
 
 impl Level {
     pub fn val(self) -> u64 {
         self as u64
     }
+
+    /// Returns the next lower level, if there is one.
+    pub fn next_lower(self) -> Option<Self> {
+        match self {
+            Self::One => None,
+            Self::Two => Some(Self::One),
+            Self::Three => Some(Self::Two),
+            Self::Four => Some(Self::Three),
+            Self::Five => Some(Self::Four),
+        }
+    }
+}
+
+/// The page sizes supported by x86_64 4-level paging.
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Hash, Eq, Ord)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    /// Requires CPUID `pdpe1gb`.
+    Size1GiB,
+}
+
+impl PageSize {
+    /// Returns the size in bytes.
+    pub fn val(self) -> u64 {
+        match self {
+            Self::Size4KiB => PAGE_SIZE,
+            Self::Size2MiB => 0x200000,
+            Self::Size1GiB => 0x40000000,
+        }
+    }
+
+    /// Returns the next smaller page size, if there is one.
+    pub fn next_smaller(self) -> Option<Self> {
+        match self {
+            Self::Size4KiB => None,
+            Self::Size2MiB => Some(Self::Size4KiB),
+            Self::Size1GiB => Some(Self::Size2MiB),
+        }
+    }
+
+    /// Returns the level of the page table that holds the entry for a page of
+    /// this size.
+    pub fn level(self) -> Level {
+        match self {
+            Self::Size4KiB => Level::One,
+            Self::Size2MiB => Level::Two,
+            Self::Size1GiB => Level::Three,
+        }
+    }
 }
 
 /// Helper for common impls of phys and virt addresses.
@@ -83,7 +164,19 @@ pub struct PhysAddr(u64);
 impl_addr!(PhysAddr);
 impl_addr!(VirtAddr);
 
+impl PhysAddr {
+    /// Returns true if the address is aligned to the given power of two.
+    pub fn is_aligned(self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+}
+
 impl VirtAddr {
+    /// Returns true if the address is aligned to the given power of two.
+    pub fn is_aligned(self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+
     /// Returns the index into the page table of the given level.
     /// The returned value is in range `0..512`.
     pub fn pt_index(&self, level: Level) -> u64 {
@@ -99,13 +192,288 @@ impl VirtAddr {
     }
 }
 
-/// Creates one single 1 GiB mapping with rwx permissions.
-fn _map_single_entry(_src: VirtAddr, _dest: PhysAddr, _flags: u64) {}
+/// A single page table with 512 entries.
+#[derive(Clone, Debug)]
+#[repr(C, align(4096))]
+pub struct PageTable([u64; PAGE_TABLE_ENTRY_COUNT]);
+
+impl PageTable {
+    /// Creates a page table with no present entries.
+    pub const fn new() -> Self {
+        Self([0; PAGE_TABLE_ENTRY_COUNT])
+    }
+
+    /// Returns the raw entries.
+    pub fn entries(&self) -> &[u64; PAGE_TABLE_ENTRY_COUNT] {
+        &self.0
+    }
+
+    /// Returns the raw entries.
+    pub fn entries_mut(&mut self) -> &mut [u64; PAGE_TABLE_ENTRY_COUNT] {
+        &mut self.0
+    }
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Allocator for physical frames that back page tables.
+pub trait FrameAllocator {
+    /// Allocates a 4 KiB-aligned physical frame of 4 KiB. The content of the
+    /// frame is undefined.
+    fn allocate_frame(&mut self) -> Option<PhysAddr>;
+}
+
+/// Errors that can happen when creating a mapping in an [`AddressSpace`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum MapError {
+    /// The [`FrameAllocator`] has no more frames for page tables.
+    OutOfMemory,
+    /// The virtual or the physical address is not aligned to the page size.
+    Misaligned {
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: PageSize,
+    },
+    /// The virtual address is already (partially) mapped.
+    AlreadyMapped(VirtAddr),
+}
+
+/// Builder for a hierarchy of 4-level or 5-level page tables.
+///
+/// All page tables must be accessible via an identity mapping of their
+/// physical address. In the loader, this is given for the frames from the
+/// [`FrameAllocator`]; in unit tests, the "physical" addresses are just
+/// heap addresses.
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: PhysAddr,
+    /// Level of the root page table, i.e., the number of paging levels.
+    root_level: Level,
+    /// Virtual ranges that [`Self::fill_range`] leaves unmapped.
+    holes: Vec<Range<u64>>,
+}
+
+impl AddressSpace {
+    /// Creates a new 4-level address space without any mappings.
+    pub fn new(alloc: &mut impl FrameAllocator) -> Result<Self, MapError> {
+        Self::with_levels(Level::Four, alloc)
+    }
+
+    /// Creates a new address space with the given number of paging levels
+    /// ([`Level::Four`] or [`Level::Five`]) without any mappings.
+    pub fn with_levels(
+        root_level: Level,
+        alloc: &mut impl FrameAllocator,
+    ) -> Result<Self, MapError> {
+        assert!(root_level >= Level::Four);
+        let root = Self::allocate_table(alloc)?;
+        Ok(Self {
+            root,
+            root_level,
+            holes: Vec::new(),
+        })
+    }
+
+    /// Creates an address space from an existing hierarchy of 4-level page
+    /// tables, such as the one that `%cr3` references.
+    ///
+    /// # Safety
+    /// `root` must point to a valid page-table hierarchy that is accessible
+    /// via an identity mapping.
+    pub unsafe fn from_root(root: PhysAddr) -> Self {
+        Self {
+            root,
+            root_level: Level::Four,
+            holes: Vec::new(),
+        }
+    }
+
+    /// Returns the physical address of the root page table. This is the value
+    /// for `%cr3`.
+    pub fn root(&self) -> PhysAddr {
+        self.root
+    }
+
+    /// Returns the level of the root page table, i.e., the number of paging
+    /// levels.
+    pub fn root_level(&self) -> Level {
+        self.root_level
+    }
+
+    /// Returns the size of the lower canonical half of the address space:
+    /// 128 TiB with 4-level paging and 64 PiB with 5-level paging.
+    pub fn lower_half_size(&self) -> u64 {
+        1 << (12 + 9 * self.root_level.val() - 1)
+    }
+
+    /// Maps a single page of the given size. Intermediate page tables are
+    /// created as needed. `flags` are applied to the leaf entry;
+    /// [`flags::PRESENT`] is always set.
+    pub fn map_page(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: PageSize,
+        flags: u64,
+        alloc: &mut impl FrameAllocator,
+    ) -> Result<(), MapError> {
+        if !vaddr.is_aligned(size.val()) || !paddr.is_aligned(size.val()) {
+            return Err(MapError::Misaligned { vaddr, paddr, size });
+        }
+
+        let mut table = self.root;
+        let mut level = self.root_level;
+        while level != size.level() {
+            let entry = Self::entry_mut(table, vaddr, level);
+            if *entry & flags::PRESENT == 0 {
+                let next = Self::allocate_table(alloc)?;
+                *entry = next.val() | flags::PRESENT | flags::WRITABLE;
+            } else if *entry & flags::HUGE_PAGE != 0 {
+                return Err(MapError::AlreadyMapped(vaddr));
+            }
+            // Otherwise, user mappings are not reachable.
+            *entry |= flags & flags::USER;
+            table = PhysAddr::new(*entry & ENTRY_ADDR_BITMASK);
+            level = level.next_lower().unwrap();
+        }
+
+        let entry = Self::entry_mut(table, vaddr, level);
+        if *entry & flags::PRESENT != 0 {
+            return Err(MapError::AlreadyMapped(vaddr));
+        }
+        let huge_flag = if size == PageSize::Size4KiB {
+            0
+        } else {
+            flags::HUGE_PAGE
+        };
+        *entry = paddr.val() | flags | flags::PRESENT | huge_flag;
+        Ok(())
+    }
+
+    /// Maps `len` bytes starting at `vaddr` to `paddr` using pages of the
+    /// given size. `len` is rounded up to the page size.
+    pub fn map_range(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        len: u64,
+        size: PageSize,
+        flags: u64,
+        alloc: &mut impl FrameAllocator,
+    ) -> Result<(), MapError> {
+        let count = len.div_ceil(size.val());
+        for i in 0..count {
+            let offset = i * size.val();
+            self.map_page(
+                VirtAddr::new(vaddr.val() + offset),
+                PhysAddr::new(paddr.val() + offset),
+                size,
+                flags,
+                alloc,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the virtual range unmapped in [`Self::fill_range`], such as a
+    /// guard page that isn't mapped yet.
+    pub fn add_hole(&mut self, range: Range<u64>) {
+        self.holes.push(range);
+    }
+
+    /// Like [`Self::map_range`], but leaves existing mappings and the holes
+    /// from [`Self::add_hole`] untouched. Where a page of the given size can't
+    /// be mapped, as parts of it are already mapped or a hole, the remaining
+    /// parts are mapped with the next smaller page size.
+    pub fn fill_range(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        len: u64,
+        size: PageSize,
+        flags: u64,
+        alloc: &mut impl FrameAllocator,
+    ) -> Result<(), MapError> {
+        let count = len.div_ceil(size.val());
+        for i in 0..count {
+            let offset = i * size.val();
+            let vaddr = VirtAddr::new(vaddr.val() + offset);
+            let paddr = PhysAddr::new(paddr.val() + offset);
+            let page = vaddr.val()..vaddr.val() + size.val();
+            let in_hole = self
+                .holes
+                .iter()
+                .any(|hole| hole.start < page.end && page.start < hole.end);
+            let result = if in_hole {
+                Err(MapError::AlreadyMapped(vaddr))
+            } else {
+                self.map_page(vaddr, paddr, size, flags, alloc)
+            };
+            match result {
+                Err(MapError::AlreadyMapped(_)) => {
+                    if let Some(smaller) = size.next_smaller() {
+                        self.fill_range(vaddr, paddr, size.val(), smaller, flags, alloc)?;
+                    }
+                }
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Translates a virtual address to the physical address it is mapped to.
+    pub fn translate(&self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        let mut table = self.root;
+        let mut level = self.root_level;
+        loop {
+            let entry = *Self::entry_mut(table, vaddr, level);
+            if entry & flags::PRESENT == 0 {
+                return None;
+            }
+            let addr = entry & ENTRY_ADDR_BITMASK;
+            let is_leaf = level == Level::One
+                || (level <= Level::Three && entry & flags::HUGE_PAGE != 0);
+            if is_leaf {
+                let page_mask = (1 << ((level.val() - 1) * 9 + 12)) - 1;
+                return Some(PhysAddr::new((addr & !page_mask) | (vaddr.val() & page_mask)));
+            }
+            table = PhysAddr::new(addr);
+            level = level.next_lower().unwrap();
+        }
+    }
+
+    /// Allocates and zeroes a frame for a new page table.
+    fn allocate_table(alloc: &mut impl FrameAllocator) -> Result<PhysAddr, MapError> {
+        let frame = alloc.allocate_frame().ok_or(MapError::OutOfMemory)?;
+        let table = frame.val() as *mut PageTable;
+        unsafe { table.write(PageTable::new()) };
+        Ok(frame)
+    }
+
+    /// Returns the entry for `vaddr` in the given page table.
+    fn entry_mut<'a>(table: PhysAddr, vaddr: VirtAddr, level: Level) -> &'a mut u64 {
+        let table = unsafe { &mut *(table.val() as *mut PageTable) };
+        &mut table.0[vaddr.pt_index(level) as usize]
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn lower_half_size() {
+        let mut alloc = HeapFrameAllocator::default();
+        let address_space = AddressSpace::new(&mut alloc).unwrap();
+        assert_eq!(address_space.lower_half_size(), 0x8000_0000_0000);
+        let address_space = AddressSpace::with_levels(Level::Five, &mut alloc).unwrap();
+        assert_eq!(address_space.lower_half_size(), 0x100_0000_0000_0000);
+    }
+
     /// Tests that the indices and offsets into page tables are properly
     /// calculated. I used the "paging-calculator" facility to verify those
     /// results.
@@ -116,9 +484,252 @@ mod tests {
         assert_eq!(addr.pt_index(Level::Two), 153);
         assert_eq!(addr.pt_index(Level::Three), 444);
         assert_eq!(addr.pt_index(Level::Four), 381);
+        assert_eq!(addr.pt_index(Level::Five), 173);
         assert_eq!(addr.pt_offset(Level::One), 0xb88);
         assert_eq!(addr.pt_offset(Level::Two), 0x4c8);
         assert_eq!(addr.pt_offset(Level::Three), 0xde0);
         assert_eq!(addr.pt_offset(Level::Four), 0xbe8);
     }
+
+    /// Frame allocator that hands out heap memory. The "physical" address is
+    /// the address on the heap.
+    #[derive(Default)]
+    struct HeapFrameAllocator(std::vec::Vec<std::boxed::Box<PageTable>>);
+
+    impl FrameAllocator for HeapFrameAllocator {
+        fn allocate_frame(&mut self) -> Option<PhysAddr> {
+            let table = std::boxed::Box::new(PageTable::new());
+            let addr = &*table as *const PageTable as u64;
+            self.0.push(table);
+            Some(PhysAddr::new(addr))
+        }
+    }
+
+    #[test]
+    fn map_and_translate() {
+        let mut alloc = HeapFrameAllocator::default();
+        let mut space = AddressSpace::new(&mut alloc).unwrap();
+        let vaddr = VirtAddr::new(0xffffffff80000000);
+        space
+            .map_range(
+                vaddr,
+                PhysAddr::new(0x100000),
+                0x1800,
+                PageSize::Size4KiB,
+                flags::WRITABLE,
+                &mut alloc,
+            )
+            .unwrap();
+        // root + L3 + L2 + L1
+        assert_eq!(alloc.0.len(), 4);
+        assert_eq!(space.translate(vaddr), Some(PhysAddr::new(0x100000)));
+        assert_eq!(
+            space.translate(VirtAddr::new(vaddr.val() + 0x1337)),
+            Some(PhysAddr::new(0x101337))
+        );
+        assert_eq!(space.translate(VirtAddr::new(vaddr.val() + 0x2000)), None);
+
+        let existing = unsafe { AddressSpace::from_root(space.root()) };
+        assert_eq!(existing.translate(vaddr), Some(PhysAddr::new(0x100000)));
+    }
+
+    #[test]
+    fn map_five_levels() {
+        let mut alloc = HeapFrameAllocator::default();
+        let mut space = AddressSpace::with_levels(Level::Five, &mut alloc).unwrap();
+        assert_eq!(space.root_level(), Level::Five);
+        // Only addressable with 5-level paging.
+        let vaddr = VirtAddr::new(0xff00_0000_0000_0000);
+        space
+            .map_page(
+                vaddr,
+                PhysAddr::new(0x200000),
+                PageSize::Size4KiB,
+                0,
+                &mut alloc,
+            )
+            .unwrap();
+        // root + L4 + L3 + L2 + L1
+        assert_eq!(alloc.0.len(), 5);
+        assert_eq!(
+            space.translate(VirtAddr::new(vaddr.val() + 0x42)),
+            Some(PhysAddr::new(0x200042))
+        );
+        assert_eq!(space.translate(VirtAddr::new(0xffff_8000_0000_0000)), None);
+    }
+
+    #[test]
+    fn map_huge_pages() {
+        let mut alloc = HeapFrameAllocator::default();
+        let mut space = AddressSpace::new(&mut alloc).unwrap();
+        space
+            .map_page(
+                VirtAddr::new(0x40000000),
+                PhysAddr::new(0x80000000),
+                PageSize::Size1GiB,
+                0,
+                &mut alloc,
+            )
+            .unwrap();
+        space
+            .map_page(
+                VirtAddr::new(0x200000),
+                PhysAddr::new(0x200000),
+                PageSize::Size2MiB,
+                0,
+                &mut alloc,
+            )
+            .unwrap();
+        assert_eq!(
+            space.translate(VirtAddr::new(0x40001337)),
+            Some(PhysAddr::new(0x80001337))
+        );
+        assert_eq!(
+            space.translate(VirtAddr::new(0x3fffff)),
+            Some(PhysAddr::new(0x3fffff))
+        );
+    }
+
+    #[test]
+    fn map_errors() {
+        let mut alloc = HeapFrameAllocator::default();
+        let mut space = AddressSpace::new(&mut alloc).unwrap();
+        let err = space
+            .map_page(
+                VirtAddr::new(0x1000),
+                PhysAddr::new(0x1000),
+                PageSize::Size2MiB,
+                0,
+                &mut alloc,
+            )
+            .unwrap_err();
+        assert!(matches!(err, MapError::Misaligned { .. }));
+
+        space
+            .map_page(
+                VirtAddr::new(0x200000),
+                PhysAddr::new(0x200000),
+                PageSize::Size2MiB,
+                0,
+                &mut alloc,
+            )
+            .unwrap();
+        // 4 KiB page inside the huge page
+        assert_eq!(
+            space.map_page(
+                VirtAddr::new(0x201000),
+                PhysAddr::new(0x1000),
+                PageSize::Size4KiB,
+                0,
+                &mut alloc,
+            ),
+            Err(MapError::AlreadyMapped(VirtAddr::new(0x201000)))
+        );
+    }
+
+    #[test]
+    fn fill_range_keeps_existing_mappings() {
+        let mut alloc = HeapFrameAllocator::default();
+        let mut space = AddressSpace::new(&mut alloc).unwrap();
+        space
+            .map_page(
+                VirtAddr::new(0x1000),
+                PhysAddr::new(0x5000),
+                PageSize::Size4KiB,
+                0,
+                &mut alloc,
+            )
+            .unwrap();
+        space
+            .fill_range(
+                VirtAddr::new(0),
+                PhysAddr::new(0),
+                0x400000,
+                PageSize::Size2MiB,
+                flags::WRITABLE,
+                &mut alloc,
+            )
+            .unwrap();
+        assert_eq!(
+            space.translate(VirtAddr::new(0x1000)),
+            Some(PhysAddr::new(0x5000))
+        );
+        assert_eq!(
+            space.translate(VirtAddr::new(0x2000)),
+            Some(PhysAddr::new(0x2000))
+        );
+        assert_eq!(
+            space.translate(VirtAddr::new(0x3fffff)),
+            Some(PhysAddr::new(0x3fffff))
+        );
+    }
+
+    #[test]
+    fn fill_range_skips_holes() {
+        let mut alloc = HeapFrameAllocator::default();
+        let mut space = AddressSpace::new(&mut alloc).unwrap();
+        // A stack with the guard page below it.
+        space
+            .map_page(
+                VirtAddr::new(0x203000),
+                PhysAddr::new(0x203000),
+                PageSize::Size4KiB,
+                flags::WRITABLE,
+                &mut alloc,
+            )
+            .unwrap();
+        space.add_hole(0x202000..0x203000);
+        space
+            .fill_range(
+                VirtAddr::new(0),
+                PhysAddr::new(0),
+                0x400000,
+                PageSize::Size2MiB,
+                flags::WRITABLE,
+                &mut alloc,
+            )
+            .unwrap();
+        assert_eq!(space.translate(VirtAddr::new(0x202000)), None);
+        assert_eq!(space.translate(VirtAddr::new(0x202fff)), None);
+        assert_eq!(
+            space.translate(VirtAddr::new(0x201fff)),
+            Some(PhysAddr::new(0x201fff))
+        );
+        assert_eq!(
+            space.translate(VirtAddr::new(0x203000)),
+            Some(PhysAddr::new(0x203000))
+        );
+        assert_eq!(
+            space.translate(VirtAddr::new(0x1fffff)),
+            Some(PhysAddr::new(0x1fffff))
+        );
+    }
+
+    #[test]
+    fn fill_range_skips_all_holes() {
+        let mut alloc = HeapFrameAllocator::default();
+        let mut space = AddressSpace::new(&mut alloc).unwrap();
+        // The guard pages of several stacks, such as the ones of the APs.
+        let guard_pages = [0x1000, 0x5000, 0x201000, 0x3ff000];
+        for page in guard_pages {
+            space.add_hole(page..page + PAGE_SIZE);
+        }
+        space
+            .fill_range(
+                VirtAddr::new(0),
+                PhysAddr::new(0),
+                0x400000,
+                PageSize::Size2MiB,
+                flags::WRITABLE,
+                &mut alloc,
+            )
+            .unwrap();
+        for page in guard_pages {
+            assert_eq!(space.translate(VirtAddr::new(page)), None);
+            assert_eq!(
+                space.translate(VirtAddr::new(page + PAGE_SIZE)).is_some(),
+                page + PAGE_SIZE < 0x400000
+            );
+        }
+    }
 }
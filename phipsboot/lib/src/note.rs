@@ -0,0 +1,281 @@
+//! The PhipsBoot ELF note, with which a kernel requests features from
+//! PhipsBoot.
+//!
+//! Each request is an ELF note with the name [`NAME`] in a `PT_NOTE` segment of
+//! the kernel. The type of the note selects the request, the description
+//! holds its value. PhipsBoot fails to load a kernel whose requests it can't
+//! satisfy. Kernels written in Rust can embed a request with [`ElfNote`]:
+//!
+//! ```
+//! use lib::note::{ElfNote, TYPE_PAGING_LEVELS};
+//!
+//! #[link_section = ".note.phipsboot"]
+//! #[used]
+//! static PAGING_LEVELS: ElfNote<u32> = ElfNote::new(TYPE_PAGING_LEVELS, 5);
+//! ```
+
+use crate::cpu::CpuFeatures;
+use crate::elf::Note;
+use crate::mem::paging::Level;
+use core::fmt::{Display, Formatter};
+use core::mem::size_of;
+
+/// Name of all PhipsBoot notes.
+pub const NAME: &str = "PhipsBoot";
+
+/// Request for the number of paging levels, as `u32`: `4` or `5`. 5-level
+/// paging is only used if the CPU supports it; otherwise, PhipsBoot falls back
+/// to 4-level paging. The boot information reports the actual number.
+pub const TYPE_PAGING_LEVELS: u32 = 1;
+
+/// Request for the minimum size of the kernel stack in bytes, as `u64`.
+/// `--stack-size` can only increase it.
+pub const TYPE_STACK_SIZE: u32 = 2;
+
+/// Request for the offset of the direct map of physical memory, as `u64`. It
+/// has the same effect as `--direct-map`, which must not specify a different
+/// offset.
+pub const TYPE_DIRECT_MAP: u32 = 3;
+
+/// Request for a framebuffer of the given kind, as `u32`: one of the
+/// `FRAMEBUFFER_*` kinds of [`bootinfo`](crate::bootinfo). The bootloader must
+/// have set up such a framebuffer. It is identity-mapped into the kernel's
+/// address space.
+pub const TYPE_FRAMEBUFFER: u32 = 4;
+
+/// Request for CPU features, as `u64` with the bits of
+/// [`CpuFeature`](crate::cpu::CpuFeature). Unlike with `--cpu-features`, all
+/// of them must be supported by the CPU.
+pub const TYPE_CPU_FEATURES: u32 = 5;
+
+/// Request for the minimum version of the boot information, as `u32`.
+pub const TYPE_MIN_VERSION: u32 = 6;
+
+/// Layout of a PhipsBoot note with the description `T` in memory.
+#[derive(Copy, Clone, Debug)]
+#[repr(C, align(4))]
+pub struct ElfNote<T> {
+    name_size: u32,
+    desc_size: u32,
+    typ: u32,
+    /// [`NAME`] with the terminating NUL, padded to 4 bytes.
+    name: [u8; 12],
+    desc: T,
+}
+
+impl<T> ElfNote<T> {
+    pub const fn new(typ: u32, desc: T) -> Self {
+        Self {
+            name_size: NAME.len() as u32 + 1,
+            desc_size: size_of::<T>() as u32,
+            typ,
+            name: *b"PhipsBoot\0\0\0",
+            desc,
+        }
+    }
+}
+
+/// Errors in the PhipsBoot notes of a kernel.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum NoteError {
+    /// The type of the note is unknown. The kernel probably requires a newer
+    /// version of PhipsBoot.
+    UnknownType(u32),
+    /// The description has the wrong size for the type.
+    InvalidSize { typ: u32, size: usize },
+    /// The value is not valid for the type.
+    InvalidValue { typ: u32, value: u64 },
+}
+
+impl Display for NoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnknownType(typ) => write!(f, "unknown note type {typ}"),
+            Self::InvalidSize { typ, size } => {
+                write!(f, "note type {typ} has an invalid size ({size} bytes)")
+            }
+            Self::InvalidValue { typ, value } => {
+                write!(f, "note type {typ} has an invalid value ({value:#x})")
+            }
+        }
+    }
+}
+
+/// The requests of a kernel from its PhipsBoot notes.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct KernelRequests {
+    /// Requested number of paging levels.
+    pub paging_levels: Option<Level>,
+    /// Minimum size of the kernel stack.
+    pub stack_size: Option<u64>,
+    /// Offset of the direct map.
+    pub direct_map: Option<u64>,
+    /// Kind of the framebuffer.
+    pub framebuffer: Option<u8>,
+    /// CPU features that must be enabled.
+    pub cpu_features: CpuFeatures,
+    /// Minimum version of the boot information.
+    pub min_version: Option<u32>,
+}
+
+impl KernelRequests {
+    /// Collects the requests from all notes named [`NAME`]. Other notes are
+    /// ignored. If a type appears more than once, the last note wins.
+    pub fn from_notes<'a>(notes: impl Iterator<Item = Note<'a>>) -> Result<Self, NoteError> {
+        let mut requests = Self::default();
+        for note in notes.filter(|note| note.name == NAME) {
+            let invalid = |value: u64| NoteError::InvalidValue {
+                typ: note.typ,
+                value,
+            };
+            match note.typ {
+                TYPE_PAGING_LEVELS => {
+                    requests.paging_levels = match read_u32(&note)? {
+                        4 => Some(Level::Four),
+                        5 => Some(Level::Five),
+                        value => return Err(invalid(value.into())),
+                    }
+                }
+                TYPE_STACK_SIZE => match read_u64(&note)? {
+                    0 => return Err(invalid(0)),
+                    size => requests.stack_size = Some(size),
+                },
+                TYPE_DIRECT_MAP => requests.direct_map = Some(read_u64(&note)?),
+                TYPE_FRAMEBUFFER => match read_u32(&note)? {
+                    kind @ 0..=2 => requests.framebuffer = Some(kind as u8),
+                    kind => return Err(invalid(kind.into())),
+                },
+                TYPE_CPU_FEATURES => {
+                    let bits = read_u64(&note)?;
+                    let features = CpuFeatures::from_bits(bits);
+                    if features.bits() != bits {
+                        return Err(invalid(bits));
+                    }
+                    requests.cpu_features = features;
+                }
+                TYPE_MIN_VERSION => requests.min_version = Some(read_u32(&note)?),
+                typ => return Err(NoteError::UnknownType(typ)),
+            }
+        }
+        Ok(requests)
+    }
+}
+
+fn read_u32(note: &Note) -> Result<u32, NoteError> {
+    Ok(u32::from_le_bytes(desc_bytes(note)?))
+}
+
+fn read_u64(note: &Note) -> Result<u64, NoteError> {
+    Ok(u64::from_le_bytes(desc_bytes(note)?))
+}
+
+fn desc_bytes<const N: usize>(note: &Note) -> Result<[u8; N], NoteError> {
+    note.desc.try_into().map_err(|_| NoteError::InvalidSize {
+        typ: note.typ,
+        size: note.desc.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootinfo;
+    use crate::elf::tests::{build_elf_with_notes, encode_note};
+    use crate::elf::Elf;
+
+    fn parse(notes: &[(&str, u32, &[u8])]) -> Result<KernelRequests, NoteError> {
+        let data = notes
+            .iter()
+            .flat_map(|(name, typ, desc)| encode_note(name, *typ, desc))
+            .collect::<std::vec::Vec<_>>();
+        let bytes = build_elf_with_notes(&data);
+        KernelRequests::from_notes(Elf::parse(&bytes).unwrap().notes())
+    }
+
+    #[test]
+    fn layout() {
+        let note = ElfNote::new(TYPE_PAGING_LEVELS, 5_u32);
+        assert_eq!(size_of::<ElfNote<u32>>(), 28);
+        let bytes =
+            unsafe { core::slice::from_raw_parts((&note as *const ElfNote<u32>).cast::<u8>(), 28) };
+        assert_eq!(
+            parse(&[]).unwrap(),
+            KernelRequests::default(),
+            "no notes, no requests"
+        );
+        let elf = build_elf_with_notes(bytes);
+        let requests = KernelRequests::from_notes(Elf::parse(&elf).unwrap().notes()).unwrap();
+        assert_eq!(requests.paging_levels, Some(Level::Five));
+    }
+
+    #[test]
+    fn paging_levels() {
+        let requests = parse(&[("Xen", 1, &[9]), (NAME, 1, &4_u32.to_le_bytes())]).unwrap();
+        assert_eq!(requests.paging_levels, Some(Level::Four));
+
+        assert_eq!(
+            parse(&[(NAME, 1, &3_u32.to_le_bytes())]),
+            Err(NoteError::InvalidValue { typ: 1, value: 3 })
+        );
+        assert_eq!(
+            parse(&[(NAME, 1, &[5])]),
+            Err(NoteError::InvalidSize { typ: 1, size: 1 })
+        );
+        assert_eq!(
+            parse(&[(NAME, 0x1337, &[])]),
+            Err(NoteError::UnknownType(0x1337))
+        );
+    }
+
+    #[test]
+    fn all_requests() {
+        let requests = parse(&[
+            (NAME, TYPE_STACK_SIZE, &0x100000_u64.to_le_bytes()),
+            (NAME, TYPE_DIRECT_MAP, &0xffff800000000000_u64.to_le_bytes()),
+            (NAME, TYPE_FRAMEBUFFER, &1_u32.to_le_bytes()),
+            (NAME, TYPE_CPU_FEATURES, &0b101_u64.to_le_bytes()),
+            (NAME, TYPE_MIN_VERSION, &1_u32.to_le_bytes()),
+        ])
+        .unwrap();
+        assert_eq!(
+            requests,
+            KernelRequests {
+                paging_levels: None,
+                stack_size: Some(0x100000),
+                direct_map: Some(0xffff800000000000),
+                framebuffer: Some(bootinfo::FRAMEBUFFER_RGB),
+                cpu_features: CpuFeatures::from_bits(0b101),
+                min_version: Some(1),
+            }
+        );
+
+        assert_eq!(
+            parse(&[(NAME, TYPE_STACK_SIZE, &0_u64.to_le_bytes())]),
+            Err(NoteError::InvalidValue {
+                typ: TYPE_STACK_SIZE,
+                value: 0
+            })
+        );
+        assert_eq!(
+            parse(&[(NAME, TYPE_STACK_SIZE, &1_u32.to_le_bytes())]),
+            Err(NoteError::InvalidSize {
+                typ: TYPE_STACK_SIZE,
+                size: 4
+            })
+        );
+        assert_eq!(
+            parse(&[(NAME, TYPE_FRAMEBUFFER, &3_u32.to_le_bytes())]),
+            Err(NoteError::InvalidValue {
+                typ: TYPE_FRAMEBUFFER,
+                value: 3
+            })
+        );
+        assert_eq!(
+            parse(&[(NAME, TYPE_CPU_FEATURES, &(1_u64 << 63).to_le_bytes())]),
+            Err(NoteError::InvalidValue {
+                typ: TYPE_CPU_FEATURES,
+                value: 1 << 63
+            })
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Addressing and enumeration of the PCI configuration space. How the
+//! configuration space is accessed, via port I/O or ECAM, is up to the
+//! caller.
+
+use crate::bootinfo::PciFunction;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::ops::RangeInclusive;
+
+pub const DEVICES_PER_BUS: u8 = 32;
+pub const FUNCTIONS_PER_DEVICE: u8 = 8;
+/// Vendor ID that reads from functions that don't exist return.
+const VENDOR_ID_NONE: u16 = 0xffff;
+/// Bit of the header type: function 0 is part of a multi-function device.
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+
+/// The address of a function in the configuration space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Returns the value for the `CONFIG_ADDRESS` port of the legacy
+    /// configuration mechanism to read the dword at `offset`. The mechanism
+    /// only reaches segment 0 and the first 256 bytes of each function.
+    pub fn legacy_config_address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+
+    /// Returns the offset of the configuration space of the function from
+    /// the ECAM base address of its segment.
+    pub fn ecam_offset(&self) -> u64 {
+        (self.bus as u64) << 20 | (self.device as u64) << 15 | (self.function as u64) << 12
+    }
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+impl From<&PciFunction> for PciAddress {
+    fn from(function: &PciFunction) -> Self {
+        Self {
+            segment: function.segment,
+            bus: function.bus,
+            device: function.device,
+            function: function.function,
+        }
+    }
+}
+
+/// Returns all functions on the given buses of a segment. `read` returns the
+/// dword at an offset of the configuration space of a function.
+pub fn enumerate(
+    segment: u16,
+    buses: RangeInclusive<u8>,
+    mut read: impl FnMut(PciAddress, u8) -> u32,
+) -> Vec<PciFunction> {
+    let mut functions = Vec::new();
+    for bus in buses {
+        for device in 0..DEVICES_PER_BUS {
+            for function in 0..FUNCTIONS_PER_DEVICE {
+                let address = PciAddress {
+                    segment,
+                    bus,
+                    device,
+                    function,
+                };
+                let ids = read(address, 0x0);
+                if ids as u16 == VENDOR_ID_NONE {
+                    // Without function 0, there is no device.
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                let class = read(address, 0x8);
+                let header_type = (read(address, 0xc) >> 16) as u8;
+                functions.push(PciFunction {
+                    segment,
+                    bus,
+                    device,
+                    function,
+                    revision: class as u8,
+                    vendor_id: ids as u16,
+                    device_id: (ids >> 16) as u16,
+                    class: (class >> 24) as u8,
+                    subclass: (class >> 16) as u8,
+                    prog_if: (class >> 8) as u8,
+                    header_type: header_type & !HEADER_TYPE_MULTI_FUNCTION,
+                    reserved: 0,
+                });
+                if function == 0 && header_type & HEADER_TYPE_MULTI_FUNCTION == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    functions
+}
+
+/// Returns the name of a base class code.
+pub fn class_name(class: u8) -> &'static str {
+    match class {
+        0x00 => "unclassified",
+        0x01 => "mass storage controller",
+        0x02 => "network controller",
+        0x03 => "display controller",
+        0x04 => "multimedia controller",
+        0x05 => "memory controller",
+        0x06 => "bridge",
+        0x07 => "communication controller",
+        0x08 => "system peripheral",
+        0x09 => "input device controller",
+        0x0a => "docking station",
+        0x0b => "processor",
+        0x0c => "serial bus controller",
+        0x0d => "wireless controller",
+        0x0e => "intelligent controller",
+        0x0f => "satellite communication controller",
+        0x10 => "encryption controller",
+        0x11 => "signal processing controller",
+        0x12 => "processing accelerator",
+        0x13 => "non-essential instrumentation",
+        0x40 => "co-processor",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn addresses() {
+        let address = PciAddress {
+            segment: 1,
+            bus: 0x12,
+            device: 0x1f,
+            function: 3,
+        };
+        assert_eq!(address.legacy_config_address(0x3f), 0x8012_fb3c);
+        assert_eq!(address.ecam_offset(), 0x12f_b000);
+        assert_eq!(address.to_string(), "0001:12:1f.3");
+    }
+
+    #[test]
+    fn enumerate_functions() {
+        // Bus 0: a host bridge at 00.0 and a multi-function device at 1f.0
+        // and 1f.3. Bus 1: a device with the multi-function bit unset that
+        // still answers on function 1, which must be ignored.
+        let read = |address: PciAddress, offset: u8| -> u32 {
+            let function = (address.bus, address.device, address.function);
+            let (ids, class, header_type) = match function {
+                (0, 0x00, 0) => (0x1237_8086, 0x0600_0002, 0x00),
+                (0, 0x1f, 0) => (0x2918_8086, 0x0601_0002, 0x80),
+                (0, 0x1f, 3) => (0x2930_8086, 0x0c05_0002, 0x00),
+                (1, 0x00, _) => (0x1000_1af4, 0x0200_0001, 0x00),
+                _ => return u32::MAX,
+            };
+            match offset {
+                0x0 => ids,
+                0x8 => class,
+                0xc => header_type << 16,
+                _ => 0,
+            }
+        };
+
+        let functions = enumerate(0, 0..=1, read);
+        let summary = functions
+            .iter()
+            .map(|f| {
+                (
+                    PciAddress::from(f).to_string(),
+                    f.device_id,
+                    f.class,
+                    f.subclass,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("0000:00:00.0".to_string(), 0x1237, 0x06, 0x00),
+                ("0000:00:1f.0".to_string(), 0x2918, 0x06, 0x01),
+                ("0000:00:1f.3".to_string(), 0x2930, 0x0c, 0x05),
+                ("0000:01:00.0".to_string(), 0x1000, 0x02, 0x00),
+            ]
+        );
+        assert_eq!(functions[1].header_type, 0);
+        assert_eq!(functions[2].revision, 2);
+        assert_eq!(functions[3].vendor_id, 0x1af4);
+        assert!(enumerate(0, 2..=0xff, read).is_empty());
+    }
+}
@@ -0,0 +1,541 @@
+//! Parsing of the SMBIOS entry points and of the structures that PhipsBoot
+//! logs to identify the machine. All functions work on the raw bytes of the
+//! structures, so that they are independent of how the memory is accessed.
+
+use core::fmt::{Display, Formatter};
+
+/// Anchor of the 32-bit entry point of SMBIOS 2.x.
+pub const ENTRY_POINT2_ANCHOR: &[u8; 4] = b"_SM_";
+/// Intermediate anchor of the 32-bit entry point of SMBIOS 2.x.
+const ENTRY_POINT2_DMI_ANCHOR: &[u8; 5] = b"_DMI_";
+/// Minimum length of the 32-bit entry point.
+const ENTRY_POINT2_LEN: usize = 0x1f;
+/// Anchor of the 64-bit entry point of SMBIOS 3.x.
+pub const ENTRY_POINT3_ANCHOR: &[u8; 5] = b"_SM3_";
+/// Length of the 64-bit entry point.
+pub const ENTRY_POINT3_LEN: usize = 0x18;
+/// Maximum length of the entry points of all known SMBIOS versions.
+pub const ENTRY_POINT_MAX_LEN: usize = 0x20;
+
+/// Structure type: BIOS Information.
+pub const TYPE_BIOS: u8 = 0;
+/// Structure type: System Information.
+pub const TYPE_SYSTEM: u8 = 1;
+/// Structure type: Processor Information.
+pub const TYPE_PROCESSOR: u8 = 4;
+/// Structure type: Memory Device.
+pub const TYPE_MEMORY_DEVICE: u8 = 17;
+/// Structure type: End-of-Table.
+const TYPE_END: u8 = 127;
+/// Length of the header of all structures.
+const STRUCTURE_HEADER_LEN: usize = 4;
+
+/// Errors of malformed SMBIOS entry points.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SmbiosError {
+    /// The entry point doesn't start with one of the anchors.
+    InvalidAnchor,
+    /// The bytes of the entry point don't sum up to zero.
+    InvalidChecksum,
+    /// The entry point is shorter than its length field claims or than the
+    /// minimum.
+    InvalidLength,
+}
+
+impl Display for SmbiosError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidAnchor => write!(f, "invalid anchor"),
+            Self::InvalidChecksum => write!(f, "invalid checksum"),
+            Self::InvalidLength => write!(f, "invalid length"),
+        }
+    }
+}
+
+fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// An SMBIOS entry point, which locates the structure table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub major: u8,
+    pub minor: u8,
+    /// Length of the entry point in bytes.
+    pub length: u8,
+    /// Physical address of the structure table.
+    pub table_address: u64,
+    /// Length of the structure table in bytes. This is only the maximum
+    /// length for SMBIOS 3.x.
+    pub table_len: u32,
+}
+
+impl EntryPoint {
+    /// Parses and validates a 32-bit (SMBIOS 2.x) or 64-bit (SMBIOS 3.x)
+    /// entry point.
+    pub fn parse(bytes: &[u8]) -> Result<Self, SmbiosError> {
+        if bytes.starts_with(ENTRY_POINT3_ANCHOR) {
+            Self::parse3(bytes)
+        } else if bytes.starts_with(ENTRY_POINT2_ANCHOR) {
+            Self::parse2(bytes)
+        } else {
+            Err(SmbiosError::InvalidAnchor)
+        }
+    }
+
+    fn parse2(bytes: &[u8]) -> Result<Self, SmbiosError> {
+        let len = *bytes.get(5).ok_or(SmbiosError::InvalidLength)? as usize;
+        // Some firmware reports 0x1e, an error in SMBIOS 2.1.
+        if len < ENTRY_POINT2_LEN - 1 || bytes.len() < len.max(ENTRY_POINT2_LEN) {
+            return Err(SmbiosError::InvalidLength);
+        }
+        if &bytes[0x10..0x15] != ENTRY_POINT2_DMI_ANCHOR {
+            return Err(SmbiosError::InvalidAnchor);
+        }
+        if !checksum_is_valid(&bytes[..len]) || !checksum_is_valid(&bytes[0x10..0x1f]) {
+            return Err(SmbiosError::InvalidChecksum);
+        }
+        Ok(Self {
+            major: bytes[6],
+            minor: bytes[7],
+            length: len as u8,
+            table_address: read_u32(bytes, 0x18) as u64,
+            table_len: read_u16(bytes, 0x16) as u32,
+        })
+    }
+
+    fn parse3(bytes: &[u8]) -> Result<Self, SmbiosError> {
+        let len = *bytes.get(6).ok_or(SmbiosError::InvalidLength)? as usize;
+        if len < ENTRY_POINT3_LEN || bytes.len() < len {
+            return Err(SmbiosError::InvalidLength);
+        }
+        if !checksum_is_valid(&bytes[..len]) {
+            return Err(SmbiosError::InvalidChecksum);
+        }
+        Ok(Self {
+            major: bytes[7],
+            minor: bytes[8],
+            length: len as u8,
+            table_address: read_u64(bytes, 0x10),
+            table_len: read_u32(bytes, 0xc),
+        })
+    }
+}
+
+/// Returns a 64-bit (SMBIOS 3.x) entry point for a structure table of
+/// `table_len` bytes at `table_address`.
+pub fn entry_point3(
+    major: u8,
+    minor: u8,
+    table_address: u64,
+    table_len: u32,
+) -> [u8; ENTRY_POINT3_LEN] {
+    let mut bytes = [0; ENTRY_POINT3_LEN];
+    bytes[..5].copy_from_slice(ENTRY_POINT3_ANCHOR);
+    bytes[6] = ENTRY_POINT3_LEN as u8;
+    bytes[7] = major;
+    bytes[8] = minor;
+    // Entry point revision: SMBIOS 3.0
+    bytes[0xa] = 1;
+    bytes[0xc..0x10].copy_from_slice(&table_len.to_le_bytes());
+    bytes[0x10..0x18].copy_from_slice(&table_address.to_le_bytes());
+    let sum = bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes[5] = sum.wrapping_neg();
+    bytes
+}
+
+/// Returns the offset of the first valid entry point in `bytes`. The entry
+/// point is searched on 16-byte boundaries, as in the legacy BIOS area. A
+/// 64-bit entry point is preferred over a 32-bit one.
+pub fn scan_entry_point(bytes: &[u8]) -> Option<usize> {
+    let find = |anchor: &[u8]| {
+        (0..bytes.len()).step_by(16).find(|offset| {
+            bytes[*offset..].starts_with(anchor) && EntryPoint::parse(&bytes[*offset..]).is_ok()
+        })
+    };
+    find(ENTRY_POINT3_ANCHOR).or_else(|| find(ENTRY_POINT2_ANCHOR))
+}
+
+/// A structure of the structure table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Structure<'a> {
+    pub typ: u8,
+    pub handle: u16,
+    /// The formatted area, including the header.
+    pub data: &'a [u8],
+    /// The string-set without its terminator.
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    /// Returns the byte at `offset` of the formatted area. Structures of
+    /// older SMBIOS versions may be shorter.
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    /// Returns the word at `offset` of the formatted area.
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        (offset + 2 <= self.data.len()).then(|| read_u16(self.data, offset))
+    }
+
+    /// Returns the double word at `offset` of the formatted area.
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        (offset + 4 <= self.data.len()).then(|| read_u32(self.data, offset))
+    }
+
+    /// Returns the string that the byte at `offset` of the formatted area
+    /// references, without surrounding whitespace. `None` if there is no
+    /// such string.
+    pub fn string(&self, offset: usize) -> Option<&'a str> {
+        let index = self.byte(offset)? as usize;
+        let string = self
+            .strings
+            .split(|byte| *byte == 0)
+            .nth(index.checked_sub(1)?)?;
+        core::str::from_utf8(string)
+            .ok()
+            .map(str::trim)
+            .filter(|string| !string.is_empty())
+    }
+}
+
+/// Returns an iterator over the structures of a structure table. It ends at
+/// the End-of-Table structure or at the first malformed structure.
+pub fn structures(table: &[u8]) -> impl Iterator<Item = Structure<'_>> {
+    let mut bytes = table;
+    core::iter::from_fn(move || {
+        let len = *bytes.get(1)? as usize;
+        if len < STRUCTURE_HEADER_LEN || bytes.len() < len {
+            return None;
+        }
+        let strings_len = bytes[len..].windows(2).position(|w| w == [0, 0])?;
+        let structure = Structure {
+            typ: bytes[0],
+            handle: read_u16(bytes, 2),
+            data: &bytes[..len],
+            strings: &bytes[len..len + strings_len],
+        };
+        bytes = &bytes[len + strings_len + 2..];
+        Some(structure)
+    })
+    .take_while(|structure| structure.typ != TYPE_END)
+}
+
+/// The BIOS Information (type 0).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BiosInfo<'a> {
+    pub vendor: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub release_date: Option<&'a str>,
+}
+
+impl<'a> BiosInfo<'a> {
+    /// Returns the information if the structure is of type [`TYPE_BIOS`].
+    pub fn parse(structure: &Structure<'a>) -> Option<Self> {
+        (structure.typ == TYPE_BIOS).then(|| Self {
+            vendor: structure.string(0x4),
+            version: structure.string(0x5),
+            release_date: structure.string(0x8),
+        })
+    }
+}
+
+/// The System Information (type 1).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SystemInfo<'a> {
+    pub manufacturer: Option<&'a str>,
+    pub product: Option<&'a str>,
+    pub version: Option<&'a str>,
+}
+
+impl<'a> SystemInfo<'a> {
+    /// Returns the information if the structure is of type [`TYPE_SYSTEM`].
+    pub fn parse(structure: &Structure<'a>) -> Option<Self> {
+        (structure.typ == TYPE_SYSTEM).then(|| Self {
+            manufacturer: structure.string(0x4),
+            product: structure.string(0x5),
+            version: structure.string(0x6),
+        })
+    }
+}
+
+/// The Processor Information (type 4).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProcessorInfo<'a> {
+    pub socket: Option<&'a str>,
+    pub manufacturer: Option<&'a str>,
+    pub version: Option<&'a str>,
+    /// Whether the socket holds a processor.
+    pub populated: bool,
+    pub max_speed_mhz: Option<u16>,
+    pub core_count: Option<u8>,
+    pub thread_count: Option<u8>,
+}
+
+impl<'a> ProcessorInfo<'a> {
+    /// Returns the information if the structure is of type
+    /// [`TYPE_PROCESSOR`].
+    pub fn parse(structure: &Structure<'a>) -> Option<Self> {
+        // `0` means unknown for all numbers.
+        let known_u8 = |offset| structure.byte(offset).filter(|v| *v != 0);
+        (structure.typ == TYPE_PROCESSOR).then(|| Self {
+            socket: structure.string(0x4),
+            manufacturer: structure.string(0x7),
+            version: structure.string(0x10),
+            populated: structure
+                .byte(0x18)
+                .is_some_and(|status| status & (1 << 6) != 0),
+            max_speed_mhz: structure.word(0x14).filter(|v| *v != 0),
+            core_count: known_u8(0x23),
+            thread_count: known_u8(0x25),
+        })
+    }
+}
+
+/// A Memory Device (type 17).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryDevice<'a> {
+    pub locator: Option<&'a str>,
+    pub manufacturer: Option<&'a str>,
+    pub part_number: Option<&'a str>,
+    /// Size in bytes, `Some(0)` if no device is installed, and `None` if
+    /// unknown.
+    pub size: Option<u64>,
+    /// Speed in MT/s.
+    pub speed: Option<u16>,
+}
+
+impl<'a> MemoryDevice<'a> {
+    /// Returns the information if the structure is of type
+    /// [`TYPE_MEMORY_DEVICE`].
+    pub fn parse(structure: &Structure<'a>) -> Option<Self> {
+        if structure.typ != TYPE_MEMORY_DEVICE {
+            return None;
+        }
+        let size = match structure.word(0xc) {
+            None | Some(0xffff) => None,
+            // The size is in the Extended Size field, in MiB.
+            Some(0x7fff) => structure
+                .dword(0x1c)
+                .map(|size| (size & 0x7fff_ffff) as u64 * 1024 * 1024),
+            // Bit 15 selects KiB instead of MiB.
+            Some(size) if size & 0x8000 != 0 => Some((size & 0x7fff) as u64 * 1024),
+            Some(size) => Some(size as u64 * 1024 * 1024),
+        };
+        Some(Self {
+            locator: structure.string(0x10),
+            manufacturer: structure.string(0x17),
+            part_number: structure.string(0x1a),
+            size,
+            speed: structure.word(0x15).filter(|v| *v != 0 && *v != 0xffff),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// Sets the checksum byte at `offset` so that `bytes` sum up to zero.
+    fn fix_checksum(bytes: &mut [u8], offset: usize) {
+        bytes[offset] = 0;
+        let sum = bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes[offset] = sum.wrapping_neg();
+    }
+
+    /// Returns a structure with the given formatted area after the header
+    /// and the given strings.
+    fn structure(typ: u8, data: &[u8], strings: &[&str]) -> Vec<u8> {
+        let mut bytes = vec![typ, (STRUCTURE_HEADER_LEN + data.len()) as u8, 0x34, 0x12];
+        bytes.extend_from_slice(data);
+        for string in strings {
+            bytes.extend_from_slice(string.as_bytes());
+            bytes.push(0);
+        }
+        if strings.is_empty() {
+            bytes.push(0);
+        }
+        bytes.push(0);
+        bytes
+    }
+
+    #[test]
+    fn entry_point() {
+        let ep3 = entry_point3(3, 2, 0x1_0000_0000, 0x1234);
+        assert_eq!(
+            EntryPoint::parse(&ep3),
+            Ok(EntryPoint {
+                major: 3,
+                minor: 2,
+                length: ENTRY_POINT3_LEN as u8,
+                table_address: 0x1_0000_0000,
+                table_len: 0x1234,
+            })
+        );
+        let mut invalid = ep3;
+        invalid[0x10] = 1;
+        assert_eq!(
+            EntryPoint::parse(&invalid),
+            Err(SmbiosError::InvalidChecksum)
+        );
+        assert_eq!(
+            EntryPoint::parse(&ep3[..0x10]),
+            Err(SmbiosError::InvalidLength)
+        );
+
+        let mut ep2 = vec![0; ENTRY_POINT2_LEN];
+        ep2[..4].copy_from_slice(ENTRY_POINT2_ANCHOR);
+        ep2[5] = ENTRY_POINT2_LEN as u8;
+        ep2[6] = 2;
+        ep2[7] = 8;
+        ep2[0x10..0x15].copy_from_slice(ENTRY_POINT2_DMI_ANCHOR);
+        ep2[0x16..0x18].copy_from_slice(&0x200_u16.to_le_bytes());
+        ep2[0x18..0x1c].copy_from_slice(&0xf0000_u32.to_le_bytes());
+        assert_eq!(EntryPoint::parse(&ep2), Err(SmbiosError::InvalidChecksum));
+        // The intermediate checksum is part of the checksum of the whole
+        // entry point.
+        fix_checksum(&mut ep2[0x10..], 0x5);
+        fix_checksum(&mut ep2, 0x4);
+        assert_eq!(
+            EntryPoint::parse(&ep2),
+            Ok(EntryPoint {
+                major: 2,
+                minor: 8,
+                length: ENTRY_POINT2_LEN as u8,
+                table_address: 0xf0000,
+                table_len: 0x200,
+            })
+        );
+
+        ep2[0x10] = b'X';
+        assert_eq!(EntryPoint::parse(&ep2), Err(SmbiosError::InvalidAnchor));
+        assert_eq!(EntryPoint::parse(&[0; 32]), Err(SmbiosError::InvalidAnchor));
+    }
+
+    #[test]
+    fn entry_point_scan() {
+        let mut area = vec![0; 0x100];
+        // Not on a 16-byte boundary
+        area[0x08..0x20].copy_from_slice(&entry_point3(3, 0, 0x1000, 0x100));
+        // Invalid checksum
+        area[0x40..0x58].copy_from_slice(&entry_point3(3, 0, 0x1000, 0x100));
+        area[0x41] = 0;
+        assert_eq!(scan_entry_point(&area), None);
+
+        area[0x80..0x98].copy_from_slice(&entry_point3(3, 0, 0x1000, 0x100));
+        assert_eq!(scan_entry_point(&area), Some(0x80));
+        assert_eq!(scan_entry_point(&area[..0x90]), None);
+    }
+
+    #[test]
+    fn structures_and_strings() {
+        let mut table = structure(
+            TYPE_BIOS,
+            &[1, 2, 0, 0, 3],
+            &["SeaBIOS ", "1.16", "04/01/2014"],
+        );
+        table.extend(structure(
+            TYPE_SYSTEM,
+            &[0, 2, 0, 0],
+            &["QEMU", "Standard PC"],
+        ));
+        table.extend(structure(TYPE_END, &[], &[]));
+        table.extend(structure(TYPE_BIOS, &[], &[]));
+
+        let structures = structures(&table).collect::<Vec<_>>();
+        assert_eq!(structures.len(), 2);
+        assert_eq!(structures[0].handle, 0x1234);
+        assert_eq!(
+            BiosInfo::parse(&structures[0]),
+            Some(BiosInfo {
+                vendor: Some("SeaBIOS"),
+                version: Some("1.16"),
+                release_date: Some("04/01/2014"),
+            })
+        );
+        assert_eq!(BiosInfo::parse(&structures[1]), None);
+        assert_eq!(
+            SystemInfo::parse(&structures[1]),
+            Some(SystemInfo {
+                manufacturer: None,
+                product: Some("Standard PC"),
+                version: None,
+            })
+        );
+        // Out of the formatted area
+        assert_eq!(structures[1].string(0x10), None);
+
+        // Missing string-set terminator
+        let truncated = structure(TYPE_SYSTEM, &[], &["X"]);
+        assert_eq!(super::structures(&truncated[..6]).count(), 0);
+    }
+
+    #[test]
+    fn processor_and_memory() {
+        let mut data = vec![0; 0x28 - STRUCTURE_HEADER_LEN];
+        data[0x4 - 4] = 1;
+        data[0x10 - 4] = 2;
+        data[0x14 - 4..0x16 - 4].copy_from_slice(&3000_u16.to_le_bytes());
+        data[0x18 - 4] = 0x41;
+        data[0x23 - 4] = 8;
+        let processor = structure(TYPE_PROCESSOR, &data, &["CPU 0", "Xeon"]);
+        let processor = structures(&processor).next().unwrap();
+        assert_eq!(
+            ProcessorInfo::parse(&processor),
+            Some(ProcessorInfo {
+                socket: Some("CPU 0"),
+                manufacturer: None,
+                version: Some("Xeon"),
+                populated: true,
+                max_speed_mhz: Some(3000),
+                core_count: Some(8),
+                thread_count: None,
+            })
+        );
+
+        let mut data = vec![0; 0x22 - STRUCTURE_HEADER_LEN];
+        data[0x10 - 4] = 1;
+        data[0x15 - 4..0x17 - 4].copy_from_slice(&3200_u16.to_le_bytes());
+        data[0xc - 4..0xe - 4].copy_from_slice(&0x2000_u16.to_le_bytes());
+        let memory = structure(TYPE_MEMORY_DEVICE, &data, &["DIMM 0"]);
+        let memory = structures(&memory).next().unwrap();
+        assert_eq!(
+            MemoryDevice::parse(&memory),
+            Some(MemoryDevice {
+                locator: Some("DIMM 0"),
+                manufacturer: None,
+                part_number: None,
+                size: Some(8 << 30),
+                speed: Some(3200),
+            })
+        );
+
+        let size = |size: u16, extended_size: u32| {
+            let mut data = data.clone();
+            data[0xc - 4..0xe - 4].copy_from_slice(&size.to_le_bytes());
+            data[0x1c - 4..0x20 - 4].copy_from_slice(&extended_size.to_le_bytes());
+            let memory = structure(TYPE_MEMORY_DEVICE, &data, &[]);
+            let memory = structures(&memory).next().unwrap();
+            MemoryDevice::parse(&memory).unwrap().size
+        };
+        assert_eq!(size(0x8200, 0), Some(512 << 10));
+        assert_eq!(size(0x7fff, 64 << 10), Some(64 << 30));
+        assert_eq!(size(0xffff, 0), None);
+        assert_eq!(size(0, 0), Some(0));
+    }
+}
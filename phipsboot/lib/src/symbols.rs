@@ -0,0 +1,269 @@
+//! Compact symbol table that is embedded into PhipsBoot to symbolize
+//! backtraces.
+//!
+//! The table is generated after linking from the `.symtab` of PhipsBoot's ELF
+//! file and written into a reserved section of the same file. Its layout is
+//! (all values little endian):
+//!
+//! - magic (`u32`, [`MAGIC`])
+//! - number of symbols (`u32`)
+//! - base address (`u64`), to which all symbol offsets are relative
+//! - one entry per symbol, sorted by offset: offset (`u32`), size (`u32`),
+//!   name offset (`u32`), name length (`u32`)
+//! - the UTF-8 names; the name offsets are relative to the start of the table
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Magic value at the beginning of the symbol table: `"PSYM"`.
+pub const MAGIC: u32 = u32::from_le_bytes(*b"PSYM");
+
+/// Names are truncated to this length in bytes.
+pub const MAX_NAME_LEN: usize = 96;
+
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+/// Errors when encoding a symbol table.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum EncodeError {
+    /// A symbol is too far away from the base address.
+    OutOfRange(u64),
+}
+
+/// A symbol table in the format described in the module documentation.
+#[derive(Debug, Copy, Clone)]
+pub struct SymbolTable<'a> {
+    bytes: &'a [u8],
+    count: usize,
+    base: u64,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parses the table. Returns `None` if the bytes don't contain a valid
+    /// table, such as the zeroed section if the table was not embedded.
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || read_u32(bytes, 0) != MAGIC {
+            return None;
+        }
+        let count = read_u32(bytes, 4) as usize;
+        if HEADER_SIZE + count * ENTRY_SIZE > bytes.len() {
+            return None;
+        }
+        Some(Self {
+            bytes,
+            count,
+            base: read_u64(bytes, 8),
+        })
+    }
+
+    /// Returns the number of symbols.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the name of the symbol that contains `addr` and the offset of
+    /// `addr` into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        let offset = u32::try_from(addr.checked_sub(self.base)?).ok()?;
+        // Index of the first entry after `offset`.
+        let index = self.partition_point(|entry_offset| entry_offset <= offset);
+        let entry = HEADER_SIZE + index.checked_sub(1)? * ENTRY_SIZE;
+        let start = read_u32(self.bytes, entry);
+        let size = read_u32(self.bytes, entry + 4);
+        if offset - start >= size.max(1) {
+            return None;
+        }
+        let name_offset = read_u32(self.bytes, entry + 8) as usize;
+        let name_len = read_u32(self.bytes, entry + 12) as usize;
+        let name = self.bytes.get(name_offset..name_offset + name_len)?;
+        Some((core::str::from_utf8(name).ok()?, (offset - start) as u64))
+    }
+
+    /// Binary search over the offsets of the entries.
+    fn partition_point(&self, pred: impl Fn(u32) -> bool) -> usize {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(read_u32(self.bytes, HEADER_SIZE + mid * ENTRY_SIZE)) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+/// Encodes the symbols `(address, size, name)` into a symbol table. All
+/// symbols must be within 4 GiB above the lowest address.
+pub fn encode(symbols: &[(u64, u64, &str)]) -> Result<Vec<u8>, EncodeError> {
+    let mut symbols = symbols.to_vec();
+    symbols.sort_by_key(|(addr, _, _)| *addr);
+    symbols.dedup_by_key(|(addr, _, _)| *addr);
+    let base = symbols.first().map(|(addr, _, _)| *addr).unwrap_or(0);
+
+    let mut table = Vec::new();
+    table.extend_from_slice(&MAGIC.to_le_bytes());
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&base.to_le_bytes());
+
+    let mut names = Vec::new();
+    let names_start = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+    for (addr, size, name) in &symbols {
+        let offset = u32::try_from(addr - base).map_err(|_| EncodeError::OutOfRange(*addr))?;
+        let name = truncate(name, MAX_NAME_LEN);
+        table.extend_from_slice(&offset.to_le_bytes());
+        table.extend_from_slice(&(*size as u32).to_le_bytes());
+        table.extend_from_slice(&((names_start + names.len()) as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    Ok(table)
+}
+
+/// Demangles a symbol name of the legacy Rust mangling scheme, such as
+/// `_ZN4core9panicking9panic_fmt17h0123456789abcdefE`, without the hash.
+/// Other names are returned unchanged.
+pub fn demangle(name: &str) -> String {
+    demangle_legacy(name).unwrap_or_else(|| name.into())
+}
+
+fn demangle_legacy(name: &str) -> Option<String> {
+    let mut rest = name.strip_prefix("_ZN")?.strip_suffix('E')?;
+    let mut components = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len = rest[..digits].parse::<usize>().ok()?;
+        let component = rest.get(digits..digits + len)?;
+        components.push(component);
+        rest = &rest[digits + len..];
+    }
+    let is_hash = |c: &&str| {
+        c.len() == 17 && c.starts_with('h') && c[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    if components.last().is_some_and(is_hash) {
+        components.pop();
+    }
+
+    let mut demangled = String::new();
+    for (i, component) in components.iter().enumerate() {
+        if i > 0 {
+            demangled.push_str("::");
+        }
+        // Components that start with an escape sequence are prefixed with `_`.
+        let component = if component.starts_with("_$") {
+            &component[1..]
+        } else {
+            component
+        };
+        demangled.push_str(&unescape(component));
+    }
+    Some(demangled)
+}
+
+/// Replaces the escape sequences of the legacy mangling scheme.
+fn unescape(component: &str) -> String {
+    const ESCAPES: [(&str, &str); 15] = [
+        ("$SP$", "@"),
+        ("$BP$", "*"),
+        ("$RF$", "&"),
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u3b$", ";"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+    ];
+    let mut result = String::new();
+    let mut rest = component;
+    while !rest.is_empty() {
+        if let Some((escape, replacement)) = ESCAPES.iter().find(|(e, _)| rest.starts_with(e)) {
+            result.push_str(replacement);
+            rest = &rest[escape.len()..];
+        } else if let Some(after) = rest.strip_prefix("..") {
+            result.push_str("::");
+            rest = after;
+        } else {
+            let c = rest.chars().next().unwrap();
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    result
+}
+
+/// Truncates a string to at most `len` bytes at a character boundary.
+fn truncate(str: &str, len: usize) -> &str {
+    let mut end = str.len().min(len);
+    while !str.is_char_boundary(end) {
+        end -= 1;
+    }
+    &str[..end]
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_lookup() {
+        let table = encode(&[
+            (0xffffffff88201020, 0x10, "helper"),
+            (0xffffffff88201000, 0x20, "main"),
+            (0xffffffff88201100, 0, "no_size"),
+        ])
+        .unwrap();
+        let table = SymbolTable::parse(&table).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.lookup(0xffffffff88201000), Some(("main", 0)));
+        assert_eq!(table.lookup(0xffffffff8820101f), Some(("main", 0x1f)));
+        assert_eq!(table.lookup(0xffffffff88201025), Some(("helper", 5)));
+        assert_eq!(table.lookup(0xffffffff88201030), None);
+        assert_eq!(table.lookup(0xffffffff88201100), Some(("no_size", 0)));
+        assert_eq!(table.lookup(0xffffffff88200fff), None);
+        assert_eq!(table.lookup(0), None);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(SymbolTable::parse(&[0; 64]).is_none());
+        let mut table = encode(&[(0x1000, 0x10, "main")]).unwrap();
+        table.truncate(HEADER_SIZE);
+        assert!(SymbolTable::parse(&table).is_none());
+        assert!(encode(&[(0x1000, 1, "a"), (0x200000000, 1, "b")]).is_err());
+    }
+
+    #[test]
+    fn demangle_names() {
+        assert_eq!(
+            demangle("_ZN4core9panicking9panic_fmt17h0123456789abcdefE"),
+            "core::panicking::panic_fmt"
+        );
+        assert_eq!(
+            demangle("_ZN63_$LT$lib..mem..map..PhysRange$u20$as$u20$core..fmt..Display$GT$3fmt17h0123456789abcdefE"),
+            "<lib::mem::map::PhysRange as core::fmt::Display>::fmt"
+        );
+        assert_eq!(demangle("rust_entry64"), "rust_entry64");
+        assert_eq!(demangle("_ZN3foo"), "_ZN3foo");
+    }
+}
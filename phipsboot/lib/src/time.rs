@@ -0,0 +1,61 @@
+//! Conversions between ticks of a timer and durations, as needed to
+//! calibrate a timer against a reference clock.
+
+use core::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Returns the frequency in Hz of a timer that advanced by `ticks` during
+/// `interval`. Returns `None` for an empty interval.
+pub fn frequency(ticks: u64, interval: Duration) -> Option<u64> {
+    let nanos = interval.as_nanos();
+    if nanos == 0 {
+        return None;
+    }
+    u64::try_from(ticks as u128 * NANOS_PER_SEC / nanos).ok()
+}
+
+/// Converts ticks of a timer with the given frequency to a duration.
+pub fn ticks_to_duration(ticks: u64, hz: u64) -> Duration {
+    assert_ne!(hz, 0);
+    let nanos = ticks as u128 * NANOS_PER_SEC / hz as u128;
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+/// Converts a duration to ticks of a timer with the given frequency, rounded
+/// up.
+pub fn duration_to_ticks(duration: Duration, hz: u64) -> u64 {
+    let ticks = (duration.as_nanos() * hz as u128).div_ceil(NANOS_PER_SEC);
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration() {
+        assert_eq!(
+            frequency(625_000, Duration::from_millis(10)),
+            Some(62_500_000)
+        );
+        assert_eq!(frequency(1, Duration::ZERO), None);
+        assert_eq!(frequency(u64::MAX, Duration::from_nanos(1)), None);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(
+            ticks_to_duration(62_500_000, 62_500_000),
+            Duration::from_secs(1)
+        );
+        assert_eq!(ticks_to_duration(3, 1_000_000), Duration::from_micros(3));
+        assert_eq!(
+            duration_to_ticks(Duration::from_secs(2), 1_193_182),
+            2_386_364
+        );
+        // Rounded up, so that a wait is never too short.
+        assert_eq!(duration_to_ticks(Duration::from_nanos(1), 1_000_000), 1);
+        assert_eq!(duration_to_ticks(Duration::ZERO, 1_000_000), 0);
+    }
+}
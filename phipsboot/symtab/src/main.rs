@@ -0,0 +1,76 @@
+//! Embeds the symbol table into the PhipsBoot binary.
+//!
+//! Reads the function symbols from the `.symtab` of the linked binary, encodes
+//! them with [`lib::symbols::encode`], and writes the result into the reserved
+//! `.symbols` section of the same file. PhipsBoot uses the table at runtime to
+//! symbolize backtraces. The binary is modified in place, so the tool must run
+//! after each link.
+//!
+//! Usage: `cargo run -p symtab -- <path to phipsboot>`
+
+use lib::elf::Elf;
+use lib::symbols;
+use std::process::ExitCode;
+
+/// Name of the section that is reserved for the table in `link.ld`.
+const SECTION: &str = ".symbols";
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: symtab <path to phipsboot>");
+        return ExitCode::FAILURE;
+    };
+    match embed(&path) {
+        Ok(msg) => {
+            println!("{path}: {msg}");
+            ExitCode::SUCCESS
+        }
+        Err(msg) => {
+            eprintln!("{path}: {msg}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn embed(path: &str) -> Result<String, String> {
+    let mut bytes = std::fs::read(path).map_err(|e| format!("can't read file: {e}"))?;
+    let elf = Elf::parse(&bytes).map_err(|e| format!("invalid ELF: {e:?}"))?;
+    let section = elf
+        .section_by_name(SECTION)
+        .ok_or_else(|| format!("no section {SECTION}"))?;
+
+    let names = elf
+        .function_symbols()
+        .filter(|symbol| symbol.value != 0)
+        .map(|symbol| (symbol.value, symbol.size, symbols::demangle(symbol.name)))
+        .collect::<Vec<_>>();
+    let entries = names
+        .iter()
+        .map(|(value, size, name)| (*value, *size, name.as_str()))
+        .collect::<Vec<_>>();
+    let table = symbols::encode(&entries).map_err(|e| format!("can't encode table: {e:?}"))?;
+
+    if table.len() as u64 > section.size {
+        return Err(format!(
+            "table needs {:#x} bytes but {SECTION} has only {:#x}",
+            table.len(),
+            section.size
+        ));
+    }
+    let start = section.offset as usize;
+    let end = start + section.size as usize;
+    let target = bytes
+        .get_mut(start..end)
+        .ok_or_else(|| format!("{SECTION} is not within the file"))?;
+    // Zero the rest, in case the tool ran before on the same file.
+    target.fill(0);
+    target[..table.len()].copy_from_slice(&table);
+
+    std::fs::write(path, &bytes).map_err(|e| format!("can't write file: {e}"))?;
+    Ok(format!(
+        "embedded {} symbols ({:#x} of {:#x} bytes)",
+        entries.len(),
+        table.len(),
+        section.size
+    ))
+}